@@ -0,0 +1,145 @@
+//! `i8080!`, assembling Intel 8080 source at compile time with the assembler of
+//! `emulator8080`, whose sources this crate shares.
+
+// The shared modules' tests need the rest of `emulator8080` and run there.
+#![cfg(not(test))]
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+#[allow(dead_code)]
+#[path = "../../src/assembler.rs"]
+mod assembler;
+#[allow(dead_code, clippy::upper_case_acronyms)]
+#[path = "../../src/op_code.rs"]
+mod op_code;
+#[allow(dead_code)]
+#[path = "../../src/symbols.rs"]
+mod symbols;
+#[allow(dead_code)]
+#[path = "../../src/syntax.rs"]
+mod syntax;
+
+/// Assembles the 8080 source given as tokens into a `[u8; N]` array, starting at the lowest
+/// address assembled. Statements end at a `;` or at the end of a line, and comments are
+/// written as Rust comments:
+///
+/// ```ignore
+/// const ECHO: [u8; 7] = i8080! {
+///     loop: IN 1; OUT 0   // copy port 1 to port 0
+///     JMP loop
+/// };
+/// ```
+///
+/// Everything the assembler supports works, but a few numbers are not valid Rust tokens:
+/// hex constants with an `E` right after a leading digit, such as `0EH` or `1EH`, have to be
+/// written `0x0E` or in decimal instead. Errors are reported on the offending statement.
+#[proc_macro]
+pub fn i8080(input: TokenStream) -> TokenStream {
+    let mut source = Source::default();
+    source.tokens(input);
+    source.end_line();
+    match assembler::assemble(&source.text) {
+        Ok(program) => array(&program.code),
+        Err(e) => {
+            let span = e
+                .location
+                .line
+                .checked_sub(1)
+                .and_then(|line| source.spans.get(line).copied())
+                .unwrap_or_else(Span::call_site);
+            compile_error(&format!("{}", e.kind), span)
+        }
+    }
+}
+
+/// Assembler source rebuilt from tokens, with the span of the first token of each line.
+#[derive(Default)]
+struct Source {
+    text: String,
+    line: String,
+    spans: Vec<Span>,
+    first: Option<Span>,
+    /// Line in the Rust source of the last token.
+    rust_line: usize,
+}
+
+impl Source {
+    fn tokens(&mut self, input: TokenStream) {
+        for token in input {
+            match token {
+                TokenTree::Punct(punct) if punct.as_char() == ';' => self.end_line(),
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::None => ("", ""),
+                    };
+                    self.push(open, group.span_open());
+                    self.tokens(group.stream());
+                    self.push(close, group.span_close());
+                }
+                token => self.push(&token.to_string(), token.span()),
+            }
+        }
+    }
+
+    fn push(&mut self, text: &str, span: Span) {
+        if text.is_empty() {
+            return;
+        }
+        let rust_line = span.start().line();
+        if rust_line != self.rust_line {
+            self.end_line();
+            self.rust_line = rust_line;
+        }
+        self.first.get_or_insert(span);
+        let glued = self.line.is_empty()
+            || self.line.ends_with(['.', '('])
+            || text.starts_with([',', ':', ')']);
+        if !glued {
+            self.line.push(' ');
+        }
+        self.line.push_str(text);
+    }
+
+    fn end_line(&mut self) {
+        if let Some(span) = self.first.take() {
+            self.text.push_str(&self.line);
+            self.text.push('\n');
+            self.line.clear();
+            self.spans.push(span);
+        }
+    }
+}
+
+fn array(code: &[u8]) -> TokenStream {
+    let mut bytes = TokenStream::new();
+    for (i, byte) in code.iter().enumerate() {
+        if i > 0 {
+            bytes.extend([TokenTree::Punct(Punct::new(',', Spacing::Alone))]);
+        }
+        bytes.extend([TokenTree::Literal(Literal::u8_suffixed(*byte))]);
+    }
+    TokenTree::Group(Group::new(Delimiter::Bracket, bytes)).into()
+}
+
+/// `compile_error!("message")`, pointing at `span`.
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let mut group = Group::new(
+        Delimiter::Parenthesis,
+        TokenTree::Literal(Literal::string(message)).into(),
+    );
+    group.set_span(span);
+    [
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+        TokenTree::Group(group),
+    ]
+    .into_iter()
+    .map(|mut token| {
+        token.set_span(span);
+        token
+    })
+    .collect()
+}
@@ -0,0 +1,1404 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::{
+    op_code::{Instruction, Register, RegisterPair},
+    symbols::SymbolTable,
+    syntax::HexStyle,
+};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    #[error("unknown instruction or directive {0:?}")]
+    UnknownOperation(String),
+
+    #[error("{0} expects {1} operand(s)")]
+    OperandCount(String, usize),
+
+    #[error("{0:?} is not a register")]
+    InvalidRegister(String),
+
+    #[error("{0:?} is not a register pair")]
+    InvalidRegisterPair(String),
+
+    #[error("invalid operands for {0}")]
+    InvalidOperands(String),
+
+    #[error("invalid expression {0:?}")]
+    InvalidExpression(String),
+
+    #[error("undefined symbol {0:?}")]
+    UndefinedSymbol(String),
+
+    #[error("{0:?} is already defined")]
+    DuplicateSymbol(String),
+
+    #[error("{0:?} is not a valid label")]
+    InvalidLabel(String),
+
+    #[error("{0} needs a label")]
+    MissingLabel(String),
+
+    #[error("value {0} does not fit in {1} bits")]
+    OutOfRange(i32, u8),
+
+    #[error("division by zero")]
+    DivisionByZero,
+
+    #[error("address {0:04x} is assembled twice")]
+    Overlap(u16),
+
+    #[error("cannot read {0}: {1}")]
+    Include(String, String),
+
+    #[error("{0} without a matching {1}")]
+    Unmatched(String, &'static str),
+
+    #[error("{0} has no matching {1}")]
+    Unterminated(String, &'static str),
+
+    #[error("macro {0} takes {1} argument(s)")]
+    TooManyArguments(String, usize),
+
+    #[error("includes or macro calls nested too deeply")]
+    NestingTooDeep,
+}
+
+/// Source file and line of a statement.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    /// `None` for source given as a string.
+    pub file: Option<PathBuf>,
+    pub line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), 0) => write!(f, "{}", file.display()),
+            (Some(file), line) => write!(f, "{}:{}", file.display(), line),
+            (None, line) => write!(f, "Line {}", line),
+        }
+    }
+}
+
+/// Assembly error, with the location of the offending line.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{location}: {kind}.{}", expansion_note(.expansion))]
+pub struct AssemblerError {
+    pub location: Location,
+    pub kind: ErrorKind,
+    /// Macro calls the line was expanded from, innermost first.
+    pub expansion: Vec<(String, Location)>,
+}
+
+fn expansion_note(expansion: &[(String, Location)]) -> String {
+    expansion
+        .iter()
+        .map(|(name, location)| format!(" In {} called at {}.", name, location))
+        .collect()
+}
+
+/// Output of [`assemble`]: a flat binary loaded at `origin`, and the value of every label and
+/// `EQU`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    pub origin: u16,
+    /// Bytes from the lowest to the highest address assembled, gaps filled with zeros.
+    pub code: Vec<u8>,
+    pub symbols: BTreeMap<String, u16>,
+    /// Operand of `END`, if given.
+    pub start: Option<u16>,
+}
+
+impl Program {
+    pub fn symbol_table(&self) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for (name, value) in self.by_value() {
+            table.insert(value, name);
+        }
+        table
+    }
+
+    /// Symbols as `LABEL EQU value` lines, readable by [`SymbolTable::parse`].
+    pub fn symbol_file(&self) -> String {
+        let mut out = String::new();
+        for (name, value) in self.by_value() {
+            let _ = writeln!(out, "{} EQU {}", name, HexStyle::Intel.word(value));
+        }
+        out
+    }
+
+    fn by_value(&self) -> Vec<(&str, u16)> {
+        let mut symbols = self
+            .symbols
+            .iter()
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect::<Vec<_>>();
+        symbols.sort_by_key(|(_, value)| *value);
+        symbols
+    }
+}
+
+const DIRECTIVES: [&str; 13] = [
+    "ORG", "EQU", "DB", "DW", "DS", "END", "IF", "ELSE", "ENDIF", "MACRO", "ENDM", "LOCAL",
+    "INCLUDE",
+];
+
+/// Depth of includes and macro calls beyond which the source is assumed to be recursive.
+const MAX_NESTING: usize = 64;
+
+const MNEMONICS: [&str; 78] = [
+    "ACI", "ADC", "ADD", "ADI", "ANA", "ANI", "CALL", "CC", "CM", "CMA", "CMC", "CMP", "CNC",
+    "CNZ", "CP", "CPE", "CPI", "CPO", "CZ", "DAA", "DAD", "DCR", "DCX", "DI", "EI", "HLT", "IN",
+    "INR", "INX", "JC", "JM", "JMP", "JNC", "JNZ", "JP", "JPE", "JPO", "JZ", "LDA", "LDAX", "LHLD",
+    "LXI", "MOV", "MVI", "NOP", "ORA", "ORI", "OUT", "PCHL", "POP", "PUSH", "RAL", "RAR", "RC",
+    "RET", "RLC", "RM", "RNC", "RNZ", "RP", "RPE", "RPO", "RRC", "RST", "RZ", "SBB", "SBI", "SHLD",
+    "SPHL", "STA", "STAX", "STC", "SUB", "SUI", "XCHG", "XRA", "XRI", "XTHL",
+];
+
+fn is_operation(word: &str) -> bool {
+    let word = word.to_ascii_uppercase();
+    DIRECTIVES.contains(&word.as_str()) || MNEMONICS.contains(&word.as_str())
+}
+
+/// One source line split into its fields.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Statement {
+    label: Option<String>,
+    /// Upper case mnemonic, directive or macro name.
+    operation: Option<String>,
+    operands: Vec<String>,
+}
+
+/// Part of `line` before its comment.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\'' | '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Splits `operands` at the commas that are neither quoted nor parenthesized.
+fn split_operands(operands: &str) -> Vec<String> {
+    if operands.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut parts = Vec::new();
+    let (mut quoted, mut depth, mut start) = (false, 0, 0);
+    for (i, c) in operands.char_indices() {
+        match c {
+            '\'' | '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                parts.push(operands[start..i].trim().to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(operands[start..].trim().to_string());
+    parts
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_?@.".contains(c)
+}
+
+fn is_valid_label(name: &str) -> bool {
+    name.starts_with(|c: char| is_name_char(c) && !c.is_ascii_digit())
+        && name.chars().all(is_name_char)
+        && !is_operation(name)
+}
+
+/// Splits a line into label, operation and operands. A label is a first word ending with `:`,
+/// a first word in column 1 that is neither an operation nor one of `macros`, or the name
+/// before an `EQU` or `MACRO`.
+fn parse_statement(line: &str, macros: &BTreeMap<String, Macro>) -> Result<Statement, ErrorKind> {
+    let text = strip_comment(line);
+    let mut statement = Statement::default();
+    let mut rest = text.trim_start();
+    let first_word = |rest: &str| rest.split_whitespace().next().map(str::len);
+    let is_known =
+        |word: &str| is_operation(word) || macros.contains_key(&word.to_ascii_uppercase());
+
+    if let Some(len) = first_word(rest) {
+        let word = &rest[..len];
+        let after = rest[len..].trim_start();
+        let names_next = after.split_whitespace().next().is_some_and(|next| {
+            next.eq_ignore_ascii_case("EQU") || next.eq_ignore_ascii_case("MACRO")
+        });
+        let label = if let Some(label) = word.strip_suffix(':') {
+            Some(label)
+        } else if (!text.starts_with(char::is_whitespace) && !is_known(word)) || names_next {
+            Some(word)
+        } else {
+            None
+        };
+        if let Some(label) = label {
+            if !is_valid_label(label) {
+                return Err(ErrorKind::InvalidLabel(label.to_string()));
+            }
+            statement.label = Some(label.to_string());
+            rest = after;
+        }
+    }
+    if let Some(len) = first_word(rest) {
+        statement.operation = Some(rest[..len].to_ascii_uppercase());
+        statement.operands = split_operands(&rest[len..]);
+    }
+    Ok(statement)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i32),
+    Name(String),
+    Text(String),
+    Symbol(char),
+}
+
+fn parse_number(text: &str) -> Option<i32> {
+    let lower = text.to_ascii_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        (hex, 16)
+    } else if let Some(bin) = lower.strip_suffix('b') {
+        (bin, 2)
+    } else if let Some(oct) = lower.strip_suffix(['o', 'q']) {
+        (oct, 8)
+    } else if let Some(dec) = lower.strip_suffix('d') {
+        (dec, 10)
+    } else {
+        (lower.as_str(), 10)
+    };
+    u32::from_str_radix(digits, radix)
+        .ok()
+        .filter(|value| *value <= 0xffff)
+        .map(|value| value as i32)
+}
+
+/// Text of a quoted string, quotes doubled inside it standing for one.
+fn parse_string(text: &str) -> Option<String> {
+    let quote = text.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+    let inner = text.strip_prefix(quote)?.strip_suffix(quote)?;
+    let doubled = format!("{0}{0}", quote);
+    if inner.replace(&doubled, "").contains(quote) {
+        return None;
+    }
+    Some(inner.replace(&doubled, &quote.to_string()))
+}
+
+fn tokenize(text: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '\'' | '"' => {
+                let mut end = None;
+                while let Some((i, next)) = chars.next() {
+                    if next == c {
+                        if chars.peek().is_some_and(|(_, after)| *after == c) {
+                            chars.next();
+                        } else {
+                            end = Some(i);
+                            break;
+                        }
+                    }
+                }
+                tokens.push(Token::Text(parse_string(&text[start..=end?])?));
+            }
+            c if is_name_char(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, next)) = chars.peek().copied() {
+                    if !is_name_char(next) {
+                        break;
+                    }
+                    end = i + next.len_utf8();
+                    chars.next();
+                }
+                let word = &text[start..end];
+                tokens.push(if c.is_ascii_digit() {
+                    Token::Number(parse_number(word)?)
+                } else {
+                    Token::Name(word.to_string())
+                });
+            }
+            '+' | '-' | '*' | '/' | '(' | ')' | '$' => tokens.push(Token::Symbol(c)),
+            _ => return None,
+        }
+    }
+    Some(tokens)
+}
+
+/// What expressions are evaluated against.
+struct Scope<'a> {
+    symbols: &'a BTreeMap<String, u16>,
+    /// Value of `$`, the address of the current statement.
+    location: u16,
+    /// Label local names are relative to.
+    global: &'a str,
+    /// Whether values are checked. The first pass only sizes statements: it reads undefined
+    /// symbols as 0, and leaves range checks and division by zero to the second pass.
+    strict: bool,
+}
+
+impl Scope<'_> {
+    fn evaluate(&self, text: &str) -> Result<i32, ErrorKind> {
+        let invalid = || ErrorKind::InvalidExpression(text.to_string());
+        let tokens = tokenize(text).ok_or_else(invalid)?;
+        let mut parser = Parser {
+            scope: self,
+            tokens: &tokens,
+            position: 0,
+        };
+        let value = parser.expression()?.ok_or_else(invalid)?;
+        if parser.position != tokens.len() {
+            return Err(invalid());
+        }
+        Ok(value)
+    }
+
+    fn byte(&self, text: &str) -> Result<u8, ErrorKind> {
+        match self.evaluate(text)? {
+            value @ -128..=255 => Ok(value as u8),
+            value if !self.strict => Ok(value as u8),
+            value => Err(ErrorKind::OutOfRange(value, 8)),
+        }
+    }
+
+    fn word(&self, text: &str) -> Result<u16, ErrorKind> {
+        match self.evaluate(text)? {
+            value @ -32768..=65535 => Ok(value as u16),
+            value if !self.strict => Ok(value as u16),
+            value => Err(ErrorKind::OutOfRange(value, 16)),
+        }
+    }
+
+    fn symbol(&self, name: &str) -> Result<i32, ErrorKind> {
+        let name = qualify(name, self.global);
+        match self.symbols.get(&name) {
+            Some(value) => Ok(*value as i32),
+            None if !self.strict => Ok(0),
+            None => Err(ErrorKind::UndefinedSymbol(name)),
+        }
+    }
+}
+
+/// Recursive descent over the tokens of an expression. Each level returns `None` on a syntax
+/// error, which the caller reports for the whole expression.
+///
+/// From the loosest to the tightest: `OR`/`XOR`, `AND`, `NOT`, `+`/`-`, `*`/`/`/`MOD`/`SHL`/
+/// `SHR`, then the unary `-`, `+`, `HIGH` and `LOW`.
+struct Parser<'a> {
+    scope: &'a Scope<'a>,
+    tokens: &'a [Token],
+    position: usize,
+}
+
+type Parsed = Result<Option<i32>, ErrorKind>;
+
+impl Parser<'_> {
+    fn peek_operator(&self) -> Option<String> {
+        match self.tokens.get(self.position)? {
+            Token::Symbol(c) => Some(c.to_string()),
+            Token::Name(name) => Some(name.to_ascii_uppercase()),
+            _ => None,
+        }
+    }
+
+    fn binary(
+        &mut self,
+        operators: &[&str],
+        operand: fn(&mut Self) -> Parsed,
+        apply: fn(&str, i32, i32) -> Result<i32, ErrorKind>,
+    ) -> Parsed {
+        let Some(mut value) = operand(self)? else {
+            return Ok(None);
+        };
+        while let Some(operator) = self
+            .peek_operator()
+            .filter(|op| operators.contains(&op.as_str()))
+        {
+            self.position += 1;
+            let Some(right) = operand(self)? else {
+                return Ok(None);
+            };
+            value = match apply(&operator, value, right) {
+                Err(ErrorKind::DivisionByZero) if !self.scope.strict => 0,
+                result => result?,
+            };
+        }
+        Ok(Some(value))
+    }
+
+    fn expression(&mut self) -> Parsed {
+        self.binary(&["OR", "XOR"], Self::and, |op, a, b| {
+            Ok(if op == "OR" { a | b } else { a ^ b })
+        })
+    }
+
+    fn and(&mut self) -> Parsed {
+        self.binary(&["AND"], Self::not, |_, a, b| Ok(a & b))
+    }
+
+    fn not(&mut self) -> Parsed {
+        if self.peek_operator().as_deref() == Some("NOT") {
+            self.position += 1;
+            return Ok(self.not()?.map(|value| !value & 0xffff));
+        }
+        self.sum()
+    }
+
+    fn sum(&mut self) -> Parsed {
+        self.binary(&["+", "-"], Self::product, |op, a, b| {
+            Ok(if op == "+" {
+                a.wrapping_add(b)
+            } else {
+                a.wrapping_sub(b)
+            })
+        })
+    }
+
+    fn product(&mut self) -> Parsed {
+        self.binary(
+            &["*", "/", "MOD", "SHL", "SHR"],
+            Self::unary,
+            |op, a, b| match op {
+                "*" => Ok(a.wrapping_mul(b)),
+                "SHL" => Ok(a.wrapping_shl(b as u32) & 0xffff),
+                "SHR" => Ok(a.wrapping_shr(b as u32)),
+                _ if b == 0 => Err(ErrorKind::DivisionByZero),
+                "/" => Ok(a.wrapping_div(b)),
+                _ => Ok(a.wrapping_rem(b)),
+            },
+        )
+    }
+
+    fn unary(&mut self) -> Parsed {
+        let operator = self.peek_operator();
+        let apply: fn(i32) -> i32 = match operator.as_deref() {
+            Some("-") => i32::wrapping_neg,
+            Some("+") => |value| value,
+            Some("HIGH") => |value| (value >> 8) & 0xff,
+            Some("LOW") => |value| value & 0xff,
+            _ => return self.primary(),
+        };
+        self.position += 1;
+        Ok(self.unary()?.map(apply))
+    }
+
+    fn primary(&mut self) -> Parsed {
+        let Some(token) = self.tokens.get(self.position) else {
+            return Ok(None);
+        };
+        self.position += 1;
+        Ok(match token {
+            Token::Number(value) => Some(*value),
+            Token::Symbol('$') => Some(self.scope.location as i32),
+            Token::Name(name) => Some(self.scope.symbol(name)?),
+            Token::Text(text) => match text.as_bytes() {
+                [c] => Some(*c as i32),
+                [h, l] => Some(u16::from_be_bytes([*h, *l]) as i32),
+                _ => None,
+            },
+            Token::Symbol('(') => {
+                let value = self.expression()?;
+                if self.tokens.get(self.position) != Some(&Token::Symbol(')')) {
+                    return Ok(None);
+                }
+                self.position += 1;
+                value
+            }
+            Token::Symbol(_) => None,
+        })
+    }
+}
+
+fn register(text: &str) -> Result<Register, ErrorKind> {
+    Ok(match text.to_ascii_uppercase().as_str() {
+        "A" => Register::A,
+        "B" => Register::B,
+        "C" => Register::C,
+        "D" => Register::D,
+        "E" => Register::E,
+        "H" => Register::H,
+        "L" => Register::L,
+        "M" => Register::M,
+        _ => return Err(ErrorKind::InvalidRegister(text.to_string())),
+    })
+}
+
+fn register_pair(text: &str) -> Result<RegisterPair, ErrorKind> {
+    Ok(match text.to_ascii_uppercase().as_str() {
+        "B" => RegisterPair::B,
+        "D" => RegisterPair::D,
+        "H" => RegisterPair::H,
+        "SP" => RegisterPair::SP,
+        "PSW" => RegisterPair::PSW,
+        _ => return Err(ErrorKind::InvalidRegisterPair(text.to_string())),
+    })
+}
+
+/// Builds the instruction for `mnemonic`, checking that it can be encoded.
+fn instruction(
+    mnemonic: &str,
+    operands: &[String],
+    scope: &Scope,
+) -> Result<Instruction, ErrorKind> {
+    use Instruction::*;
+    let expected = match mnemonic {
+        "MOV" | "MVI" | "LXI" => 2,
+        "CMA" | "CMC" | "DAA" | "DI" | "EI" | "HLT" | "NOP" | "PCHL" | "RAL" | "RAR" | "RC"
+        | "RET" | "RLC" | "RM" | "RNC" | "RNZ" | "RP" | "RPE" | "RPO" | "RRC" | "RZ" | "SPHL"
+        | "STC" | "XCHG" | "XTHL" => 0,
+        _ => 1,
+    };
+    if operands.len() != expected {
+        return Err(ErrorKind::OperandCount(mnemonic.to_string(), expected));
+    }
+    let operand = |i: usize| operands[i].as_str();
+    let r = |i| register(operand(i));
+    let rp = |i| register_pair(operand(i));
+    let byte = |i| scope.byte(operand(i));
+    let word = |i| scope.word(operand(i));
+    let instruction = match mnemonic {
+        "MOV" => Mov(r(0)?, r(1)?),
+        "MVI" => Mvi(r(0)?, byte(1)?),
+        "LXI" => {
+            let [lo, hi] = word(1)?.to_le_bytes();
+            Lxi(rp(0)?, lo, hi)
+        }
+        "ADD" => Add(r(0)?),
+        "ADC" => Adc(r(0)?),
+        "SUB" => Sub(r(0)?),
+        "SBB" => Sbb(r(0)?),
+        "ANA" => Ana(r(0)?),
+        "XRA" => Xra(r(0)?),
+        "ORA" => Ora(r(0)?),
+        "CMP" => Cmp(r(0)?),
+        "INR" => Inr(r(0)?),
+        "DCR" => Dcr(r(0)?),
+        "INX" => Inx(rp(0)?),
+        "DCX" => Dcx(rp(0)?),
+        "DAD" => Dad(rp(0)?),
+        "PUSH" => Push(rp(0)?),
+        "POP" => Pop(rp(0)?),
+        "LDAX" => Ldax(rp(0)?),
+        "STAX" => Stax(rp(0)?),
+        "ADI" => Adi(byte(0)?),
+        "ACI" => Aci(byte(0)?),
+        "SUI" => Sui(byte(0)?),
+        "SBI" => Sbi(byte(0)?),
+        "ANI" => Ani(byte(0)?),
+        "XRI" => Xri(byte(0)?),
+        "ORI" => Ori(byte(0)?),
+        "CPI" => Cpi(byte(0)?),
+        "IN" => In(byte(0)?),
+        "OUT" => Out(byte(0)?),
+        "RST" => match scope.evaluate(operand(0))? {
+            n @ 0..=7 => Rst(n as u8),
+            _ if !scope.strict => Rst(0),
+            n => return Err(ErrorKind::OutOfRange(n, 3)),
+        },
+        "LDA" => Lda(word(0)?),
+        "STA" => Sta(word(0)?),
+        "LHLD" => Lhld(word(0)?),
+        "SHLD" => Shld(word(0)?),
+        "JMP" => Jmp(word(0)?),
+        "JNZ" => Jnz(word(0)?),
+        "JZ" => Jz(word(0)?),
+        "JNC" => Jnc(word(0)?),
+        "JC" => Jc(word(0)?),
+        "JPO" => Jpo(word(0)?),
+        "JPE" => Jpe(word(0)?),
+        "JP" => Jp(word(0)?),
+        "JM" => Jm(word(0)?),
+        "CALL" => Call(word(0)?),
+        "CNZ" => Cnz(word(0)?),
+        "CZ" => Cz(word(0)?),
+        "CNC" => Cnc(word(0)?),
+        "CC" => Cc(word(0)?),
+        "CPO" => Cpo(word(0)?),
+        "CPE" => Cpe(word(0)?),
+        "CP" => Cp(word(0)?),
+        "CM" => Cm(word(0)?),
+        "RET" => Ret,
+        "RNZ" => Rnz,
+        "RZ" => Rz,
+        "RNC" => Rnc,
+        "RC" => Rc,
+        "RPO" => Rpo,
+        "RPE" => Rpe,
+        "RP" => Rp,
+        "RM" => Rm,
+        "CMA" => Cma,
+        "CMC" => Cmc,
+        "DAA" => Daa,
+        "DI" => Di,
+        "EI" => Ei,
+        "HLT" => Hlt,
+        "NOP" => Nop,
+        "PCHL" => Pchl,
+        "RAL" => Ral,
+        "RAR" => Rar,
+        "RLC" => Rlc,
+        "RRC" => Rrc,
+        "SPHL" => Sphl,
+        "STC" => Stc,
+        "XCHG" => Xchg,
+        "XTHL" => Xthl,
+        _ => return Err(ErrorKind::UnknownOperation(mnemonic.to_string())),
+    };
+    match instruction.encode() {
+        Some(_) => Ok(instruction),
+        None => Err(ErrorKind::InvalidOperands(mnemonic.to_string())),
+    }
+}
+
+/// State of one pass over the source.
+#[derive(Default)]
+struct Assembler {
+    symbols: BTreeMap<String, u16>,
+    location: u16,
+    /// Last label not starting with `.`, which scopes the local labels that follow it.
+    global: String,
+    /// Assembled bytes by address, only filled by the second pass.
+    image: BTreeMap<u16, u8>,
+    /// First `ORG`, the origin of a program that assembles to nothing.
+    origin: Option<u16>,
+    start: Option<u16>,
+}
+
+impl Assembler {
+    fn scope(&self, strict: bool) -> Scope<'_> {
+        Scope {
+            symbols: &self.symbols,
+            location: self.location,
+            global: &self.global,
+            strict,
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), ErrorKind> {
+        for byte in bytes {
+            if self.image.insert(self.location, *byte).is_some() {
+                return Err(ErrorKind::Overlap(self.location));
+            }
+            self.location = self.location.wrapping_add(1);
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, value: u16) -> Result<(), ErrorKind> {
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            return Err(ErrorKind::DuplicateSymbol(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Runs `statement` through the first pass, which only computes addresses, or the second,
+    /// which assembles it. Returns `false` at `END`.
+    fn statement(&mut self, statement: &Statement, second_pass: bool) -> Result<bool, ErrorKind> {
+        let operation = statement.operation.as_deref();
+        let operands = &statement.operands;
+        let count = |expected: usize| {
+            if operands.len() == expected {
+                Ok(())
+            } else {
+                Err(ErrorKind::OperandCount(
+                    operation.unwrap_or_default().to_string(),
+                    expected,
+                ))
+            }
+        };
+        let label = statement
+            .label
+            .as_deref()
+            .map(|label| qualify(label, &self.global));
+
+        if operation == Some("EQU") {
+            let name = label.ok_or_else(|| ErrorKind::MissingLabel("EQU".to_string()))?;
+            count(1)?;
+            // `EQU`s are all defined by the end of the first pass, see `assemble_lines`.
+            if !second_pass {
+                if let Ok(value) = self.scope(true).word(&operands[0]) {
+                    self.define(&name, value)?;
+                }
+            }
+            return Ok(true);
+        }
+        if operation == Some("ORG") {
+            count(1)?;
+            self.location = self.scope(true).word(&operands[0])?;
+            self.origin.get_or_insert(self.location);
+        }
+        if let (Some(label), Some(written)) = (label, &statement.label) {
+            if !second_pass {
+                self.define(&label, self.location)?;
+            }
+            // Names generated for `LOCAL` do not open a scope either.
+            if !written.starts_with('.') && !written.starts_with("??") {
+                self.global = label;
+            }
+        }
+
+        let scope = self.scope(second_pass);
+        match operation {
+            None | Some("ORG") => {}
+            Some("END") => {
+                if let Some(start) = operands.first() {
+                    self.start = Some(scope.word(start)?);
+                }
+                return Ok(false);
+            }
+            Some("DB") => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    match parse_string(operand) {
+                        Some(text) if text.len() != 1 => bytes.extend(text.bytes()),
+                        _ => bytes.push(scope.byte(operand)?),
+                    }
+                }
+                self.put(&bytes, second_pass)?;
+            }
+            Some("DW") => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    bytes.extend(scope.word(operand)?.to_le_bytes());
+                }
+                self.put(&bytes, second_pass)?;
+            }
+            Some("DS") => {
+                count(1)?;
+                let size = self.scope(true).word(&operands[0])?;
+                self.location = self.location.wrapping_add(size);
+            }
+            Some(mnemonic) => {
+                let instruction = instruction(mnemonic, operands, &scope)?;
+                let bytes = instruction.encode().unwrap_or_default();
+                self.put(&bytes, second_pass)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Emits `bytes` in the second pass, only advances the location in the first.
+    fn put(&mut self, bytes: &[u8], second_pass: bool) -> Result<(), ErrorKind> {
+        if second_pass {
+            self.emit(bytes)
+        } else {
+            self.location = self.location.wrapping_add(bytes.len() as u16);
+            Ok(())
+        }
+    }
+}
+
+/// Full name of `label`: local labels, starting with `.`, belong to the last global one.
+fn qualify(label: &str, global: &str) -> String {
+    if label.starts_with('.') {
+        format!("{}{}", global, label)
+    } else {
+        label.to_string()
+    }
+}
+
+/// Source line, with where it comes from.
+#[derive(Debug, Clone)]
+struct SourceLine {
+    text: String,
+    location: Location,
+    /// Macro calls the line was expanded from, innermost first.
+    expansion: Vec<(String, Location)>,
+}
+
+impl SourceLine {
+    fn error(&self, kind: ErrorKind) -> AssemblerError {
+        AssemblerError {
+            location: self.location.clone(),
+            kind,
+            expansion: self.expansion.clone(),
+        }
+    }
+}
+
+fn source_lines(source: &str, file: Option<&Path>) -> Vec<SourceLine> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| SourceLine {
+            text: text.to_string(),
+            location: Location {
+                file: file.map(Path::to_path_buf),
+                line: i + 1,
+            },
+            expansion: Vec::new(),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<SourceLine>,
+}
+
+impl Macro {
+    /// Body of the macro for a call with `arguments`, `LOCAL` names replaced by `??nnnn`
+    /// names numbered from `unique`, each line along with the one it was expanded from.
+    fn expand(&self, arguments: &[String], unique: &mut usize) -> Vec<(String, &SourceLine)> {
+        let mut substitutions = self
+            .parameters
+            .iter()
+            .zip(arguments.iter().chain(std::iter::repeat(&String::new())))
+            .map(|(parameter, argument)| (parameter.clone(), argument.clone()))
+            .collect::<Vec<_>>();
+        let mut body = Vec::new();
+        let mut depth = 0;
+        for line in &self.body {
+            let operation = parse_statement(&line.text, &BTreeMap::new())
+                .ok()
+                .and_then(|statement| Some((statement.operation?, statement.operands)));
+            match operation {
+                Some((operation, names)) if operation == "LOCAL" && depth == 0 => {
+                    for name in names {
+                        *unique += 1;
+                        substitutions.push((name, format!("??{:04}", unique)));
+                    }
+                    continue;
+                }
+                Some((operation, _)) if operation == "MACRO" => depth += 1,
+                Some((operation, _)) if operation == "ENDM" => depth -= 1,
+                _ => {}
+            }
+            body.push((substitute(&line.text, &substitutions), line));
+        }
+        body
+    }
+}
+
+/// Replaces the names in `text` found in `substitutions`, outside quotes. `&` joins a
+/// parameter to surrounding text and is dropped.
+fn substitute(text: &str, substitutions: &[(String, String)]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut quoted = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match (quoted, c) {
+            (Some(quote), c) if c == quote => quoted = None,
+            (None, '\'' | '"') => quoted = Some(c),
+            (None, '&') => continue,
+            (None, ';') => {
+                out.push_str(&text[start..]);
+                break;
+            }
+            (None, c) if is_name_char(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, next)) = chars.peek().copied() {
+                    if !is_name_char(next) {
+                        break;
+                    }
+                    end = i + next.len_utf8();
+                    chars.next();
+                }
+                let word = &text[start..end];
+                let replacement = substitutions
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(word));
+                out.push_str(replacement.map_or(word, |(_, value)| value.as_str()));
+                continue;
+            }
+            _ => {}
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// State of an `IF` block.
+struct Condition {
+    /// Whether the enclosing code is assembled at all.
+    enclosing: bool,
+    value: bool,
+    in_else: bool,
+    line: SourceLine,
+}
+
+impl Condition {
+    fn active(&self) -> bool {
+        self.enclosing && self.value != self.in_else
+    }
+}
+
+/// Macro being defined, up to its `ENDM`.
+struct Definition {
+    name: String,
+    definition: Macro,
+    /// Nested `MACRO`s, whose `ENDM`s belong to the body.
+    depth: usize,
+    line: SourceLine,
+}
+
+/// Assembles Intel 8080 source, `INCLUDE`s being read relative to the current directory.
+pub fn assemble(source: &str) -> Result<Program, AssemblerError> {
+    assemble_lines(source_lines(source, None), Path::new(""))
+}
+
+/// Assembles the Intel 8080 source in `path`; errors name the file they occur in and
+/// `INCLUDE`s are read relative to the including file.
+pub fn assemble_file(path: impl AsRef<Path>) -> Result<Program, AssemblerError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| AssemblerError {
+        location: Location {
+            file: Some(path.to_path_buf()),
+            line: 0,
+        },
+        kind: ErrorKind::Include(path.display().to_string(), e.to_string()),
+        expansion: Vec::new(),
+    })?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    assemble_lines(source_lines(&source, Some(path)), dir)
+}
+
+/// Two pass assembly of `lines`.
+///
+/// The first pass expands `INCLUDE`s and macros, evaluates `IF`s and assigns an address to
+/// every label; the second one encodes the statements the first one kept. Operands of `ORG`,
+/// `DS` and `IF` must therefore only refer to symbols defined above them, while `EQU` may
+/// refer to any label.
+fn assemble_lines(lines: Vec<SourceLine>, dir: &Path) -> Result<Program, AssemblerError> {
+    let mut assembler = Assembler::default();
+    let mut statements = Vec::new();
+    let mut equs = Vec::new();
+    let mut macros = BTreeMap::new();
+    let mut conditions: Vec<Condition> = Vec::new();
+    let mut definition: Option<Definition> = None;
+    let mut unique = 0;
+    // Lines still to read from each file or macro expansion being assembled, innermost last.
+    let mut sources = vec![(lines.into_iter(), dir.to_path_buf())];
+
+    while let Some((lines, dir)) = sources.last_mut() {
+        let dir = dir.clone();
+        let Some(line) = lines.next() else {
+            sources.pop();
+            continue;
+        };
+        let error = |kind| line.error(kind);
+        let enabled = conditions.iter().all(Condition::active);
+        let statement = match parse_statement(&line.text, &macros) {
+            Ok(statement) => statement,
+            Err(_) if definition.is_some() || !enabled => Statement::default(),
+            Err(kind) => return Err(error(kind)),
+        };
+        let operation = statement.operation.as_deref();
+
+        if let Some(current) = &mut definition {
+            match operation {
+                Some("MACRO") => current.depth += 1,
+                Some("ENDM") if current.depth == 0 => {
+                    let Definition {
+                        name, definition, ..
+                    } = definition.take().unwrap_or_else(|| unreachable!());
+                    macros.insert(name, definition);
+                    continue;
+                }
+                Some("ENDM") => current.depth -= 1,
+                _ => {}
+            }
+            current.definition.body.push(line);
+            continue;
+        }
+
+        match operation {
+            Some("IF") => {
+                let value = if enabled {
+                    if statement.operands.len() != 1 {
+                        return Err(error(ErrorKind::OperandCount("IF".to_string(), 1)));
+                    }
+                    assembler
+                        .scope(true)
+                        .evaluate(&statement.operands[0])
+                        .map_err(error)?
+                        != 0
+                } else {
+                    false
+                };
+                conditions.push(Condition {
+                    enclosing: enabled,
+                    value,
+                    in_else: false,
+                    line,
+                });
+                continue;
+            }
+            Some("ELSE") => {
+                match conditions.last_mut() {
+                    Some(condition) if !condition.in_else => condition.in_else = true,
+                    _ => return Err(error(ErrorKind::Unmatched("ELSE".to_string(), "IF"))),
+                }
+                continue;
+            }
+            Some("ENDIF") => {
+                if conditions.pop().is_none() {
+                    return Err(error(ErrorKind::Unmatched("ENDIF".to_string(), "IF")));
+                }
+                continue;
+            }
+            _ if !enabled => continue,
+            Some("MACRO") => {
+                let name = statement
+                    .label
+                    .ok_or_else(|| error(ErrorKind::MissingLabel("MACRO".to_string())))?;
+                definition = Some(Definition {
+                    name: name.to_ascii_uppercase(),
+                    definition: Macro {
+                        parameters: statement.operands,
+                        body: Vec::new(),
+                    },
+                    depth: 0,
+                    line,
+                });
+                continue;
+            }
+            Some(directive @ ("ENDM" | "LOCAL")) => {
+                return Err(error(ErrorKind::Unmatched(directive.to_string(), "MACRO")));
+            }
+            Some("INCLUDE") => {
+                let [file] = &statement.operands[..] else {
+                    return Err(error(ErrorKind::OperandCount("INCLUDE".to_string(), 1)));
+                };
+                if sources.len() >= MAX_NESTING {
+                    return Err(error(ErrorKind::NestingTooDeep));
+                }
+                let path = dir.join(parse_string(file).unwrap_or(file.to_string()));
+                let source = fs::read_to_string(&path).map_err(|e| {
+                    error(ErrorKind::Include(
+                        path.display().to_string(),
+                        e.to_string(),
+                    ))
+                })?;
+                let mut included = source_lines(&source, Some(&path));
+                for included in &mut included {
+                    included.expansion = line.expansion.clone();
+                }
+                let parent = path.parent().unwrap_or(Path::new("")).to_path_buf();
+                sources.push((included.into_iter(), parent));
+                continue;
+            }
+            Some(name) if macros.contains_key(name) => {
+                let called = &macros[name];
+                if statement.operands.len() > called.parameters.len() {
+                    return Err(error(ErrorKind::TooManyArguments(
+                        name.to_string(),
+                        called.parameters.len(),
+                    )));
+                }
+                if sources.len() >= MAX_NESTING {
+                    return Err(error(ErrorKind::NestingTooDeep));
+                }
+                let mut expansion = vec![(name.to_string(), line.location.clone())];
+                expansion.extend(line.expansion.iter().cloned());
+                let mut expanded = Vec::new();
+                if let Some(label) = &statement.label {
+                    expanded.push(SourceLine {
+                        text: format!("{}:", label),
+                        ..line.clone()
+                    });
+                }
+                let body = called.expand(&statement.operands, &mut unique);
+                for (text, source) in body {
+                    expanded.push(SourceLine {
+                        text,
+                        location: source.location.clone(),
+                        expansion: expansion.clone(),
+                    });
+                }
+                sources.push((expanded.into_iter(), dir));
+                continue;
+            }
+            _ => {}
+        }
+
+        if operation == Some("EQU") {
+            equs.push((
+                statements.len(),
+                assembler.location,
+                assembler.global.clone(),
+            ));
+        }
+        let more = assembler.statement(&statement, false).map_err(error)?;
+        statements.push((statement, line));
+        if !more {
+            break;
+        }
+    }
+    if let Some(definition) = definition {
+        let kind = ErrorKind::Unterminated(format!("macro {}", definition.name), "ENDM");
+        return Err(definition.line.error(kind));
+    }
+    if let Some(condition) = conditions.pop() {
+        return Err(condition
+            .line
+            .error(ErrorKind::Unterminated("IF".to_string(), "ENDIF")));
+    }
+
+    // `EQU`s referring to later symbols, resolved once everything else is known.
+    loop {
+        let before = equs.len();
+        let mut failed = Vec::new();
+        for (i, location, global) in equs {
+            let (statement, line) = &statements[i];
+            let Some(name) = statement
+                .label
+                .as_deref()
+                .map(|label| qualify(label, &global))
+                .filter(|name| !assembler.symbols.contains_key(name))
+            else {
+                continue;
+            };
+            let scope = Scope {
+                symbols: &assembler.symbols,
+                location,
+                global: &global,
+                strict: true,
+            };
+            match scope.word(&statement.operands[0]) {
+                Ok(value) => {
+                    assembler.symbols.insert(name, value);
+                }
+                Err(kind) => failed.push((i, location, global, line.error(kind))),
+            }
+        }
+        if failed.is_empty() {
+            break;
+        }
+        if failed.len() == before {
+            return Err(failed.swap_remove(0).3);
+        }
+        equs = failed
+            .into_iter()
+            .map(|(i, location, global, _)| (i, location, global))
+            .collect();
+    }
+
+    assembler.location = 0;
+    assembler.global.clear();
+    for (statement, line) in &statements {
+        if !assembler
+            .statement(statement, true)
+            .map_err(|kind| line.error(kind))?
+        {
+            break;
+        }
+    }
+
+    let origin = match assembler.image.first_key_value() {
+        Some((first, _)) => *first,
+        None => assembler.origin.unwrap_or_default(),
+    };
+    let mut code = Vec::new();
+    for (addr, byte) in &assembler.image {
+        code.resize((addr - origin) as usize, 0);
+        code.push(*byte);
+    }
+    Ok(Program {
+        origin,
+        code,
+        symbols: assembler.symbols,
+        start: assembler.start,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble, assemble_file, AssemblerError, ErrorKind, Location};
+    use crate::disassembler::{Analysis, DEFAULT_ENTRY_POINTS};
+    use crate::symbols::SymbolTable;
+    use crate::syntax::Syntax;
+
+    #[test]
+    fn assembles_directives_and_expressions() {
+        let source = "\
+BDOS    EQU 5
+        ORG 100H
+start:  MVI C,PRINT     ; function number
+        LXI D,message
+        CALL BDOS
+        JMP $+3
+        RST 7
+        LXI H,(finish-start) SHL 1
+        MVI A,HIGH finish AND 0FH
+        DW start,-1
+        DS 2
+message DB 'It''s',0DH,'$'
+finish:
+PRINT   EQU 9
+        END start
+";
+        let program = assemble(source).unwrap();
+        assert_eq!(program.origin, 0x100);
+        assert_eq!(
+            program.code,
+            [
+                0x0e, 0x09, 0x11, 0x17, 0x01, 0xcd, 0x05, 0x00, 0xc3, 0x0b, 0x01, 0xff, 0x21, 0x3a,
+                0x00, 0x3e, 0x01, 0x00, 0x01, 0xff, 0xff, 0x00, 0x00, b'I', b't', b'\'', b's',
+                0x0d, b'$'
+            ]
+        );
+        assert_eq!(program.symbols["message"], 0x117);
+        assert_eq!(program.symbols["PRINT"], 9);
+        assert_eq!(program.start, Some(0x100));
+        let symbols = SymbolTable::parse(&program.symbol_file()).unwrap();
+        assert_eq!(symbols.resolve("finish"), Some(0x11d));
+    }
+
+    #[test]
+    fn reports_line_numbers() {
+        let error = |source| assemble(source).unwrap_err();
+        assert_eq!(
+            error("  NOP\n  MOV A,X\n"),
+            AssemblerError {
+                location: Location {
+                    file: None,
+                    line: 2
+                },
+                kind: ErrorKind::InvalidRegister("X".to_string()),
+                expansion: Vec::new(),
+            }
+        );
+        assert_eq!(
+            error("  JMP nowhere").kind,
+            ErrorKind::UndefinedSymbol("nowhere".to_string())
+        );
+        assert_eq!(
+            error("a: NOP\na: NOP").to_string(),
+            "Line 2: \"a\" is already defined."
+        );
+        assert_eq!(
+            error("  PUSH SP").kind,
+            ErrorKind::InvalidOperands("PUSH".to_string())
+        );
+        assert_eq!(error("  MVI A,256").kind, ErrorKind::OutOfRange(256, 8));
+        assert_eq!(error("  DW 1/0").kind, ErrorKind::DivisionByZero);
+    }
+
+    #[test]
+    fn forward_references_are_checked_once_defined() {
+        let program = assemble("  MVI A,later-100H\n  DS 120H\nlater: NOP").unwrap();
+        assert_eq!(program.code[..2], [0x3e, 0x22]);
+        let program = assemble("  DW 100/size\n  RST size-1\nsize EQU 4").unwrap();
+        assert_eq!(program.code, [25, 0, 0xdf]);
+        assert_eq!(
+            assemble("  MVI A,later\n  DS 120H\nlater: NOP")
+                .unwrap_err()
+                .kind,
+            ErrorKind::OutOfRange(0x122, 8)
+        );
+    }
+
+    #[test]
+    fn macros_conditions_and_includes() {
+        let dir = std::env::temp_dir().join(format!("asm-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, text: &str| std::fs::write(dir.join(name), text).unwrap();
+        write(
+            "macros.inc",
+            "\
+PRINT   MACRO msg
+        LOCAL skip
+        LXI D,msg
+        JMP skip
+        NOP
+skip:   CALL BDOS
+        ENDM
+COPY    MACRO from, to
+        LDA from
+        STA to&H
+        ENDM
+",
+        );
+        write(
+            "main.asm",
+            "\
+BDOS    EQU 5
+DEBUG   EQU 0
+        INCLUDE 'macros.inc'
+        ORG 100H
+start:  PRINT text
+.loop:  JMP .loop
+        IF DEBUG
+        HLT
+        ELSE
+        COPY 1,20
+        ENDIF
+other:
+.loop:  JMP .loop
+text:   DB '$'
+",
+        );
+        write("bad.asm", "        INCLUDE 'macros.inc'\n        COPY X\n");
+        write(
+            "local.asm",
+            "M1      MACRO\n        LOCAL skip\n        MOV A,X\nskip:   NOP\n        ENDM\n        M1\n",
+        );
+
+        let program = assemble_file(dir.join("main.asm")).unwrap();
+        assert_eq!(
+            program.code,
+            [
+                0x11, 0x16, 0x01, 0xc3, 0x07, 0x01, 0x00, 0xcd, 0x05, 0x00, 0xc3, 0x0a, 0x01, 0x3a,
+                0x01, 0x00, 0x32, 0x20, 0x00, 0xc3, 0x13, 0x01, b'$'
+            ]
+        );
+        assert_eq!(program.symbols["start.loop"], 0x10a);
+        assert_eq!(program.symbols["other.loop"], 0x113);
+        assert_eq!(program.symbols["??0001"], 0x107);
+
+        let error = assemble_file(dir.join("bad.asm")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{}:9: undefined symbol \"X\". In COPY called at {}:2.",
+                dir.join("macros.inc").display(),
+                dir.join("bad.asm").display()
+            )
+        );
+
+        // Lines after a LOCAL keep their own location.
+        let error = assemble_file(dir.join("local.asm")).unwrap_err();
+        let local = dir.join("local.asm");
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{}:3: \"X\" is not a register. In M1 called at {}:6.",
+                local.display(),
+                local.display()
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disassembled_source_round_trips() {
+        let rom = include_bytes!("../roms/cputest");
+        let mut entry_points = DEFAULT_ENTRY_POINTS.to_vec();
+        entry_points.push(0x100);
+        let analysis = Analysis::new(rom, 0x100, &entry_points);
+        let source = analysis.source(rom, &SymbolTable::new(), Syntax::Intel);
+        let program = assemble(&source).unwrap();
+        assert_eq!(program.origin, 0x100);
+        assert_eq!(program.code, rom);
+    }
+}
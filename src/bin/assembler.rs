@@ -0,0 +1,57 @@
+use emulator8080::{
+    assembler::assemble_file,
+    cli::{take_flag, take_option},
+    intel_hex::{self, HexImage},
+};
+use std::env::args;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("No input file given.")]
+    MissingCliArgument,
+}
+
+const USAGE: &str = "\
+usage: assembler <file> [options]
+
+  -o <file>          binary output (default: the input with a .bin extension); written as
+                     Intel HEX, with the address given to END as start, for .hex and .ihx
+  --symbols <file>   symbol file output (default: the input with a .sym extension)
+
+Assembles Intel 8080 source into a flat binary starting at the lowest address assembled,
+and writes every label and EQU to the symbol file, as read by the other tools.
+Supports MACRO/ENDM with LOCAL labels, IF/ELSE/ENDIF, INCLUDE (relative to the including
+file) and local labels starting with `.`, scoped by the previous label.";
+
+fn main() -> anyhow::Result<()> {
+    let mut args = args().collect::<Vec<_>>();
+    if take_flag(&mut args, "--help") || take_flag(&mut args, "-h") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let output = take_option(&mut args, "-o")?;
+    let symbols = take_option(&mut args, "--symbols")?;
+
+    let fname = PathBuf::from(args.get(1).ok_or(Error::MissingCliArgument)?);
+    let program = assemble_file(&fname)?;
+
+    let output = output.map_or_else(|| fname.with_extension("bin"), PathBuf::from);
+    let symbols = symbols.map_or_else(|| fname.with_extension("sym"), PathBuf::from);
+    if intel_hex::is_hex_file(&output) {
+        let mut image = HexImage::new(program.origin, &program.code);
+        image.start = program.start.map(u32::from);
+        fs::write(&output, image.to_string())?;
+    } else {
+        fs::write(&output, &program.code)?;
+    }
+    fs::write(&symbols, program.symbol_file())?;
+    println!(
+        "{}: {} bytes at {:04x}",
+        output.display(),
+        program.code.len(),
+        program.origin
+    );
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use emulator8080::{
+    cli::{take_flag, take_option},
+    cpm::{Console, Exit, StdConsole},
+    cpm_disk::{Disk, DiskFormat},
+    cpm_machine::CpmMachine,
+};
+use ratatui::crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    terminal,
+};
+use std::env::args;
+use std::io::{self, IsTerminal, Write};
+use std::time::Duration;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("No disk image given.")]
+    MissingCliArgument,
+
+    #[error("At most 16 disk images can be inserted.")]
+    TooManyDisks,
+
+    #[error("{0:?} is not a disk format, expected 3740 or hd.")]
+    InvalidFormat(String),
+}
+
+const USAGE: &str = "\
+usage: cpm [--format <3740|hd>] <a.dsk> [b.dsk ...]
+
+  --format <format>  format of every image: 3740 for 8\" IBM 3740 single density disks,
+                     hd for the 4 MB z80pack hard disk; short images are padded
+
+Boots CP/M 2.2 from the system tracks of the first image, with the following images in
+drives B, C and so on. Without --format, 3740 images (256256 bytes) and hard disk images
+are recognized by their size; shorter images are taken as 3740 disks.
+Sectors are written back to the images as the system writes them.
+
+The console is the terminal, in raw mode when it is one; Ctrl-\\ quits.";
+
+fn main() -> anyhow::Result<()> {
+    let mut args = args().collect::<Vec<_>>();
+    if take_flag(&mut args, "--help") || take_flag(&mut args, "-h") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let format = match take_option(&mut args, "--format")?.as_deref() {
+        Some("3740") => Some(DiskFormat::ibm_3740()),
+        Some("hd") => Some(DiskFormat::z80pack_hd()),
+        Some(other) => return Err(Error::InvalidFormat(other.to_string()).into()),
+        None => None,
+    };
+    if args.len() < 2 {
+        return Err(Error::MissingCliArgument.into());
+    }
+    if args.len() > 17 {
+        return Err(Error::TooManyDisks.into());
+    }
+    let disks = args[1..]
+        .iter()
+        .map(|path| match &format {
+            Some(format) => Disk::open_as(path, format.clone()),
+            None => Disk::open(path),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let exit = if io::stdin().is_terminal() {
+        run(CpmMachine::new(TerminalConsole::new()?), disks)?
+    } else {
+        run(CpmMachine::new(StdConsole::default()), disks)?
+    };
+    if exit == Exit::Halt {
+        eprintln!("Halted.");
+    }
+    Ok(())
+}
+
+fn run<C: Console>(mut machine: CpmMachine<C>, disks: Vec<Disk>) -> anyhow::Result<Exit> {
+    for (drive, disk) in disks.into_iter().enumerate() {
+        machine.insert(drive as u8, disk);
+    }
+    machine.boot()?;
+    let result = machine.run();
+    if result.is_err() {
+        machine.system().dump_state();
+    }
+    Ok(result?)
+}
+
+/// Console on a terminal in raw mode, so that programs get keys as they are typed.
+struct TerminalConsole {
+    /// Key read while checking whether one is ready.
+    pending: Option<u8>,
+    /// Whether Ctrl-\ was typed.
+    quit: bool,
+}
+
+impl TerminalConsole {
+    fn new() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(TerminalConsole {
+            pending: None,
+            quit: false,
+        })
+    }
+
+    /// Waits up to `timeout` for a key; `None` waits as long as it takes. Returns `None`
+    /// on Ctrl-\ or when the terminal fails.
+    fn next_key(&mut self, timeout: Option<Duration>) -> Option<Option<u8>> {
+        let _ = io::stdout().flush();
+        loop {
+            if let Some(timeout) = timeout {
+                if !event::poll(timeout).ok()? {
+                    return Some(None);
+                }
+            }
+            if let Event::Key(key) = event::read().ok()? {
+                if key.kind == KeyEventKind::Press {
+                    if is_quit(&key) {
+                        self.quit = true;
+                        return None;
+                    }
+                    if let Some(byte) = key_byte(&key) {
+                        return Some(Some(byte));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for TerminalConsole {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+impl Console for TerminalConsole {
+    fn read(&mut self) -> Option<u8> {
+        match self.pending.take() {
+            Some(byte) => Some(byte),
+            None if self.quit => None,
+            None => self.next_key(None)?,
+        }
+    }
+
+    fn ready(&mut self) -> bool {
+        if self.pending.is_none() {
+            // On Ctrl-\ a key is reported ready, so that the read ends the session.
+            if let Some(key) = self.next_key(Some(Duration::ZERO)) {
+                self.pending = key;
+            }
+        }
+        self.pending.is_some() || self.quit
+    }
+
+    fn write(&mut self, byte: u8) {
+        let _ = io::stdout().write_all(&[byte]);
+    }
+}
+
+fn is_quit(key: &KeyEvent) -> bool {
+    key.modifiers.contains(KeyModifiers::CONTROL)
+        && matches!(key.code, KeyCode::Char('\\') | KeyCode::Char('4'))
+}
+
+/// ASCII code CP/M expects for `key`.
+fn key_byte(key: &KeyEvent) -> Option<u8> {
+    match key.code {
+        KeyCode::Char(c) if c.is_ascii() => {
+            if key.modifiers.contains(KeyModifiers::CONTROL) {
+                Some(c as u8 & 0x1f)
+            } else {
+                Some(c as u8)
+            }
+        }
+        KeyCode::Enter => Some(b'\r'),
+        KeyCode::Backspace => Some(0x08),
+        KeyCode::Tab => Some(b'\t'),
+        KeyCode::Esc => Some(0x1b),
+        KeyCode::Delete => Some(0x7f),
+        _ => None,
+    }
+}
@@ -0,0 +1,908 @@
+//! Debug Adapter Protocol server speaking over stdin/stdout.
+//!
+//! The program is presented to the editor as a single disassembly listing (`sourceReference`
+//! 1), labelled from the symbol file given in the `symbols` launch argument if any. Registers
+//! and flags are exposed as variables, memory through `readMemory`.
+
+use emulator8080::{
+    cpu_state::{Flag, MemoryAccess, Ram, System},
+    debugger::{Debugger, DebuggerError, StopReason},
+    in_out::PortLatch,
+    op_code::{Instruction, Register, RegisterPair},
+    symbols::SymbolTable,
+};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{stdin, stdout, BufRead, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Malformed message header {0:?}.")]
+    InvalidHeader(String),
+
+    #[error("Missing argument {0:?}.")]
+    MissingArgument(&'static str),
+
+    #[error("Invalid value for argument {0:?}.")]
+    InvalidArgument(&'static str),
+
+    #[error("No program has been launched.")]
+    NotLaunched,
+
+    #[error("Unknown symbol {0:?}.")]
+    UnknownSymbol(String),
+}
+
+const THREAD_ID: u64 = 1;
+const LISTING_REFERENCE: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+const FLAGS_REFERENCE: u64 = 2;
+/// Size of the 8080 address space.
+const MEMORY_SIZE: i64 = 0x10000;
+/// Instructions executed between two checks for incoming requests while running.
+const RUN_CHUNK: u64 = 20_000;
+/// Upper bound for `next` and `stepOut`, so a runaway subroutine cannot wedge the server.
+const STEP_LIMIT: u64 = 10_000_000;
+
+fn main() -> anyhow::Result<()> {
+    let requests = spawn_reader();
+    let mut server = Server::new(Box::new(stdout()));
+    loop {
+        let message = if server.running {
+            match requests.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        } else {
+            match requests.recv() {
+                Ok(message) => Some(message),
+                Err(_) => return Ok(()),
+            }
+        };
+
+        match message {
+            Some(Ok(request)) => {
+                if !server.handle(request)? {
+                    return Ok(());
+                }
+            }
+            Some(Err(e)) => return Err(e),
+            None => server.run_chunk()?,
+        }
+    }
+}
+
+/// Reads framed messages on a separate thread so that requests such as `pause` can be
+/// received while the emulator is running.
+fn spawn_reader() -> Receiver<anyhow::Result<Value>> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut input = stdin().lock();
+        loop {
+            let message = read_message(&mut input);
+            let stop = !matches!(message, Ok(Some(_)));
+            if let Some(message) = message.transpose() {
+                if sender.send(message).is_err() {
+                    return;
+                }
+            }
+            if stop {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+fn read_message(input: &mut impl BufRead) -> anyhow::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = Some(
+                value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| Error::InvalidHeader(header.to_string()))?,
+            );
+        }
+    }
+    let length = length.ok_or_else(|| Error::InvalidHeader(String::new()))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// One line of the disassembly listing shown to the editor.
+struct ListingLine {
+    addr: u16,
+    text: String,
+    is_label: bool,
+}
+
+struct Session {
+    debugger: Debugger,
+    name: String,
+    symbols: SymbolTable,
+    listing: Vec<ListingLine>,
+    addr_to_line: HashMap<u16, usize>,
+    stop_on_entry: bool,
+}
+
+struct Server {
+    /// Where messages are written, stdout outside of tests.
+    out: Box<dyn Write>,
+    seq: u64,
+    session: Option<Session>,
+    running: bool,
+    source_breakpoints: BTreeSet<u16>,
+    function_breakpoints: BTreeSet<u16>,
+    instruction_breakpoints: BTreeSet<u16>,
+    last_step: Option<Result<StopReason, DebuggerError>>,
+}
+
+impl Server {
+    fn new(out: Box<dyn Write>) -> Self {
+        Server {
+            out,
+            seq: 0,
+            session: None,
+            running: false,
+            source_breakpoints: BTreeSet::new(),
+            function_breakpoints: BTreeSet::new(),
+            instruction_breakpoints: BTreeSet::new(),
+            last_step: None,
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> anyhow::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = serde_json::to_string(&message)?;
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> anyhow::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) -> anyhow::Result<()> {
+        self.running = false;
+        self.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "description": description,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        )
+    }
+
+    fn output(&mut self, text: String) -> anyhow::Result<()> {
+        self.event("output", json!({ "category": "console", "output": text }))
+    }
+
+    /// Handles one request; returns `false` once the client disconnected.
+    fn handle(&mut self, request: Value) -> anyhow::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default().to_string();
+        let args = &request["arguments"];
+        let result = self.dispatch(&command, args);
+        let response = match &result {
+            Ok(body) => json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "command": command,
+                "success": true,
+                "body": body,
+            }),
+            Err(e) => json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "command": command,
+                "success": false,
+                "message": e.to_string(),
+            }),
+        };
+        self.send(response)?;
+
+        if result.is_ok() {
+            match command.as_str() {
+                "initialize" => self.event("initialized", json!({}))?,
+                "configurationDone" => match &self.session {
+                    Some(session) if session.stop_on_entry => self.stopped("entry", None)?,
+                    Some(_) => self.running = true,
+                    None => {}
+                },
+                "next" | "stepIn" | "stepOut" => {
+                    let reason = self.last_step.take();
+                    self.report(reason)?;
+                }
+                "pause" => self.stopped("pause", None)?,
+                "disconnect" | "terminate" => {
+                    self.event("terminated", json!({}))?;
+                    return Ok(command != "disconnect");
+                }
+                _ => {}
+            }
+        }
+        Ok(true)
+    }
+
+    fn dispatch(&mut self, command: &str, args: &Value) -> anyhow::Result<Value> {
+        Ok(match command {
+            "initialize" => json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsDisassembleRequest": true,
+                "supportsSteppingGranularity": true,
+                "supportsTerminateRequest": true,
+            }),
+            "launch" => {
+                self.session = Some(launch(args)?);
+                json!({})
+            }
+            "configurationDone" | "disconnect" | "terminate" => json!({}),
+            "setBreakpoints" => self.set_breakpoints(args)?,
+            "setFunctionBreakpoints" => self.set_function_breakpoints(args)?,
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args)?,
+            "setExceptionBreakpoints" => json!({ "breakpoints": [] }),
+            "threads" => json!({ "threads": [{ "id": THREAD_ID, "name": "8080" }] }),
+            "stackTrace" => self.stack_trace()?,
+            "scopes" => json!({
+                "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false },
+                ]
+            }),
+            "variables" => self.variables(args)?,
+            "source" => {
+                let session = self.session()?;
+                let content = session
+                    .listing
+                    .iter()
+                    .map(|line| line.text.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                json!({ "content": content, "mimeType": "text/x-asm" })
+            }
+            "readMemory" => self.read_memory(args)?,
+            "disassemble" => self.disassemble(args)?,
+            "continue" => {
+                self.session()?;
+                self.running = true;
+                json!({ "allThreadsContinued": true })
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let debugger = &mut self.session_mut()?.debugger;
+                let reason = match command {
+                    "next" => debugger.next(Some(STEP_LIMIT)),
+                    "stepIn" => debugger.step(),
+                    _ => debugger.finish(Some(STEP_LIMIT)),
+                };
+                self.last_step = Some(reason);
+                json!({})
+            }
+            "pause" => {
+                self.session()?;
+                json!({})
+            }
+            "evaluate" => self.evaluate(args)?,
+            _ => json!({}),
+        })
+    }
+
+    fn session(&self) -> anyhow::Result<&Session> {
+        Ok(self.session.as_ref().ok_or(Error::NotLaunched)?)
+    }
+
+    fn session_mut(&mut self) -> anyhow::Result<&mut Session> {
+        Ok(self.session.as_mut().ok_or(Error::NotLaunched)?)
+    }
+
+    fn run_chunk(&mut self) -> anyhow::Result<()> {
+        let reason = self.session_mut()?.debugger.cont(Some(RUN_CHUNK));
+        match reason {
+            Ok(StopReason::InstructionLimit) => Ok(()),
+            reason => self.report(Some(reason)),
+        }
+    }
+
+    fn report(&mut self, reason: Option<Result<StopReason, DebuggerError>>) -> anyhow::Result<()> {
+        let mismatches = self
+            .session_mut()?
+            .debugger
+            .system_mut()
+            .take_stack_mismatches();
+        for mismatch in mismatches {
+            self.output(format!("Stack: {}\n", mismatch))?;
+        }
+        match reason {
+            None | Some(Ok(StopReason::Step)) => self.stopped("step", None),
+            Some(Ok(StopReason::Breakpoint(_))) => self.stopped("breakpoint", None),
+            Some(Ok(StopReason::Watchpoint(access))) => {
+                let description = match access {
+                    MemoryAccess::Read(addr) => format!("Read of {:04x}", addr),
+                    MemoryAccess::Write(addr) => format!("Write to {:04x}", addr),
+                };
+                self.stopped("data breakpoint", Some(description))
+            }
+            Some(Ok(StopReason::Halted)) => self.stopped("pause", Some("HLT".to_string())),
+            Some(Ok(StopReason::InstructionLimit | StopReason::CycleLimit)) => {
+                self.stopped("pause", Some("Step limit reached".to_string()))
+            }
+            Some(Err(e)) => {
+                let state = self.session()?.debugger.system().cpu().to_string();
+                self.output(format!("{}\n{}", e, state))?;
+                self.stopped("exception", Some(e.to_string()))
+            }
+        }
+    }
+
+    fn sync_breakpoints(&mut self) -> anyhow::Result<()> {
+        let all = self
+            .source_breakpoints
+            .iter()
+            .chain(&self.function_breakpoints)
+            .chain(&self.instruction_breakpoints)
+            .copied()
+            .collect::<Vec<_>>();
+        let debugger = &mut self.session_mut()?.debugger;
+        debugger.clear_breakpoints();
+        for addr in all {
+            debugger.add_breakpoint(addr);
+        }
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> anyhow::Result<Value> {
+        let session = self.session()?;
+        let mut addresses = BTreeSet::new();
+        let mut breakpoints = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            // Breakpoints on a label line apply to the instruction that follows it.
+            let addr = session
+                .listing
+                .get(line.wrapping_sub(1)..)
+                .and_then(|rest| rest.iter().position(|l| !l.is_label).map(|i| line + i));
+            match addr {
+                Some(actual_line) => {
+                    addresses.insert(session.listing[actual_line - 1].addr);
+                    breakpoints.push(json!({ "verified": true, "line": actual_line }));
+                }
+                None => breakpoints.push(json!({ "verified": false, "line": line })),
+            }
+        }
+        self.source_breakpoints = addresses;
+        self.sync_breakpoints()?;
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_function_breakpoints(&mut self, args: &Value) -> anyhow::Result<Value> {
+        let session = self.session()?;
+        let mut addresses = BTreeSet::new();
+        let mut breakpoints = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let name = breakpoint["name"].as_str().unwrap_or_default();
+            match resolve(&session.symbols, name) {
+                Ok(addr) => {
+                    addresses.insert(addr);
+                    breakpoints.push(json!({
+                        "verified": true,
+                        "instructionReference": format!("{:#06x}", addr),
+                        "line": session.addr_to_line.get(&addr),
+                        "source": listing_source(session),
+                    }));
+                }
+                Err(e) => breakpoints.push(json!({ "verified": false, "message": e.to_string() })),
+            }
+        }
+        self.function_breakpoints = addresses;
+        self.sync_breakpoints()?;
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> anyhow::Result<Value> {
+        let mut addresses = BTreeSet::new();
+        let mut breakpoints = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let reference = breakpoint["instructionReference"]
+                .as_str()
+                .unwrap_or_default();
+            let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+            match parse_address(reference) {
+                Some(addr) => {
+                    let addr = addr.wrapping_add(offset as u16);
+                    addresses.insert(addr);
+                    breakpoints.push(json!({
+                        "verified": true,
+                        "instructionReference": format!("{:#06x}", addr),
+                    }));
+                }
+                None => breakpoints.push(json!({ "verified": false })),
+            }
+        }
+        self.instruction_breakpoints = addresses;
+        self.sync_breakpoints()?;
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_trace(&self) -> anyhow::Result<Value> {
+        let session = self.session()?;
+        let system = session.debugger.system();
+        let call_sites = system
+            .call_stack()
+            .map(|call_stack| {
+                call_stack
+                    .frames()
+                    .iter()
+                    .rev()
+                    .map(|frame| frame.call_site)
+            })
+            .into_iter()
+            .flatten();
+        let frames = std::iter::once(system.cpu().pc())
+            .chain(call_sites)
+            .enumerate()
+            .map(|(id, pc)| {
+                json!({
+                    "id": id,
+                    "name": session.symbols.format(pc),
+                    "source": listing_source(session),
+                    "line": session.addr_to_line.get(&pc).copied().unwrap_or(0),
+                    "column": 1,
+                    "instructionPointerReference": format!("{:#06x}", pc),
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(json!({ "totalFrames": frames.len(), "stackFrames": frames }))
+    }
+
+    fn variables(&self, args: &Value) -> anyhow::Result<Value> {
+        let cpu = self.session()?.debugger.system().cpu();
+        let byte = |name: &str, value: u8| json!({ "name": name, "value": format!("{:#04x}", value), "variablesReference": 0 });
+        let word = |name: &str, value: u16| {
+            json!({
+                "name": name,
+                "value": format!("{:#06x}", value),
+                "variablesReference": 0,
+                "memoryReference": format!("{:#06x}", value),
+            })
+        };
+        let flag = |name: &str, value: bool| json!({ "name": name, "value": (value as u8).to_string(), "variablesReference": 0 });
+        let variables = match args["variablesReference"].as_u64() {
+            Some(REGISTERS_REFERENCE) => vec![
+                byte("A", cpu.get(Register::A)),
+                byte("B", cpu.get(Register::B)),
+                byte("C", cpu.get(Register::C)),
+                byte("D", cpu.get(Register::D)),
+                byte("E", cpu.get(Register::E)),
+                byte("H", cpu.get(Register::H)),
+                byte("L", cpu.get(Register::L)),
+                word("BC", cpu.get_rp(RegisterPair::B)),
+                word("DE", cpu.get_rp(RegisterPair::D)),
+                word("HL", cpu.get_rp(RegisterPair::H)),
+                word("PSW", cpu.psw()),
+                word("SP", cpu.sp()),
+                word("PC", cpu.pc()),
+            ],
+            Some(FLAGS_REFERENCE) => vec![
+                flag("S", cpu.flag(Flag::S)),
+                flag("Z", cpu.flag(Flag::Z)),
+                flag("AC", cpu.flag(Flag::Ac)),
+                flag("P", cpu.flag(Flag::P)),
+                flag("CY", cpu.flag(Flag::Cy)),
+                flag("INTE", cpu.inte()),
+            ],
+            _ => vec![],
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn evaluate(&self, args: &Value) -> anyhow::Result<Value> {
+        let session = self.session()?;
+        let expression = args["expression"].as_str().unwrap_or_default().trim();
+        let addr = resolve(&session.symbols, expression)?;
+        let value = session.debugger.system().ram().peek(addr)?;
+        Ok(json!({
+            "result": format!("{:#06x} ({}): {:#04x}", addr, session.symbols.format(addr), value),
+            "variablesReference": 0,
+            "memoryReference": format!("{:#06x}", addr),
+        }))
+    }
+
+    fn read_memory(&self, args: &Value) -> anyhow::Result<Value> {
+        let ram = self.session()?.debugger.system().ram();
+        let base = args["memoryReference"]
+            .as_str()
+            .and_then(parse_address)
+            .ok_or(Error::InvalidArgument("memoryReference"))?;
+        let start = (base as i64).saturating_add(args["offset"].as_i64().unwrap_or(0));
+        let count = args["count"]
+            .as_u64()
+            .ok_or(Error::MissingArgument("count"))?;
+        let bytes = (0..count as i64)
+            .map(|i| start + i)
+            .take_while(|&addr| (0..ram.len() as i64).contains(&addr))
+            .map(|addr| ram.peek(addr as u16))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(json!({
+            "address": format!("{:#06x}", start),
+            "data": base64(&bytes),
+            "unreadableBytes": count as usize - bytes.len(),
+        }))
+    }
+
+    fn disassemble(&self, args: &Value) -> anyhow::Result<Value> {
+        let session = self.session()?;
+        let base = args["memoryReference"]
+            .as_str()
+            .and_then(parse_address)
+            .ok_or(Error::InvalidArgument("memoryReference"))?;
+        let base = base.wrapping_add(args["offset"].as_i64().unwrap_or(0) as u16);
+        // Past one instruction per address, rows only repeat the listing.
+        let instruction_offset = args["instructionOffset"]
+            .as_i64()
+            .unwrap_or(0)
+            .clamp(-MEMORY_SIZE, MEMORY_SIZE);
+        let count = args["instructionCount"]
+            .as_u64()
+            .ok_or(Error::MissingArgument("instructionCount"))?
+            .min(MEMORY_SIZE as u64) as usize;
+
+        let memory = session.debugger.system().get_slice(0)?;
+        let row = |addr: u16, instruction: Option<Instruction>| match instruction {
+            Some(instruction) => {
+                let bytes = &memory[addr as usize..addr as usize + instruction.size() as usize];
+                json!({
+                    "address": format!("{:#06x}", addr),
+                    "instructionBytes": bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "),
+                    "instruction": session.symbols.annotate(instruction),
+                    "symbol": session.symbols.label(addr),
+                    "location": listing_source(session),
+                    "line": session.addr_to_line.get(&addr),
+                })
+            }
+            None => json!({
+                "address": format!("{:#06x}", addr),
+                "instruction": "??",
+                "presentationHint": "invalid",
+            }),
+        };
+
+        // Row i is the instruction `instructionOffset + i` instructions away from `base`.
+        let mut instructions = Vec::with_capacity(count);
+        if instruction_offset < 0 {
+            let wanted = instruction_offset.unsigned_abs() as usize;
+            let bytes = wanted.saturating_mul(3).min(u16::MAX as usize);
+            let preceding = session
+                .debugger
+                .disassemble_around(base, bytes as u16, bytes + 1)
+                .into_iter()
+                .take_while(|(addr, _)| *addr != base)
+                .collect::<Vec<_>>();
+            // Bytes that could not be decoded as instructions ending at `base` are shown
+            // one per row.
+            let first = preceding.first().map_or(base, |(addr, _)| *addr);
+            let missing = wanted.saturating_sub(preceding.len());
+            instructions.extend(
+                (1..=missing)
+                    .rev()
+                    .take(count)
+                    .map(|i| row(first.wrapping_sub(i as u16), None)),
+            );
+            let kept = &preceding[preceding.len().saturating_sub(wanted)..];
+            instructions.extend(
+                kept.iter()
+                    .map(|(addr, instruction)| row(*addr, Some(*instruction))),
+            );
+            instructions.truncate(count);
+        }
+        let mut skip = instruction_offset.max(0);
+        let mut pc = base;
+        while instructions.len() < count {
+            let instruction = Instruction::read_at(memory, pc).ok();
+            if skip > 0 {
+                skip -= 1;
+            } else {
+                instructions.push(row(pc, instruction));
+            }
+            pc = pc.wrapping_add(instruction.map_or(1, Instruction::size));
+        }
+        Ok(json!({ "instructions": instructions }))
+    }
+}
+
+fn launch(args: &Value) -> anyhow::Result<Session> {
+    let program = args["program"]
+        .as_str()
+        .ok_or(Error::MissingArgument("program"))?;
+    let number = |key: &'static str, default: u64| -> anyhow::Result<u64> {
+        match &args[key] {
+            Value::Null => Ok(default),
+            Value::Number(n) => n.as_u64().ok_or(Error::InvalidArgument(key).into()),
+            Value::String(s) => Ok(parse_address(s).ok_or(Error::InvalidArgument(key))? as u64),
+            _ => Err(Error::InvalidArgument(key).into()),
+        }
+    };
+    let load_address = number("loadAddress", 0)? as u16;
+    let entry = number("entry", load_address as u64)? as u16;
+    let ram_size = match number("ramSize", MEMORY_SIZE as u64)? {
+        size @ 1..=0x10000 => size as usize,
+        _ => return Err(Error::InvalidArgument("ramSize").into()),
+    };
+    let symbols = match args["symbols"].as_str() {
+        Some(path) => SymbolTable::load(path)?,
+        None => SymbolTable::new(),
+    };
+
+    let rom = std::fs::read(program)?;
+    let mut ram = Ram::new(ram_size, false);
+    ram.register_rom(&rom, load_address as usize)?;
+    let system = System::new(ram, entry);
+
+    let mut listing = Vec::new();
+    let mut addr_to_line = HashMap::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let pc = (load_address as usize + offset) as u16;
+        let Ok(instruction) = Instruction::read_at(system.get_slice(0)?, pc) else {
+            break;
+        };
+        if let Some(label) = symbols.label(pc) {
+            listing.push(ListingLine {
+                addr: pc,
+                text: format!("{}:", label),
+                is_label: true,
+            });
+        }
+        listing.push(ListingLine {
+            addr: pc,
+            text: format!("    {:04x}  {}", pc, symbols.annotate(instruction)),
+            is_label: false,
+        });
+        addr_to_line.insert(pc, listing.len());
+        offset += instruction.size() as usize;
+    }
+
+    let ports = Rc::new(PortLatch::default());
+    Ok(Session {
+        debugger: Debugger::new(system, ports),
+        name: std::path::Path::new(program)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        symbols,
+        listing,
+        addr_to_line,
+        stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+    })
+}
+
+fn listing_source(session: &Session) -> Value {
+    json!({
+        "name": format!("{}.lst", session.name),
+        "sourceReference": LISTING_REFERENCE,
+    })
+}
+
+fn resolve(symbols: &SymbolTable, name: &str) -> anyhow::Result<u16> {
+    Ok(symbols
+        .resolve(name)
+        .or_else(|| parse_address(name))
+        .ok_or_else(|| Error::UnknownSymbol(name.to_string()))?)
+}
+
+fn parse_address(s: &str) -> Option<u16> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_suffix(['h', 'H']))
+        .unwrap_or(s);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    use emulator8080::i8080;
+    use serde_json::{json, Value};
+
+    use super::{read_message, Server};
+
+    /// Output shared with the test, which reads back what the server sent.
+    #[derive(Clone, Default)]
+    struct Sink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Sink {
+        fn take(&self) -> Vec<Value> {
+            let bytes = std::mem::take(&mut *self.0.borrow_mut());
+            let mut input = bytes.as_slice();
+            std::iter::from_fn(|| read_message(&mut input).unwrap()).collect()
+        }
+    }
+
+    fn request(server: &mut Server, sink: &Sink, command: &str, arguments: Value) -> Vec<Value> {
+        let request =
+            json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments });
+        assert!(server.handle(request).unwrap());
+        let messages = sink.take();
+        assert_eq!(messages[0]["success"], json!(true), "{}", messages[0]);
+        messages
+    }
+
+    fn launch(name: &str, program: &[u8], arguments: Value) -> (Server, Sink) {
+        let path = std::env::temp_dir().join(format!("dap-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, program).unwrap();
+        let sink = Sink::default();
+        let mut server = Server::new(Box::new(sink.clone()));
+        let mut arguments = arguments;
+        arguments["program"] = json!(path.to_str().unwrap());
+        request(&mut server, &sink, "launch", arguments);
+        std::fs::remove_file(path).unwrap();
+        (server, sink)
+    }
+
+    #[test]
+    fn launch_break_and_inspect() {
+        let program = i8080! {
+            LXI SP,0800H
+            MVI A,42H
+            STA 0900H
+            HLT
+        };
+        let (mut server, sink) = launch("inspect", &program, json!({ "stopOnEntry": true }));
+
+        let messages = request(
+            &mut server,
+            &sink,
+            "setBreakpoints",
+            json!({ "breakpoints": [{ "line": 3 }] }),
+        );
+        assert_eq!(
+            messages[0]["body"]["breakpoints"],
+            json!([{ "verified": true, "line": 3 }])
+        );
+        let messages = request(&mut server, &sink, "configurationDone", json!({}));
+        assert_eq!(messages[1]["body"]["reason"], json!("entry"));
+        request(&mut server, &sink, "continue", json!({}));
+        server.run_chunk().unwrap();
+        let messages = sink.take();
+        assert_eq!(messages[0]["body"]["reason"], json!("breakpoint"));
+
+        // One instruction before 0x0005, then as many as memory holds.
+        let messages = request(
+            &mut server,
+            &sink,
+            "disassemble",
+            json!({ "memoryReference": "0x0005", "instructionOffset": -1, "instructionCount": 3 }),
+        );
+        let instructions = messages[0]["body"]["instructions"].as_array().unwrap();
+        let addresses = instructions
+            .iter()
+            .map(|row| row["address"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(addresses, ["0x0003", "0x0005", "0x0008"]);
+        assert_eq!(instructions[0]["instructionBytes"], json!("3e 42"));
+
+        // Past the start of memory, rows are invalid but keep their place.
+        let messages = request(
+            &mut server,
+            &sink,
+            "disassemble",
+            json!({ "memoryReference": "0x0003", "instructionOffset": -3, "instructionCount": 4 }),
+        );
+        let instructions = messages[0]["body"]["instructions"].as_array().unwrap();
+        assert_eq!(instructions[1]["address"], json!("0xffff"));
+        assert_eq!(instructions[1]["presentationHint"], json!("invalid"));
+        assert_eq!(instructions[2]["address"], json!("0x0000"));
+        assert_eq!(instructions[3]["address"], json!("0x0003"));
+
+        let messages = request(
+            &mut server,
+            &sink,
+            "readMemory",
+            json!({ "memoryReference": "0x0003", "count": 2 }),
+        );
+        assert_eq!(messages[0]["body"]["data"], json!("PkI="));
+        assert_eq!(messages[0]["body"]["unreadableBytes"], json!(0));
+    }
+
+    #[test]
+    fn oversized_requests() {
+        let sink = Sink::default();
+        let mut server = Server::new(Box::new(sink.clone()));
+        let message = json!({
+            "seq": 1, "type": "request", "command": "launch",
+            "arguments": { "program": "unused", "ramSize": 0x10001 },
+        });
+        assert!(server.handle(message).unwrap());
+        assert_eq!(sink.take()[0]["success"], json!(false));
+
+        let (mut server, sink) = launch("oversized", &[0; 4], json!({}));
+        let messages = request(
+            &mut server,
+            &sink,
+            "disassemble",
+            json!({
+                "memoryReference": "0x0000",
+                "instructionOffset": i64::MAX,
+                "instructionCount": u64::MAX,
+            }),
+        );
+        let instructions = messages[0]["body"]["instructions"].as_array().unwrap();
+        assert_eq!(instructions.len(), 0x10000);
+        let messages = request(
+            &mut server,
+            &sink,
+            "disassemble",
+            json!({
+                "memoryReference": "0x0000",
+                "instructionOffset": i64::MIN,
+                "instructionCount": 2,
+            }),
+        );
+        let instructions = messages[0]["body"]["instructions"].as_array().unwrap();
+        assert_eq!(instructions.len(), 2);
+        let messages = request(
+            &mut server,
+            &sink,
+            "readMemory",
+            json!({ "memoryReference": "0x0000", "offset": i64::MAX, "count": u64::MAX }),
+        );
+        assert_eq!(messages[0]["body"]["data"], json!(""));
+    }
+
+    #[test]
+    fn launch_up_to_the_end_of_memory() {
+        let (server, _) = launch("end", &[0; 4], json!({ "loadAddress": "fffc" }));
+        assert_eq!(server.session.unwrap().listing.len(), 4);
+    }
+}
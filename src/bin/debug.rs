@@ -0,0 +1,383 @@
+use emulator8080::{
+    cpu_state::{MemoryAccess, Ram, System},
+    debugger::{Debugger, StopReason, Watch},
+    in_out::PortLatch,
+    op_code::Instruction,
+    symbols::SymbolTable,
+};
+use std::env::args;
+use std::fs::File;
+use std::io::{stdin, stdout, BufRead, BufReader, Read, Write};
+use std::rc::Rc;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("No input file given.")]
+    MissingCliArgument,
+
+    #[error("Could not parse {0:?} as a number.")]
+    InvalidNumber(String),
+
+    #[error("{0:?} does not fit in a byte.")]
+    InvalidByte(String),
+
+    #[error("{0:?} is not an RST vector, expected 0 to 7.")]
+    InvalidVector(String),
+
+    #[error("Missing argument, see `help`.")]
+    MissingArgument,
+
+    #[error("Unknown command {0:?}, see `help`.")]
+    UnknownCommand(String),
+
+    #[error("No command #{0} in history.")]
+    UnknownHistoryEntry(usize),
+}
+
+const HELP: &str = "\
+Addresses and bytes are hexadecimal (0x, $ and h affixes are accepted), counts are decimal.
+Addresses may also be given as labels once a symbol file is loaded.
+An empty line repeats the previous command.
+
+  s, step [count]           execute instructions, entering subroutines
+  n, next [count]           execute instructions, stepping over CALL/RST
+  c, continue [limit]       run until a breakpoint, watchpoint or HLT
+  r, regs                   print registers
+  bt, backtrace             print the call stack
+  d, dis [addr] [count]     disassemble around PC, or from addr
+  x, mem <addr> [len]       dump len bytes of memory (default 64)
+  e, edit <addr> <byte>...  write bytes into memory (ignores ROM protection)
+  b, break [addr]           set a breakpoint, or list breakpoints and watchpoints
+  del <addr>                delete the breakpoint or watchpoint at addr
+  w, watch <addr> [r|w|rw]  stop when addr is read and/or written (default w)
+  i, int <n>                raise interrupt RST n, 0 to 7 (only if interrupts are enabled)
+  in <port> <byte>          set the value returned by IN port
+  sym, symbols <file>       load labels, usable wherever an address is expected
+  history                   list previous commands
+  !<n>                      re-run history entry n
+  h, help                   print this message
+  q, quit                   exit";
+
+fn main() -> anyhow::Result<()> {
+    let fname = args().nth(1).ok_or(Error::MissingCliArgument)?;
+    let load_address = args()
+        .nth(2)
+        .map(|s| parse_hex(&s))
+        .transpose()?
+        .unwrap_or(0);
+    let f = File::open(fname)?;
+    let buf = BufReader::new(f);
+    let rom = buf.bytes().collect::<Result<Vec<_>, _>>()?;
+
+    let mut ram = Ram::new(0x10000, false);
+    ram.register_rom(&rom, load_address as usize)?;
+    let ports = Rc::new(PortLatch::default());
+    let mut session = Session {
+        debugger: Debugger::new(System::new(ram, load_address), ports.clone()),
+        ports,
+        history: Vec::new(),
+    };
+    session.show_location();
+
+    let mut lines = stdin().lock().lines();
+    loop {
+        print!("(8080) ");
+        stdout().flush()?;
+        let Some(line) = lines.next().transpose()? else {
+            return Ok(());
+        };
+        let line = match line.trim() {
+            "" => match session.history.last() {
+                Some(last) => last.clone(),
+                None => continue,
+            },
+            line => line.to_string(),
+        };
+        match session.run(&line) {
+            Ok(Flow::Quit) => return Ok(()),
+            Ok(Flow::Continue) => {}
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+enum Flow {
+    Continue,
+    Quit,
+}
+
+struct Session {
+    debugger: Debugger,
+    ports: Rc<PortLatch>,
+    history: Vec<String>,
+}
+
+impl Session {
+    fn run(&mut self, line: &str) -> anyhow::Result<Flow> {
+        if let Some(n) = line.strip_prefix('!') {
+            let n = parse_dec(n)? as usize;
+            let line = self
+                .history
+                .get(n)
+                .cloned()
+                .ok_or(Error::UnknownHistoryEntry(n))?;
+            println!("{}", line);
+            return self.run(&line);
+        }
+        if self.history.last().map(String::as_str) != Some(line) {
+            self.history.push(line.to_string());
+        }
+
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let args = words.collect::<Vec<_>>();
+        match command {
+            "s" | "step" => {
+                for _ in 0..count(&args, 0, 1)? {
+                    let reason = self.debugger.step()?;
+                    if reason != StopReason::Step {
+                        self.report(reason);
+                        break;
+                    }
+                }
+                self.report_stack();
+                self.show_location();
+            }
+            "n" | "next" => {
+                for _ in 0..count(&args, 0, 1)? {
+                    let reason = self.debugger.next(None)?;
+                    if reason != StopReason::Step {
+                        self.report(reason);
+                        break;
+                    }
+                }
+                self.report_stack();
+                self.show_location();
+            }
+            "c" | "continue" => {
+                let limit = args.first().map(|s| parse_dec(s)).transpose()?;
+                let reason = self.debugger.cont(limit)?;
+                self.report(reason);
+                self.report_stack();
+                self.show_location();
+            }
+            "r" | "regs" => {
+                print!("{}", self.debugger.system().cpu());
+                println!(
+                    "PC: {:#06x}  ({} instructions, {} cycles)",
+                    self.debugger.system().cpu().pc(),
+                    self.debugger.instructions(),
+                    self.debugger.cycles()
+                );
+            }
+            "bt" | "backtrace" => {
+                if let Some(backtrace) = self.debugger.system().backtrace() {
+                    print!("{}", backtrace);
+                }
+            }
+            "d" | "dis" => {
+                let count = count(&args, 1, 16)? as usize;
+                let listing = match args.first() {
+                    Some(addr) => self
+                        .debugger
+                        .disassemble_around(self.address(addr)?, 0, count),
+                    None => {
+                        let pc = self.debugger.system().cpu().pc();
+                        self.debugger.disassemble_around(pc, 8, count)
+                    }
+                };
+                for (addr, instruction) in listing {
+                    self.print_instruction(addr, instruction);
+                }
+            }
+            "x" | "mem" => {
+                let addr = self.address(args.first().ok_or(Error::MissingArgument)?)?;
+                let len = count(&args, 1, 64)?.min(0x10000) as u32;
+                self.dump_memory(addr, len);
+            }
+            "e" | "edit" => {
+                let addr = self.address(args.first().ok_or(Error::MissingArgument)?)?;
+                if args.len() < 2 {
+                    return Err(Error::MissingArgument.into());
+                }
+                for (offset, byte) in args[1..].iter().enumerate() {
+                    let byte = parse_byte(byte)?;
+                    self.debugger
+                        .system_mut()
+                        .ram_mut()
+                        .poke(addr.wrapping_add(offset as u16), byte)?;
+                }
+            }
+            "b" | "break" => match args.first() {
+                Some(addr) => {
+                    let addr = self.address(addr)?;
+                    self.debugger.add_breakpoint(addr);
+                    println!("Breakpoint at {:04x}", addr);
+                }
+                None => {
+                    for addr in self.debugger.breakpoints() {
+                        println!("break {:04x}", addr);
+                    }
+                    for (addr, watch) in self.debugger.watchpoints() {
+                        println!("watch {:04x} {:?}", addr, watch);
+                    }
+                }
+            },
+            "del" => {
+                let addr = self.address(args.first().ok_or(Error::MissingArgument)?)?;
+                let removed =
+                    self.debugger.remove_breakpoint(addr) | self.debugger.remove_watchpoint(addr);
+                if !removed {
+                    println!("Nothing set at {:04x}", addr);
+                }
+            }
+            "w" | "watch" => {
+                let addr = self.address(args.first().ok_or(Error::MissingArgument)?)?;
+                let watch = match args.get(1).copied() {
+                    None | Some("w") => Watch::Write,
+                    Some("r") => Watch::Read,
+                    Some("rw") => Watch::Access,
+                    Some(other) => return Err(Error::UnknownCommand(other.to_string()).into()),
+                };
+                self.debugger.add_watchpoint(addr, watch);
+                println!("Watchpoint ({:?}) at {:04x}", watch, addr);
+            }
+            "i" | "int" => {
+                let n = args.first().ok_or(Error::MissingArgument)?;
+                let n = match parse_dec(n)? {
+                    n @ 0..=7 => n as u8,
+                    _ => return Err(Error::InvalidVector(n.to_string()).into()),
+                };
+                if self.debugger.interrupt(n)? {
+                    self.show_location();
+                } else {
+                    println!("Interrupts are disabled, RST {} ignored.", n);
+                }
+            }
+            "in" => {
+                let port = parse_byte(args.first().ok_or(Error::MissingArgument)?)?;
+                let value = parse_byte(args.get(1).ok_or(Error::MissingArgument)?)?;
+                self.ports.set_input(port, value);
+            }
+            "sym" | "symbols" => {
+                let path = args.first().ok_or(Error::MissingArgument)?;
+                let symbols = SymbolTable::load(path)?;
+                println!("Loaded {} symbols", symbols.iter().count());
+                self.debugger.system_mut().set_symbols(symbols);
+            }
+            "history" => {
+                for (i, line) in self.history.iter().enumerate() {
+                    println!("{:4}  {}", i, line);
+                }
+            }
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return Ok(Flow::Quit),
+            other => return Err(Error::UnknownCommand(other.to_string()).into()),
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn report(&self, reason: StopReason) {
+        match reason {
+            StopReason::Step => {}
+            StopReason::Breakpoint(addr) => println!("Breakpoint hit at {:04x}", addr),
+            StopReason::Watchpoint(MemoryAccess::Read(addr)) => {
+                println!("Watchpoint: read of {:04x}", addr)
+            }
+            StopReason::Watchpoint(MemoryAccess::Write(addr)) => println!(
+                "Watchpoint: write of {:02x} to {:04x}",
+                self.debugger.system().ram().peek(addr).unwrap_or_default(),
+                addr
+            ),
+            StopReason::Halted => println!("CPU halted"),
+            StopReason::InstructionLimit => println!("Instruction limit reached"),
+            StopReason::CycleLimit => println!("Cycle limit reached"),
+        }
+    }
+
+    /// Prints the stack manipulations seen since the last stop.
+    fn report_stack(&mut self) {
+        for mismatch in self.debugger.system_mut().take_stack_mismatches() {
+            println!("Stack: {}", mismatch);
+        }
+    }
+
+    fn show_location(&self) {
+        let pc = self.debugger.system().cpu().pc();
+        match self.debugger.system().next_instruction() {
+            Ok(instruction) => self.print_instruction(pc, instruction),
+            Err(e) => println!("{:04x}  <{}>", pc, e),
+        }
+    }
+
+    fn print_instruction(&self, addr: u16, instruction: Instruction) {
+        let symbols = self.debugger.system().symbols();
+        if let Some(label) = symbols.label(addr) {
+            println!("{}:", label);
+        }
+        let marker = if addr == self.debugger.system().cpu().pc() {
+            "=>"
+        } else if self.debugger.breakpoints().contains(&addr) {
+            " *"
+        } else {
+            "  "
+        };
+        println!("{} {:04x}  {}", marker, addr, symbols.annotate(instruction));
+    }
+
+    fn address(&self, s: &str) -> Result<u16, Error> {
+        match self.debugger.system().symbols().resolve(s) {
+            Some(addr) => Ok(addr),
+            None => parse_hex(s),
+        }
+    }
+
+    fn dump_memory(&self, addr: u16, len: u32) {
+        let ram = self.debugger.system().ram();
+        let start = addr & !0x0f;
+        let end = addr as u32 + len;
+        for row in (start as u32..end).step_by(16) {
+            let bytes = (row..row + 16)
+                .map(|a| ram.peek(a as u16).ok().filter(|_| a < 0x10000))
+                .collect::<Vec<_>>();
+            let hex = bytes
+                .iter()
+                .map(|b| b.map_or("  ".to_string(), |b| format!("{:02x}", b)))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = bytes
+                .iter()
+                .map(|b| match b {
+                    Some(b @ 0x20..=0x7e) => *b as char,
+                    _ => '.',
+                })
+                .collect::<String>();
+            println!("{:04x}  {}  {}", row, hex, ascii);
+        }
+    }
+}
+
+fn count(args: &[&str], index: usize, default: u64) -> anyhow::Result<u64> {
+    Ok(args
+        .get(index)
+        .map(|s| parse_dec(s))
+        .transpose()?
+        .unwrap_or(default))
+}
+
+fn parse_dec(s: &str) -> Result<u64, Error> {
+    s.parse().map_err(|_| Error::InvalidNumber(s.to_string()))
+}
+
+fn parse_hex(s: &str) -> Result<u16, Error> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix('$'))
+        .or_else(|| s.strip_suffix(['h', 'H']))
+        .unwrap_or(s);
+    u16::from_str_radix(digits, 16).map_err(|_| Error::InvalidNumber(s.to_string()))
+}
+
+fn parse_byte(s: &str) -> Result<u8, Error> {
+    u8::try_from(parse_hex(s)?).map_err(|_| Error::InvalidByte(s.to_string()))
+}
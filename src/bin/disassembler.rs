@@ -1,9 +1,18 @@
-#![feature(generic_arg_infer)]
-
-use emulator8080::{cpu_state::System, op_code::OpCodeError};
+use emulator8080::{
+    cli::{take_flag, take_option},
+    coverage::{CoverageMap, Usage},
+    disassembler::{Analysis, ListingOptions, DEFAULT_ENTRY_POINTS},
+    flow_graph::FlowGraph,
+    intel_hex::{self, HexImage},
+    json_listing,
+    symbols::SymbolTable,
+    syntax::Syntax,
+    xref,
+};
 use std::env::args;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufReader, Read};
+use std::path::Path;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -15,17 +24,191 @@ pub enum Error {
 
     #[error("Could not retrieve enough argument for instruction.")]
     NotEnoughArguments,
+
+    #[error("{0:?} is neither a hexadecimal address nor a known label.")]
+    InvalidAddress(String),
+
+    #[error("--source prints Intel mnemonics, the only ones the assembler reads; drop --zilog.")]
+    ZilogSource,
 }
 
-fn main() {
-    let fname = args().nth(1).ok_or(Error::MissingCliArgument).unwrap();
-    let f = File::open(fname).unwrap();
-    let buf = BufReader::new(f);
+/// CP/M loads .COM files at the start of the TPA (transient program area).
+const COM_ORIGIN: u16 = 0x100;
+
+const USAGE: &str = "\
+usage: disassembler <file> [symbols] [options]
+
+  --origin <addr>    address the file is loaded at (default 0, 100 for CP/M .COM files;
+                     Intel HEX files (.hex, .ihx) are placed at their own addresses)
+  --start <addr>     first address listed
+  --end <addr>       last address listed
+  --bytes            show the raw bytes of each line
+  --entry <addr>     additional entry point, may be repeated
+  --coverage <file>  coverage map recorded by `run --coverage`
+  --linear           decode every byte in sequence instead of following the control flow
+  --json             print one JSON object per decoded instruction and line
+  --source           print a source file that assembles back to the input (not with
+                     --zilog)
+  --xref             print a cross reference of addresses and I/O ports
+  --xref-json        print the cross reference as JSON
+  --dot <dir>        write the control flow graph of each subroutine (sub_XXXX.dot) and
+                     the call graph (callgraph.dot) to <dir> as Graphviz files
+  --zilog            use Zilog mnemonics
+
+Addresses are hexadecimal, or labels from the symbol file.
+Code is found by following the control flow from the reset and RST vectors, the origin, the
+--entry addresses and, with --coverage, every opcode seen executed. Bytes that cannot be
+decoded are listed as DB.";
+
+fn main() -> anyhow::Result<()> {
+    let mut args = args().collect::<Vec<_>>();
+    if take_flag(&mut args, "--help") || take_flag(&mut args, "-h") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let coverage = take_option(&mut args, "--coverage")?
+        .map(CoverageMap::load)
+        .transpose()?;
+    let mut entries = Vec::new();
+    while let Some(entry) = take_option(&mut args, "--entry")? {
+        entries.push(entry);
+    }
+    let origin = take_option(&mut args, "--origin")?;
+    let start = take_option(&mut args, "--start")?;
+    let end = take_option(&mut args, "--end")?;
+    let bytes = take_flag(&mut args, "--bytes");
+    let linear = take_flag(&mut args, "--linear");
+    let json = take_flag(&mut args, "--json");
+    let source = take_flag(&mut args, "--source");
+    let xref = take_flag(&mut args, "--xref");
+    let xref_json = take_flag(&mut args, "--xref-json");
+    let dot = take_option(&mut args, "--dot")?;
+    let syntax = if take_flag(&mut args, "--zilog") {
+        Syntax::Zilog
+    } else {
+        Syntax::Intel
+    };
+    if source && syntax == Syntax::Zilog {
+        return Err(Error::ZilogSource.into());
+    }
+
+    let fname = args.get(1).ok_or(Error::MissingCliArgument)?;
+    let (mut hex_origin, mut hex_start) = (None, None);
+    let rom = if intel_hex::is_hex_file(fname) {
+        let image = HexImage::load(fname)?;
+        let (image_origin, rom) = image.flatten()?;
+        hex_origin = Some(image_origin);
+        hex_start = image.start16()?;
+        rom
+    } else {
+        let f = File::open(fname)?;
+        let buf = BufReader::new(f);
+
+        buf.bytes().collect::<Result<Vec<_>, _>>()?
+    };
+    let symbols = args
+        .get(2)
+        .map(SymbolTable::load)
+        .transpose()?
+        .unwrap_or_default();
+    let address = |s: String| {
+        symbols
+            .resolve(&s)
+            .or_else(|| u16::from_str_radix(s.trim_start_matches("0x"), 16).ok())
+            .ok_or(Error::InvalidAddress(s))
+    };
+    let origin = match hex_origin {
+        Some(origin) => origin,
+        None if fname.to_ascii_lowercase().ends_with(".com") => {
+            origin.map(address).transpose()?.unwrap_or(COM_ORIGIN)
+        }
+        None => origin.map(address).transpose()?.unwrap_or(0),
+    };
+    let options = ListingOptions {
+        syntax,
+        bytes,
+        start: start.map(address).transpose()?,
+        end: end.map(address).transpose()?,
+    };
+
+    if linear {
+        // Decoding starts at `--start` so that it is aligned on an instruction.
+        let skip = options
+            .start
+            .map_or(0, |start| start.wrapping_sub(origin) as usize)
+            .min(rom.len());
+        let analysis = Analysis::linear(
+            &rom[skip..],
+            origin.wrapping_add(skip as u16),
+            coverage.as_ref(),
+        );
+        let rom = &rom[skip..];
+        if json {
+            print!(
+                "{}",
+                json_listing::listing(&analysis, rom, &symbols, &options)
+            );
+        } else {
+            print!("{}", analysis.listing(rom, &symbols, &options));
+        }
+        return Ok(());
+    }
+
+    let mut entry_points = DEFAULT_ENTRY_POINTS.to_vec();
+    entry_points.push(origin);
+    entry_points.extend(hex_start);
+    for entry in entries {
+        entry_points.push(address(entry)?);
+    }
+    if let Some(coverage) = &coverage {
+        entry_points.extend(
+            (0..rom.len() as u16)
+                .map(|offset| origin.wrapping_add(offset))
+                .filter(|addr| coverage.get(*addr).contains(Usage::OPCODE)),
+        );
+    }
+
+    let mut analysis = Analysis::new(&rom, origin, &entry_points);
+    if let Some(coverage) = &coverage {
+        analysis.apply_coverage(coverage);
+    }
+    if let Some(dir) = dot {
+        write_graphs(&analysis, &symbols, syntax, Path::new(&dir))?;
+    } else if xref {
+        print!("{}", xref::report(&analysis, &symbols));
+    } else if xref_json {
+        println!("{:#}", xref::json(&analysis, &symbols));
+    } else if json {
+        print!(
+            "{}",
+            json_listing::listing(&analysis, &rom, &symbols, &options)
+        );
+    } else if source {
+        print!("{}", analysis.source(&rom, &symbols, syntax));
+    } else {
+        print!("{}", analysis.listing(&rom, &symbols, &options));
+    }
+    for addr in analysis.unresolved() {
+        eprintln!("Unresolved jump at {:04x}", addr);
+    }
+    Ok(())
+}
 
-    let rom = buf.bytes().collect::<Result<Vec<_>, _>>().unwrap();
-    match System::disassembly(&rom) {
-        Err(OpCodeError::EndOfDataInstr) => Ok(()),
-        result => result,
+/// Writes one DOT file per subroutine, named after its address since labels may not be
+/// valid or distinct file names, and `callgraph.dot` into `dir`.
+fn write_graphs(
+    analysis: &Analysis,
+    symbols: &SymbolTable,
+    syntax: Syntax,
+    dir: &Path,
+) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let graph = FlowGraph::new(analysis);
+    for entry in graph.subroutines().keys() {
+        fs::write(
+            dir.join(format!("sub_{:04x}.dot", entry)),
+            graph.subroutine_dot(*entry, symbols, syntax),
+        )?;
     }
-    .unwrap()
+    fs::write(dir.join("callgraph.dot"), graph.call_graph_dot(symbols))
 }
@@ -1,13 +1,18 @@
-#![feature(generic_arg_infer)]
-
 use anyhow::anyhow;
 use emulator8080::{
+    cli::{take_flag, take_option},
+    cpm::{Cpm, Exit, StdConsole},
     cpu_state::{Ram, System},
     in_out::DummyInOut,
+    intel_hex::{self, HexImage},
+    op_code::Instruction,
+    symbols::SymbolTable,
+    syntax::Syntax,
 };
 use std::env::args;
 use std::fs::File;
 use std::io::{BufReader, Read};
+use std::path::PathBuf;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -19,50 +24,206 @@ pub enum Error {
 
     #[error("Could not retrieve enough argument for instruction.")]
     NotEnoughArguments,
+
+    #[error("{0:?} is not a drive mapping such as B=some/dir.")]
+    InvalidDrive(String),
 }
 
 fn main() -> anyhow::Result<()> {
-    let fname = args().nth(1).ok_or(Error::MissingCliArgument)?;
-    let f = File::open(fname)?;
-    let buf = BufReader::new(f);
+    let mut args = args().collect::<Vec<_>>();
+    let symbols = match take_option(&mut args, "--symbols")? {
+        Some(path) => SymbolTable::load(path)?,
+        None => SymbolTable::new(),
+    };
+    let coverage = take_option(&mut args, "--coverage")?;
+    let dump = take_option(&mut args, "--dump")?;
+    let mut drives = Vec::new();
+    while let Some(drive) = take_option(&mut args, "--drive")? {
+        drives.push(parse_drive(drive)?);
+    }
+    let command_line = take_option(&mut args, "--args")?;
+    let profile = take_flag(&mut args, "--profile");
+    let no_trace = take_flag(&mut args, "--no-trace");
+    let force_trace = take_flag(&mut args, "--trace");
+    let cpm = take_flag(&mut args, "--cpm");
+    let syntax = if take_flag(&mut args, "--zilog") {
+        Syntax::Zilog
+    } else {
+        Syntax::Intel
+    };
 
-    let rom = buf.bytes().collect::<Result<Vec<_>, _>>()?;
+    let fname = args.get(1).ok_or(Error::MissingCliArgument)?;
+    let cpm = cpm || fname.to_ascii_lowercase().ends_with(".com");
+    // Traces would be interleaved with the console output of CP/M programs.
+    let trace = force_trace || (!cpm && !no_trace);
+    if cpm {
+        let program = std::fs::read(fname)?;
+        let mut cpm = Cpm::new(&program, StdConsole::default())?;
+        if drives.is_empty() {
+            drives.push((0, PathBuf::from(".")));
+        }
+        for (drive, dir) in drives {
+            cpm.files_mut().mount(drive, dir);
+        }
+        cpm.set_command_line(command_line.as_deref().unwrap_or_default())?;
+        configure(cpm.system_mut(), symbols, profile, coverage.is_some());
+        let result = run_cpm(&mut cpm, &args, trace, syntax);
+        return report(cpm.system(), result, coverage, dump);
+    }
+    let (ram, pc) = if intel_hex::is_hex_file(fname) {
+        // HEX files place their data anywhere, and may say where to start.
+        let image = HexImage::load(fname)?;
+        let mut ram = Ram::new(0x10000, false);
+        image.register_rom(&mut ram)?;
+        (ram, image.start16()?.unwrap_or(0))
+    } else {
+        let f = File::open(fname)?;
+        let buf = BufReader::new(f);
 
-    let mut ram = Ram::new(0x4000, false);
-    ram.register_rom(&rom, 0)?;
-    let mut system = System::new(ram, 0);
+        let rom = buf.bytes().collect::<Result<Vec<_>, _>>()?;
 
-    if let e @ Err(_) = main_impl(&mut system) {
+        let mut ram = Ram::new(0x4000, false);
+        ram.register_rom(&rom, 0)?;
+        (ram, 0)
+    };
+    let mut system = System::new(ram, pc);
+    configure(&mut system, symbols, profile, coverage.is_some());
+    let result = main_impl(&mut system, &args, trace, syntax);
+    report(&system, result, coverage, dump)
+}
+
+fn configure(system: &mut System, symbols: SymbolTable, profile: bool, coverage: bool) {
+    system.set_symbols(symbols);
+    system.enable_call_stack();
+    if profile {
+        system.enable_profiling();
+    }
+    if coverage {
+        system.enable_coverage();
+    }
+}
+
+/// Prints and saves what was asked for once the program stopped with `result`.
+fn report(
+    system: &System,
+    result: anyhow::Result<()>,
+    coverage: Option<String>,
+    dump: Option<String>,
+) -> anyhow::Result<()> {
+    if result.is_err() {
         system.dump_state();
-        e
+    }
+    if let (Some(path), Some(map)) = (coverage, system.coverage()) {
+        map.save(path)?;
+    }
+    if let Some(path) = dump {
+        let memory = HexImage::new(0, system.get_slice(0)?);
+        std::fs::write(path, memory.to_string())?;
+    }
+    if let Some(profiler) = system.profiler() {
+        println!();
+        print!(
+            "{}",
+            profiler.report(system.get_slice(0)?, system.symbols(), PROFILE_ENTRIES)
+        );
+    }
+    result
+}
+
+const PROFILE_ENTRIES: usize = 30;
+
+/// Parses `B=dir` into drive 1 and `dir`.
+fn parse_drive(mapping: String) -> Result<(u8, PathBuf), Error> {
+    match mapping.split_once('=') {
+        Some((drive, dir)) if drive.len() == 1 && !dir.is_empty() => {
+            match drive.to_ascii_uppercase().as_bytes()[0] {
+                letter @ b'A'..=b'P' => Ok((letter - b'A', PathBuf::from(dir))),
+                _ => Err(Error::InvalidDrive(mapping)),
+            }
+        }
+        _ => Err(Error::InvalidDrive(mapping)),
+    }
+}
+
+fn max_instructions(args: &[String]) -> u32 {
+    args.get(2)
+        .and_then(|s| s.parse::<u32>().ok())
+        .unwrap_or(u32::MAX)
+}
+
+fn too_many_instructions(instructions: u32, max_instructions: u32) -> anyhow::Error {
+    anyhow!(
+        "Reached maximum instruction count ({} > {}), early failure (after ?? cycles).",
+        instructions,
+        max_instructions,
+    )
+}
+
+fn print_trace(system: &System, instruction: Instruction, syntax: Syntax) {
+    let pc = system.cpu().pc();
+    if system.symbols().is_empty() {
+        println!("{:04x} {}", pc, instruction.display().syntax(syntax));
     } else {
-        Ok(())
+        println!(
+            "{:04x} {:<20} {}",
+            pc,
+            system.symbols().format(pc),
+            system
+                .symbols()
+                .annotate(instruction.display().syntax(syntax))
+        );
     }
 }
 
-fn main_impl(system: &mut System) -> anyhow::Result<()> {
+fn main_impl(
+    system: &mut System,
+    args: &[String],
+    trace: bool,
+    syntax: Syntax,
+) -> anyhow::Result<()> {
     let mut instructions = 0;
-    let max_instructions = args()
-        .map(|s| s.parse::<u32>().ok())
-        .nth(2)
-        .flatten()
-        .unwrap_or(u32::MAX);
+    let max_instructions = max_instructions(args);
 
     let io = DummyInOut;
 
     loop {
         let instruction = system.next_instruction()?;
-        println!("{:04x} {:?}", system.cpu().pc(), instruction);
+        if trace {
+            print_trace(system, instruction, syntax);
+        }
         if let Err(e) = system.execute(instruction, &io) {
             return Err(e.into());
         }
         instructions += 1;
         if instructions > max_instructions {
-            return Err(anyhow!(
-                "Reached maximum instruction count ({} > {}), early failure (after ?? cycles).",
-                instructions,
-                max_instructions,
-            ));
+            return Err(too_many_instructions(instructions, max_instructions));
+        }
+    }
+}
+
+/// Runs a CP/M program on the host console until it warm boots or halts.
+fn run_cpm(
+    cpm: &mut Cpm<StdConsole>,
+    args: &[String],
+    trace: bool,
+    syntax: Syntax,
+) -> anyhow::Result<()> {
+    let mut instructions = 0;
+    let max_instructions = max_instructions(args);
+
+    loop {
+        if trace {
+            print_trace(cpm.system(), cpm.system().next_instruction()?, syntax);
+        }
+        if let Some(exit) = cpm.step()? {
+            if exit == Exit::Halt {
+                eprintln!("Halted at {:04x}.", cpm.system().cpu().pc());
+            }
+            return Ok(());
+        }
+        instructions += 1;
+        if instructions > max_instructions {
+            return Err(too_many_instructions(instructions, max_instructions));
         }
     }
 }
@@ -0,0 +1,115 @@
+use anyhow::bail;
+use emulator8080::{
+    cli::{take_flag, take_option},
+    single_step::TestCase,
+};
+use std::collections::BTreeMap;
+use std::env::args;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("No test file given.")]
+    MissingCliArgument,
+
+    #[error("Could not parse {0:?} as a number.")]
+    InvalidNumber(String),
+}
+
+const USAGE: &str = "\
+usage: single_step <file.json | dir> ... [options]
+
+  --max-failures <n>  failing tests printed per opcode (default 5, 0 for all)
+
+Runs single instruction tests, as published per opcode by the community test suites: each
+test sets up the registers and memory, executes one instruction and compares the
+registers, flags, memory, ports and cycle count with the expected state. Directories are
+searched for .json files. Prints a line per opcode, followed by every mismatch of its
+first failing tests.";
+
+/// Results of the tests of an opcode.
+#[derive(Default)]
+struct Summary {
+    passed: usize,
+    failed: usize,
+    failures: Vec<String>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = args().collect::<Vec<_>>();
+    if take_flag(&mut args, "--help") || take_flag(&mut args, "-h") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let max_failures = match take_option(&mut args, "--max-failures")? {
+        Some(n) => n.parse::<usize>().map_err(|_| Error::InvalidNumber(n))?,
+        None => 5,
+    };
+    if args.len() < 2 {
+        return Err(Error::MissingCliArgument.into());
+    }
+    let mut files = Vec::new();
+    for arg in &args[1..] {
+        collect_files(Path::new(arg), &mut files)?;
+    }
+
+    let mut summaries = BTreeMap::<u8, Summary>::new();
+    for file in files {
+        for test in TestCase::load(&file)? {
+            let opcode = test
+                .initial
+                .ram
+                .iter()
+                .find(|(addr, _)| *addr == test.initial.pc)
+                .map_or(0, |(_, byte)| *byte);
+            let summary = summaries.entry(opcode).or_default();
+            let mismatches = test.run()?;
+            if mismatches.is_empty() {
+                summary.passed += 1;
+                continue;
+            }
+            summary.failed += 1;
+            if max_failures == 0 || summary.failures.len() < max_failures {
+                let mismatches = mismatches.iter().map(|m| m.to_string());
+                summary.failures.push(format!(
+                    "{}: {}",
+                    test.name,
+                    mismatches.collect::<Vec<_>>().join(", ")
+                ));
+            }
+        }
+    }
+
+    let mut failed = 0;
+    for (opcode, summary) in &summaries {
+        let total = summary.passed + summary.failed;
+        println!("{:02x}: {}/{} passed", opcode, summary.passed, total);
+        for failure in &summary.failures {
+            println!("    {}", failure);
+        }
+        failed += summary.failed;
+    }
+    if failed > 0 {
+        bail!("{} tests failed.", failed);
+    }
+    Ok(())
+}
+
+/// Adds `path`, or the .json files found under it if it is a directory, sorted by name.
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() || entry.extension().is_some_and(|ext| ext == "json") {
+            collect_files(&entry, files)?;
+        }
+    }
+    Ok(())
+}
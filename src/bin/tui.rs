@@ -0,0 +1,418 @@
+use emulator8080::{
+    cpu_state::{Flag, MemoryAccess, Ram, System},
+    debugger::{Debugger, StopReason},
+    op_code::{Register, RegisterPair},
+    space_invaders::{
+        SpaceInvadersPorts, MEMORY_HEIGHT, MEMORY_WIDTH, REFRESH_RATE, SYSTEM_FREQUENCY, VIDEO_RAM,
+    },
+};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    symbols::Marker,
+    text::Line,
+    widgets::{
+        canvas::{Canvas, Points},
+        Block, Paragraph,
+    },
+    DefaultTerminal, Frame,
+};
+use std::env::args;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("No input file given.")]
+    MissingCliArgument,
+
+    #[error("Could not parse {0:?} as a hexadecimal address.")]
+    InvalidAddress(String),
+}
+
+const KEYS: &str = "s step  n next  c/space run/pause  b breakpoint at PC  1/2 RST 1/2  \
+v video IRQs  m memory follow  q quit";
+
+const FRAME: Duration = Duration::from_micros(1_000_000 / REFRESH_RATE);
+
+/// Register pair followed by the memory pane.
+#[derive(Clone, Copy)]
+enum Follow {
+    Hl,
+    De,
+    Bc,
+    Sp,
+    Pc,
+}
+
+impl Follow {
+    fn next(self) -> Self {
+        match self {
+            Follow::Hl => Follow::De,
+            Follow::De => Follow::Bc,
+            Follow::Bc => Follow::Sp,
+            Follow::Sp => Follow::Pc,
+            Follow::Pc => Follow::Hl,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Follow::Hl => "HL",
+            Follow::De => "DE",
+            Follow::Bc => "BC",
+            Follow::Sp => "SP",
+            Follow::Pc => "PC",
+        }
+    }
+
+    fn addr(self, system: &System) -> u16 {
+        let cpu = system.cpu();
+        match self {
+            Follow::Hl => cpu.get_rp(RegisterPair::H),
+            Follow::De => cpu.get_rp(RegisterPair::D),
+            Follow::Bc => cpu.get_rp(RegisterPair::B),
+            Follow::Sp => cpu.sp(),
+            Follow::Pc => cpu.pc(),
+        }
+    }
+}
+
+struct App {
+    debugger: Debugger,
+    running: bool,
+    video_interrupts: bool,
+    follow: Follow,
+    status: String,
+}
+
+fn main() -> anyhow::Result<()> {
+    let fname = args().nth(1).ok_or(Error::MissingCliArgument)?;
+    let load_address = match args().nth(2) {
+        Some(s) => u16::from_str_radix(s.trim_start_matches("0x"), 16)
+            .map_err(|_| Error::InvalidAddress(s))?,
+        None => 0,
+    };
+    let f = File::open(fname)?;
+    let buf = BufReader::new(f);
+    let rom = buf.bytes().collect::<Result<Vec<_>, _>>()?;
+
+    let mut ram = Ram::new(0x10000, false);
+    ram.register_rom(&rom, load_address as usize)?;
+    let ports = Rc::new(SpaceInvadersPorts::default());
+    let mut app = App {
+        debugger: Debugger::new(System::new(ram, load_address), ports),
+        running: false,
+        video_interrupts: true,
+        follow: Follow::Hl,
+        status: "Paused".to_string(),
+    };
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl App {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> anyhow::Result<()> {
+        loop {
+            let frame_start = Instant::now();
+            if self.running {
+                self.run_frame();
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let timeout = if self.running {
+                FRAME.saturating_sub(frame_start.elapsed())
+            } else {
+                Duration::from_secs(3600)
+            };
+            if !event::poll(timeout)? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Char('s') => {
+                    self.running = false;
+                    let reason = self.debugger.step();
+                    self.report(reason);
+                }
+                KeyCode::Char('n') => {
+                    self.running = false;
+                    let reason = self.debugger.next(Some(SYSTEM_FREQUENCY));
+                    self.report(reason);
+                }
+                KeyCode::Char('c') | KeyCode::Char(' ') => {
+                    self.running = !self.running;
+                    self.status = if self.running { "Running" } else { "Paused" }.to_string();
+                }
+                KeyCode::Char('b') => {
+                    let pc = self.debugger.system().cpu().pc();
+                    if !self.debugger.remove_breakpoint(pc) {
+                        self.debugger.add_breakpoint(pc);
+                    }
+                }
+                KeyCode::Char(c @ ('1' | '2')) => {
+                    let n = c as u8 - b'0';
+                    self.status = match self.debugger.interrupt(n) {
+                        Ok(true) => format!("RST {} taken", n),
+                        Ok(false) => format!("RST {} ignored, interrupts disabled", n),
+                        Err(e) => format!("Error: {}", e),
+                    };
+                }
+                KeyCode::Char('v') => self.video_interrupts = !self.video_interrupts,
+                KeyCode::Char('m') => self.follow = self.follow.next(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Emulates one video frame: two half frames, each followed by the matching interrupt.
+    fn run_frame(&mut self) {
+        let half_frame = SYSTEM_FREQUENCY / REFRESH_RATE / 2;
+        for irq in [1, 2] {
+            match self.debugger.cont_cycles(half_frame) {
+                Ok(StopReason::CycleLimit) => {}
+                reason => {
+                    self.running = false;
+                    self.report(reason);
+                    return;
+                }
+            }
+            if self.video_interrupts {
+                if let Err(e) = self.debugger.interrupt(irq) {
+                    self.running = false;
+                    self.status = format!("Error: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn report(&mut self, reason: Result<StopReason, impl std::fmt::Display>) {
+        self.status = match reason {
+            Ok(StopReason::Step) | Ok(StopReason::CycleLimit) => "Paused".to_string(),
+            Ok(StopReason::Breakpoint(addr)) => format!("Breakpoint at {:04x}", addr),
+            Ok(StopReason::Watchpoint(MemoryAccess::Read(addr))) => {
+                format!("Watchpoint: read of {:04x}", addr)
+            }
+            Ok(StopReason::Watchpoint(MemoryAccess::Write(addr))) => {
+                format!("Watchpoint: write to {:04x}", addr)
+            }
+            Ok(StopReason::Halted) => "CPU halted".to_string(),
+            Ok(StopReason::InstructionLimit) => "Instruction limit reached".to_string(),
+            Err(e) => format!("Error: {}", e),
+        };
+        let mismatches = self.debugger.system_mut().take_stack_mismatches();
+        if let Some(last) = mismatches.last() {
+            self.status = format!("{}  |  stack: {}", self.status, last);
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(2)]).areas(frame.area());
+        let [left, middle, video] = Layout::horizontal([
+            Constraint::Length(34),
+            Constraint::Length(62),
+            Constraint::Min(0),
+        ])
+        .areas(main);
+        let [registers, stack] =
+            Layout::vertical([Constraint::Length(8), Constraint::Min(0)]).areas(left);
+        let [disassembly, memory] =
+            Layout::vertical([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(middle);
+
+        self.draw_registers(frame, registers);
+        self.draw_stack(frame, stack);
+        self.draw_disassembly(frame, disassembly);
+        self.draw_memory(frame, memory);
+        self.draw_video(frame, video);
+
+        let status_text = vec![
+            Line::from(format!(
+                "{}  |  {} instructions, {} cycles  |  video IRQs {}",
+                self.status,
+                self.debugger.instructions(),
+                self.debugger.cycles(),
+                if self.video_interrupts { "on" } else { "off" }
+            )),
+            Line::from(KEYS).style(Style::default().fg(Color::DarkGray)),
+        ];
+        frame.render_widget(Paragraph::new(status_text), status);
+    }
+
+    fn draw_registers(&self, frame: &mut Frame, area: Rect) {
+        let cpu = self.debugger.system().cpu();
+        let flag = |name: &'static str, bit| {
+            if cpu.flag(bit) {
+                name.to_string()
+            } else {
+                "-".repeat(name.len())
+            }
+        };
+        let lines = vec![
+            Line::from(format!(
+                "A  {:02x}    PSW {:04x}",
+                cpu.get(Register::A),
+                cpu.psw()
+            )),
+            Line::from(format!(
+                "B  {:02x}  C {:02x}    BC {:04x}",
+                cpu.get(Register::B),
+                cpu.get(Register::C),
+                cpu.get_rp(RegisterPair::B)
+            )),
+            Line::from(format!(
+                "D  {:02x}  E {:02x}    DE {:04x}",
+                cpu.get(Register::D),
+                cpu.get(Register::E),
+                cpu.get_rp(RegisterPair::D)
+            )),
+            Line::from(format!(
+                "H  {:02x}  L {:02x}    HL {:04x}",
+                cpu.get(Register::H),
+                cpu.get(Register::L),
+                cpu.get_rp(RegisterPair::H)
+            )),
+            Line::from(format!("SP {:04x}  PC {:04x}", cpu.sp(), cpu.pc())),
+            Line::from(format!(
+                "{} {} {} {} {}  INTE {}",
+                flag("S", Flag::S),
+                flag("Z", Flag::Z),
+                flag("AC", Flag::Ac),
+                flag("P", Flag::P),
+                flag("CY", Flag::Cy),
+                cpu.inte() as u8
+            )),
+        ];
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Registers")),
+            area,
+        );
+    }
+
+    fn draw_stack(&self, frame: &mut Frame, area: Rect) {
+        let system = self.debugger.system();
+        let sp = system.cpu().sp();
+        let rows = area.height.saturating_sub(2);
+        let lines = (0..rows)
+            .map(|i| {
+                let addr = sp.wrapping_add(2 * i);
+                let ram = system.ram();
+                match (ram.peek(addr), ram.peek(addr.wrapping_add(1))) {
+                    (Ok(l), Ok(h)) => Line::from(format!(
+                        "{:04x}  {:02x}{:02x}{}",
+                        addr,
+                        h,
+                        l,
+                        if i == 0 { "  <- SP" } else { "" }
+                    )),
+                    _ => Line::from(format!("{:04x}  ????", addr)),
+                }
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Stack")),
+            area,
+        );
+    }
+
+    fn draw_disassembly(&self, frame: &mut Frame, area: Rect) {
+        let system = self.debugger.system();
+        let pc = system.cpu().pc();
+        let rows = area.height.saturating_sub(2) as usize;
+        let lines = self
+            .debugger
+            .disassemble_around(pc, rows as u16 / 3, rows)
+            .into_iter()
+            .map(|(addr, instruction)| {
+                let breakpoint = if self.debugger.breakpoints().contains(&addr) {
+                    "*"
+                } else {
+                    " "
+                };
+                let line = Line::from(format!("{}{:04x}  {}", breakpoint, addr, instruction));
+                if addr == pc {
+                    line.style(Style::default().add_modifier(Modifier::REVERSED))
+                } else {
+                    line
+                }
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Disassembly")),
+            area,
+        );
+    }
+
+    fn draw_memory(&self, frame: &mut Frame, area: Rect) {
+        let system = self.debugger.system();
+        let target = self.follow.addr(system);
+        let rows = area.height.saturating_sub(2);
+        let start = (target & !0x0f).wrapping_sub(16 * (rows / 3));
+        let lines = (0..rows)
+            .map(|row| {
+                let row_addr = start.wrapping_add(16 * row);
+                let mut line = format!("{:04x} ", row_addr);
+                for i in 0..16 {
+                    let addr = row_addr.wrapping_add(i);
+                    let separator = if addr == target { '>' } else { ' ' };
+                    match system.ram().peek(addr) {
+                        Ok(byte) => line += &format!("{}{:02x}", separator, byte),
+                        Err(_) => line += &format!("{}..", separator),
+                    }
+                }
+                Line::from(line)
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(format!(
+                "Memory @ {} = {:04x}",
+                self.follow.name(),
+                target
+            ))),
+            area,
+        );
+    }
+
+    fn draw_video(&self, frame: &mut Frame, area: Rect) {
+        let video = self.debugger.system().get_slice(VIDEO_RAM).unwrap_or(&[]);
+        let coords = video
+            .iter()
+            .take(MEMORY_WIDTH * MEMORY_HEIGHT)
+            .enumerate()
+            .flat_map(|(i, byte)| {
+                // The screen is rotated: each memory row is a column, bottom to top.
+                let y = i / MEMORY_WIDTH;
+                let x = (i % MEMORY_WIDTH) * 8;
+                (0..8)
+                    .filter(move |bit| byte & (1 << bit) != 0)
+                    .map(move |bit| (y as f64, (x + bit) as f64))
+            })
+            .collect::<Vec<_>>();
+        let canvas = Canvas::default()
+            .block(Block::bordered().title(format!("Video RAM @ {:04x}", VIDEO_RAM)))
+            .marker(Marker::Braille)
+            .x_bounds([0.0, MEMORY_HEIGHT as f64])
+            .y_bounds([0.0, (MEMORY_WIDTH * 8) as f64])
+            .paint(|ctx| {
+                ctx.draw(&Points {
+                    coords: &coords,
+                    color: Color::White,
+                })
+            });
+        frame.render_widget(canvas, area);
+    }
+}
@@ -0,0 +1,294 @@
+use std::fmt::{self, Write};
+
+use crate::{cpu_state::stack_above, op_code::Instruction, symbols::SymbolTable};
+
+/// Frames kept at most; older ones are dropped so runaway recursion cannot exhaust memory.
+const MAX_DEPTH: usize = 1024;
+/// Mismatches kept at most until taken; older ones are dropped.
+const MAX_MISMATCHES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+/// A subroutine entered through a `CALL`, an `RST` or an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// Address of the calling instruction, or of the interrupted one.
+    pub call_site: u16,
+    pub entry: u16,
+    pub return_addr: u16,
+    /// Stack pointer right after the return address was pushed.
+    pub sp: u16,
+}
+
+/// A break from plain call/return nesting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+    /// A return to `to` unwound `frames` frames at once, e.g. when switching back from
+    /// another stack.
+    Unwound { pc: u16, to: u16, frames: usize },
+    /// A return to an address no frame expected, e.g. one pushed by hand before a `RET`.
+    UnexpectedReturn { pc: u16, to: u16 },
+    /// The stack pointer moved above the return address of `frames` frames, e.g. through
+    /// `SPHL`/`LXI SP`.
+    Discarded { pc: u16, frames: usize },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Unwound { pc, to, frames } => {
+                write!(
+                    f,
+                    "{:04x}: return to {:04x} unwound {} frames",
+                    pc, to, frames
+                )
+            }
+            Mismatch::UnexpectedReturn { pc, to } => {
+                write!(f, "{:04x}: return to {:04x}, which no call pushed", pc, to)
+            }
+            Mismatch::Discarded { pc, frames } => write!(
+                f,
+                "{:04x}: stack pointer moved above the return address of {} frames",
+                pc, frames
+            ),
+        }
+    }
+}
+
+/// Shadow call stack rebuilt from the calls, returns and interrupts actually executed.
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    mismatches: Vec<Mismatch>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Innermost frame last.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Latest mismatches seen since the last call to [`CallStack::take_mismatches`].
+    pub fn mismatches(&self) -> &[Mismatch] {
+        &self.mismatches
+    }
+
+    pub fn take_mismatches(&mut self) -> Vec<Mismatch> {
+        std::mem::take(&mut self.mismatches)
+    }
+
+    /// Records an instruction executed at `pc`, given the CPU state around its execution.
+    pub fn record(
+        &mut self,
+        pc: u16,
+        instruction: Instruction,
+        sp_before: u16,
+        sp_after: u16,
+        pc_after: u16,
+    ) {
+        let next = pc.wrapping_add(instruction.size());
+        if instruction.is_call() && sp_after == sp_before.wrapping_sub(2) {
+            let kind = match instruction {
+                Instruction::Rst(_) => FrameKind::Rst,
+                _ => FrameKind::Call,
+            };
+            self.push(kind, pc, pc_after, next, sp_after);
+        } else if instruction.is_return() && sp_after == sp_before.wrapping_add(2) {
+            self.ret(pc, pc_after, sp_before);
+        } else {
+            self.discard_above(pc, sp_after);
+        }
+    }
+
+    /// Records an interrupt taken at `pc` that jumped to `vector`.
+    pub fn record_interrupt(&mut self, pc: u16, vector: u16, sp_after: u16) {
+        self.push(FrameKind::Interrupt, pc, vector, pc, sp_after);
+    }
+
+    fn push(&mut self, kind: FrameKind, call_site: u16, entry: u16, return_addr: u16, sp: u16) {
+        self.discard_above(call_site, sp.wrapping_add(2));
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(Frame {
+            kind,
+            call_site,
+            entry,
+            return_addr,
+            sp,
+        });
+    }
+
+    fn ret(&mut self, pc: u16, to: u16, sp: u16) {
+        let matching = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.return_addr == to && frame.sp == sp);
+        match matching {
+            Some(i) => {
+                let frames = self.frames.len() - i;
+                self.frames.truncate(i);
+                if frames > 1 {
+                    self.note(Mismatch::Unwound { pc, to, frames });
+                }
+            }
+            None => {
+                self.note(Mismatch::UnexpectedReturn { pc, to });
+                self.discard_above(pc, sp.wrapping_add(2));
+            }
+        }
+    }
+
+    fn discard_above(&mut self, pc: u16, sp: u16) {
+        let kept = self
+            .frames
+            .iter()
+            .position(|frame| stack_above(sp, frame.sp))
+            .unwrap_or(self.frames.len());
+        let frames = self.frames.len() - kept;
+        if frames > 0 {
+            self.frames.truncate(kept);
+            self.note(Mismatch::Discarded { pc, frames });
+        }
+    }
+
+    fn note(&mut self, mismatch: Mismatch) {
+        if self.mismatches.len() == MAX_MISMATCHES {
+            self.mismatches.remove(0);
+        }
+        self.mismatches.push(mismatch);
+    }
+
+    /// Renders the stack innermost frame first, starting with the current `pc`.
+    pub fn backtrace(&self, pc: u16, symbols: &SymbolTable) -> String {
+        let location = |addr: u16| match symbols.format(addr) {
+            name if name == format!("{:04x}", addr) => name,
+            name => format!("{:04x} {}", addr, name),
+        };
+        let mut out = String::new();
+        let _ = writeln!(out, "#0  {}", location(pc));
+        for (i, frame) in self.frames.iter().rev().enumerate() {
+            let kind = match frame.kind {
+                FrameKind::Call => "",
+                FrameKind::Rst => " (rst)",
+                FrameKind::Interrupt => " (interrupt)",
+            };
+            let _ = writeln!(out, "#{:<2} {}{}", i + 1, location(frame.call_site), kind);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{i8080, in_out::DummyInOut, op_code::Instruction, symbols::SymbolTable, testing};
+
+    use super::{CallStack, Mismatch};
+
+    #[test]
+    fn backtrace_and_mismatches() {
+        let program = i8080! {
+            LXI SP,0800H
+            CALL outer
+            HLT
+            outer: CALL inner
+            HLT
+            inner: POP B
+            RET
+        };
+        let mut system = testing::load(&program, 0);
+        system.enable_call_stack();
+
+        testing::step(&mut system, 3);
+        let call_stack = system.call_stack().unwrap();
+        assert_eq!(call_stack.frames().len(), 2);
+        assert_eq!(
+            system.backtrace().unwrap(),
+            "#0  000b\n#1  0007\n#2  0003\n"
+        );
+
+        // POP B drops the inner return address, RET goes straight back to the outer caller.
+        testing::step(&mut system, 2);
+        let call_stack = system.call_stack().unwrap();
+        assert!(call_stack.frames().is_empty());
+        assert_eq!(
+            call_stack.mismatches(),
+            [Mismatch::Discarded {
+                pc: 0x0b,
+                frames: 1
+            }]
+        );
+        let mismatches = system.take_stack_mismatches();
+        assert_eq!(
+            mismatches[0].to_string(),
+            "000b: stack pointer moved above the return address of 1 frames"
+        );
+        assert!(system.call_stack().unwrap().mismatches().is_empty());
+    }
+
+    #[test]
+    fn rst_and_interrupt_frames() {
+        let symbols = SymbolTable::new();
+        assert_eq!(CallStack::new().backtrace(0x1234, &symbols), "#0  1234\n");
+
+        let program = i8080! {
+            JMP start
+            ORG 8
+            NOP; RET
+            ORG 10H
+            RET
+            start: LXI SP,0800H
+            EI
+            RST 1
+            HLT
+        };
+        let mut system = testing::load(&program, 0);
+        system.enable_call_stack();
+        testing::step(&mut system, 4);
+        system.process(Instruction::Rst(2), &DummyInOut).unwrap();
+        assert_eq!(
+            system.backtrace().unwrap(),
+            "#0  0010\n#1  0008 (interrupt)\n#2  0015 (rst)\n"
+        );
+
+        testing::run(&mut system);
+        let call_stack = system.call_stack().unwrap();
+        assert!(call_stack.frames().is_empty());
+        assert!(call_stack.mismatches().is_empty());
+    }
+
+    #[test]
+    fn wraps_around_memory() {
+        // The first return address is pushed at 0, the second one at 0xfffe.
+        let program = i8080! {
+            ORG 100H
+            LXI SP,2
+            CALL outer
+            HLT
+            outer: CALL inner
+            RET
+            inner: PUSH B
+            POP B
+            RET
+        };
+        let mut system = testing::load(&program, 0x100);
+        system.enable_call_stack();
+        testing::step(&mut system, 4);
+        assert_eq!(system.call_stack().unwrap().frames().len(), 2);
+
+        testing::run(&mut system);
+        let call_stack = system.call_stack().unwrap();
+        assert!(call_stack.frames().is_empty());
+        assert!(call_stack.mismatches().is_empty());
+    }
+}
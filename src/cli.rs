@@ -0,0 +1,33 @@
+//! Command line parsing shared by the binaries: options are taken out of the arguments
+//! wherever they appear, leaving the positional arguments in order.
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CliError {
+    #[error("{0} expects a value.")]
+    MissingValue(String),
+}
+
+/// Removes `flag` from `args`, returning whether it was there.
+pub fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let position = args.iter().position(|arg| arg == flag);
+    if let Some(i) = position {
+        args.remove(i);
+    }
+    position.is_some()
+}
+
+/// Removes the first `name` from `args` along with the value following it, and returns
+/// that value.
+pub fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, CliError> {
+    let Some(i) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    let value = args
+        .get(i + 1)
+        .cloned()
+        .ok_or_else(|| CliError::MissingValue(name.to_string()))?;
+    args.drain(i..i + 2);
+    Ok(Some(value))
+}
@@ -0,0 +1,209 @@
+use std::io;
+use std::path::Path;
+
+use crate::cpu_state::MemoryAccess;
+
+/// How a byte of memory has been used so far, as a set of flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage(u8);
+
+impl Usage {
+    pub const OPCODE: Usage = Usage(1 << 0);
+    pub const OPERAND: Usage = Usage(1 << 1);
+    pub const READ: Usage = Usage(1 << 2);
+    pub const WRITTEN: Usage = Usage(1 << 3);
+    /// First byte of a 16 bit value read in one go (`LHLD`, `POP`, `RET`, ...).
+    pub const WORD: Usage = Usage(1 << 4);
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, other: Usage) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_code(self) -> bool {
+        self.0 & (Usage::OPCODE.0 | Usage::OPERAND.0) != 0
+    }
+
+    /// Read or written, but never fetched as part of an instruction.
+    pub fn is_data(self) -> bool {
+        !self.is_code() && self.0 & (Usage::READ.0 | Usage::WRITTEN.0) != 0
+    }
+
+    fn insert(&mut self, other: Usage) {
+        self.0 |= other.0;
+    }
+}
+
+/// Per byte record of opcode fetches, operand fetches, data reads and writes.
+///
+/// The file format used by [`CoverageMap::save`] is one [`Usage`] byte per address, starting
+/// at address 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageMap {
+    usage: Vec<Usage>,
+}
+
+impl CoverageMap {
+    pub fn new(size: usize) -> Self {
+        CoverageMap {
+            usage: vec![Usage::default(); size],
+        }
+    }
+
+    pub fn get(&self, addr: u16) -> Usage {
+        self.usage.get(addr as usize).copied().unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.usage.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.usage.is_empty()
+    }
+
+    pub fn mark(&mut self, addr: u16, usage: Usage) {
+        if let Some(u) = self.usage.get_mut(addr as usize) {
+            u.insert(usage);
+        }
+    }
+
+    /// Records the fetch of a `size` bytes instruction at `pc`.
+    pub fn mark_instruction(&mut self, pc: u16, size: u16) {
+        self.mark(pc, Usage::OPCODE);
+        for offset in 1..size {
+            self.mark(pc.wrapping_add(offset), Usage::OPERAND);
+        }
+    }
+
+    /// Records the data accesses of a single instruction.
+    pub fn mark_accesses(&mut self, accesses: &[MemoryAccess]) {
+        for (i, access) in accesses.iter().enumerate() {
+            match *access {
+                MemoryAccess::Read(addr) => {
+                    self.mark(addr, Usage::READ);
+                    if let Some(MemoryAccess::Read(next)) = accesses.get(i + 1) {
+                        if *next == addr.wrapping_add(1) {
+                            self.mark(addr, Usage::WORD);
+                        }
+                    }
+                }
+                MemoryAccess::Write(addr) => self.mark(addr, Usage::WRITTEN),
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.usage.iter().map(|u| u.0).collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        CoverageMap {
+            usage: bytes.iter().map(|b| Usage(*b)).collect(),
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::from_bytes(&std::fs::read(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cpu_state::MemoryAccess, i8080, in_out::DummyInOut, op_code::Instruction, testing,
+    };
+
+    use super::{CoverageMap, Usage};
+
+    #[test]
+    fn records_code_and_data() {
+        let program = i8080! {
+            LXI SP,0800H
+            LHLD value
+            SHLD 0100H
+            HLT
+            value: DW 1234H
+        };
+        let mut system = testing::load(&program, 0);
+        system.enable_coverage();
+        testing::run(&mut system);
+
+        let coverage = system.coverage().unwrap();
+        assert_eq!(coverage.get(0x03), Usage::OPCODE);
+        assert_eq!(coverage.get(0x04), Usage::OPERAND);
+        assert!(coverage.get(0x0a).contains(Usage::READ));
+        assert!(coverage.get(0x0a).contains(Usage::WORD));
+        assert!(!coverage.get(0x0b).contains(Usage::WORD));
+        assert_eq!(coverage.get(0x0100), Usage::WRITTEN);
+        assert_eq!(coverage.get(0x0200), Usage::default());
+        assert!(coverage.get(0x09).is_code());
+        assert!(coverage.get(0x0b).is_data());
+        assert!(!coverage.get(0x0c).is_data());
+
+        let mut map = CoverageMap::new(4);
+        map.mark_accesses(&[MemoryAccess::Write(3)]);
+        assert_eq!(CoverageMap::from_bytes(&map.to_bytes()), map);
+    }
+
+    #[test]
+    fn empty_map() {
+        let mut map = CoverageMap::new(0);
+        map.mark_instruction(0, 3);
+        map.mark_accesses(&[MemoryAccess::Read(0), MemoryAccess::Read(1)]);
+        assert!(map.is_empty());
+        assert_eq!(map.get(0), Usage::default());
+        assert!(map.to_bytes().is_empty());
+        assert_eq!(CoverageMap::from_bytes(&[]), map);
+    }
+
+    #[test]
+    fn wraps_around_memory() {
+        let mut system = testing::load(&i8080! { LHLD 0FFFFH; HLT }, 0x100);
+        system.enable_coverage();
+        testing::run(&mut system);
+        let coverage = system.coverage().unwrap();
+        assert_eq!(coverage.get(0xffff), Usage(Usage::READ.0 | Usage::WORD.0));
+        assert_eq!(coverage.get(0x0000), Usage::READ);
+
+        let mut map = CoverageMap::new(0x10000);
+        map.mark_instruction(0xffff, 3);
+        assert_eq!(map.get(0xffff), Usage::OPCODE);
+        assert_eq!(map.get(0x0000), Usage::OPERAND);
+        assert_eq!(map.get(0x0001), Usage::OPERAND);
+    }
+
+    #[test]
+    fn interrupts_push_without_fetching() {
+        let program = i8080! {
+            LXI SP,0800H
+            EI
+            NOP
+            HLT
+            ORG 8
+            RET
+        };
+        let mut system = testing::load(&program, 0);
+        system.enable_coverage();
+        testing::step(&mut system, 2);
+        system.process(Instruction::Rst(1), &DummyInOut).unwrap();
+        let coverage = system.coverage().unwrap();
+        // The RST is supplied by the interrupting device, not fetched from memory.
+        assert_eq!(coverage.get(0x04), Usage::default());
+        assert_eq!(coverage.get(0x07fe), Usage::WRITTEN);
+
+        testing::run(&mut system);
+        let coverage = system.coverage().unwrap();
+        assert_eq!(coverage.get(0x08), Usage::OPCODE);
+        assert!(coverage.get(0x07fe).contains(Usage::READ));
+        assert!(coverage.get(0x07fe).contains(Usage::WORD));
+        assert_eq!(coverage.get(0x04), Usage::OPCODE);
+    }
+}
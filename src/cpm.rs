@@ -0,0 +1,418 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use thiserror::Error;
+
+use crate::{
+    cpm_files::{self, HostFiles},
+    cpu_state::{MemoryError, Ram, System},
+    in_out::PortLatch,
+    op_code::{Instruction, OpCodeError, Register, RegisterPair},
+};
+
+/// Where `.COM` programs are loaded and started.
+pub const TPA: u16 = 0x100;
+/// Entry point of the emulated BDOS, as found at 0x0006 by programs sizing their memory.
+pub const BDOS: u16 = 0xfe00;
+/// Start of the BIOS jump table; 0x0000 jumps to its warm boot entry.
+pub const BIOS: u16 = 0xff00;
+
+#[derive(Error, Debug)]
+pub enum CpmError {
+    #[error("The program is {0:#x} bytes long, which does not fit below the BDOS.")]
+    TooLarge(usize),
+
+    #[error("BDOS function {0} is not supported (called from {1:04x}).")]
+    UnsupportedFunction(u8, u16),
+
+    #[error("There is no system on the disk in drive A.")]
+    NoSystemDisk,
+
+    #[error("The BIOS tables of the disks inserted do not fit in memory.")]
+    BiosTooLarge,
+
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+
+    #[error(transparent)]
+    OpCode(#[from] OpCodeError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Character device behind the BDOS console functions.
+pub trait Console {
+    /// Next input byte, waiting for one; `None` at the end of input.
+    fn read(&mut self) -> Option<u8>;
+    /// Whether `read` would return immediately.
+    fn ready(&mut self) -> bool;
+    fn write(&mut self, byte: u8);
+}
+
+/// The host's standard input and output.
+#[derive(Debug, Default)]
+pub struct StdConsole {
+    at_end: bool,
+}
+
+impl Console for StdConsole {
+    fn read(&mut self) -> Option<u8> {
+        let _ = io::stdout().flush();
+        let mut byte = [0];
+        match io::stdin().read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => {
+                self.at_end = true;
+                None
+            }
+        }
+    }
+
+    /// Standard input offers no way to poll, so input is deemed ready until it ends.
+    fn ready(&mut self) -> bool {
+        !self.at_end
+    }
+
+    fn write(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[byte]);
+        if byte == b'\n' {
+            let _ = stdout.flush();
+        }
+    }
+}
+
+/// Console reading from a fixed input and recording the output, e.g. for tests.
+#[derive(Debug, Clone, Default)]
+pub struct BufferConsole {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl BufferConsole {
+    pub fn new(input: &[u8]) -> Self {
+        BufferConsole {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Output so far, as text.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+impl Console for BufferConsole {
+    fn read(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn ready(&mut self) -> bool {
+        !self.input.is_empty()
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+}
+
+/// Why a program stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// Jumped to 0x0000, returned from the program or called BDOS function 0.
+    WarmBoot,
+    Halt,
+    /// The console input ended while a program waited for it.
+    EndOfInput,
+}
+
+/// A CP/M 2.2 machine reduced to what programs need: a `.COM` file in a 64K memory, the
+/// BDOS console and file functions served by the host, and page zero pointing at them.
+pub struct Cpm<C> {
+    system: System,
+    console: C,
+    files: HostFiles,
+    io: PortLatch,
+}
+
+impl<C: Console> Cpm<C> {
+    /// Loads `program` at [`TPA`], ready to run with the stack below the BDOS and 0x0000
+    /// on it, so that returning from the program warm boots.
+    pub fn new(program: &[u8], console: C) -> Result<Self, CpmError> {
+        if program.len() > (BDOS - TPA) as usize - 2 {
+            return Err(CpmError::TooLarge(program.len()));
+        }
+        let mut ram = Ram::new(0x10000, false);
+        let [bios_l, bios_h] = (BIOS + 3).to_le_bytes();
+        let [bdos_l, bdos_h] = BDOS.to_le_bytes();
+        let page_zero = [0xc3, bios_l, bios_h, 0, 0, 0xc3, bdos_l, bdos_h];
+        // The BDOS is served by the host as soon as it is entered, and then returns; the
+        // warm boot entry of the BIOS goes back to 0x0000.
+        let images: [(u16, &[u8]); 4] = [
+            (0, &page_zero),
+            (BDOS, &[0xc9]),
+            (BIOS + 3, &[0xc3, 0, 0]),
+            (TPA, program),
+        ];
+        for (start, bytes) in images {
+            for (offset, byte) in bytes.iter().enumerate() {
+                ram.poke(start + offset as u16, *byte)?;
+            }
+        }
+        // Sets the stack up the way the CCP leaves it, then hands over to the program.
+        let mut system = System::new(ram, 0);
+        let [l, h] = (BDOS - 2).to_le_bytes();
+        let io = PortLatch::default();
+        system.execute(Instruction::Lxi(RegisterPair::SP, l, h), &io)?;
+        system.execute(Instruction::Jmp(TPA), &io)?;
+        let mut cpm = Cpm {
+            system,
+            console,
+            files: HostFiles::new(),
+            io,
+        };
+        cpm.set_command_line("")?;
+        Ok(cpm)
+    }
+
+    /// Passes `arguments` to the program the way the CCP does: as the command tail at 0x80,
+    /// and parsed into the file control blocks at 0x5C and 0x6C for the first two.
+    pub fn set_command_line(&mut self, arguments: &str) -> Result<(), CpmError> {
+        let arguments = arguments.trim().to_ascii_uppercase();
+        let mut tail = if arguments.is_empty() {
+            Vec::new()
+        } else {
+            format!(" {}", arguments).into_bytes()
+        };
+        tail.truncate(0x7f - 1);
+        let mut words = arguments.split_whitespace();
+        let fcbs =
+            [0x5c, 0x6c].map(|addr| (addr, cpm_files::parse_fcb(words.next().unwrap_or(""))));
+        let ram = self.system.ram_mut();
+        for (addr, fcb) in fcbs {
+            for (offset, byte) in fcb.iter().chain(&[0; 4]).enumerate() {
+                ram.poke(addr + offset as u16, *byte)?;
+            }
+        }
+        ram.poke(0x80, tail.len() as u8)?;
+        for (offset, byte) in tail.iter().chain(&[0]).enumerate() {
+            ram.poke(0x81 + offset as u16, *byte)?;
+        }
+        Ok(())
+    }
+
+    /// Drives whose files are served from host directories.
+    pub fn files_mut(&mut self) -> &mut HostFiles {
+        &mut self.files
+    }
+
+    pub fn system(&self) -> &System {
+        &self.system
+    }
+
+    pub fn system_mut(&mut self) -> &mut System {
+        &mut self.system
+    }
+
+    pub fn console(&self) -> &C {
+        &self.console
+    }
+
+    pub fn console_mut(&mut self) -> &mut C {
+        &mut self.console
+    }
+
+    /// Executes one instruction, or the BDOS function called if the program entered it.
+    /// Returns why the program stopped, if it did.
+    pub fn step(&mut self) -> Result<Option<Exit>, CpmError> {
+        let pc = self.system.cpu().pc();
+        if pc == 0 {
+            return Ok(Some(Exit::WarmBoot));
+        }
+        if pc == BDOS {
+            if let Some(exit) = self.bdos()? {
+                return Ok(Some(exit));
+            }
+        }
+        let instruction = self.system.next_instruction()?;
+        match self.system.execute(instruction, &self.io)? {
+            Some(_) => Ok(None),
+            None => Ok(Some(Exit::Halt)),
+        }
+    }
+
+    /// Runs the program until it stops.
+    pub fn run(&mut self) -> Result<Exit, CpmError> {
+        loop {
+            if let Some(exit) = self.step()? {
+                return Ok(exit);
+            }
+        }
+    }
+
+    /// Performs the BDOS function in C with parameter DE. Returns why the program stopped if
+    /// the function warm boots or waits for input that has ended.
+    fn bdos(&mut self) -> Result<Option<Exit>, CpmError> {
+        let function = self.system.cpu().get(Register::C);
+        let e = self.system.cpu().get(Register::E);
+        let de = self.system.cpu().get_rp(RegisterPair::D);
+        let result: u16 = match function {
+            0 => return Ok(Some(Exit::WarmBoot)),
+            1 => {
+                let Some(byte) = self.console.read() else {
+                    return Ok(Some(Exit::EndOfInput));
+                };
+                self.console.write(byte);
+                byte as u16
+            }
+            2 => {
+                self.console.write(e);
+                0
+            }
+            6 if e == 0xff => match self.console.ready() {
+                true => match self.console.read() {
+                    Some(byte) => byte as u16,
+                    None => return Ok(Some(Exit::EndOfInput)),
+                },
+                false => 0,
+            },
+            6 => {
+                self.console.write(e);
+                0
+            }
+            9 => {
+                let mut addr = de;
+                loop {
+                    let byte = self.system.ram().peek(addr)?;
+                    if byte == b'$' {
+                        break;
+                    }
+                    self.console.write(byte);
+                    addr = addr.wrapping_add(1);
+                }
+                0
+            }
+            10 => {
+                if !self.read_line(de)? {
+                    return Ok(Some(Exit::EndOfInput));
+                }
+                0
+            }
+            11 => match self.console.ready() {
+                true => 0xff,
+                false => 0,
+            },
+            function => {
+                if let Some(result) = self.files.call(function, de, self.system.ram_mut())? {
+                    self.set_result(result)?;
+                    return Ok(None);
+                }
+                let sp = self.system.cpu().sp();
+                let ram = self.system.ram();
+                let ret = u16::from_le_bytes([ram.peek(sp)?, ram.peek(sp.wrapping_add(1))?]);
+                return Err(CpmError::UnsupportedFunction(function, ret.wrapping_sub(3)));
+            }
+        };
+        self.set_result(result)?;
+        Ok(None)
+    }
+
+    /// Returns `result` in HL, its low byte also in A and its high byte in B.
+    fn set_result(&mut self, result: u16) -> Result<(), CpmError> {
+        let [low, high] = result.to_le_bytes();
+        *self.system.get_mut(Register::A)? = low;
+        *self.system.get_mut(Register::L)? = low;
+        *self.system.get_mut(Register::B)? = high;
+        *self.system.get_mut(Register::H)? = high;
+        Ok(())
+    }
+
+    /// Function 10: reads a line into the buffer at `buffer`, whose first byte is its
+    /// capacity; the second receives the length read. The line is echoed, backspace
+    /// and delete erase the last character. Returns `false` if the input ended before
+    /// anything was typed.
+    fn read_line(&mut self, buffer: u16) -> Result<bool, CpmError> {
+        let capacity = self.system.ram().peek(buffer)? as usize;
+        let mut line = Vec::new();
+        while line.len() < capacity {
+            match self.console.read() {
+                None if line.is_empty() => return Ok(false),
+                None | Some(b'\r') | Some(b'\n') => break,
+                Some(0x08) | Some(0x7f) => {
+                    if line.pop().is_some() {
+                        for byte in [0x08, b' ', 0x08] {
+                            self.console.write(byte);
+                        }
+                    }
+                }
+                Some(byte) => {
+                    self.console.write(byte);
+                    line.push(byte);
+                }
+            }
+        }
+        self.console.write(b'\r');
+        let ram = self.system.ram_mut();
+        ram.poke(buffer.wrapping_add(1), line.len() as u8)?;
+        for (i, byte) in line.iter().enumerate() {
+            ram.poke(buffer.wrapping_add(2 + i as u16), *byte)?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::i8080;
+
+    use super::{BufferConsole, Cpm, Exit};
+
+    #[test]
+    fn console_functions() {
+        let program = i8080! {
+            ORG 100H
+            MVI C,9; LXI D,prompt; CALL 5
+            MVI C,10; LXI D,buffer; CALL 5
+            MVI C,1; CALL 5
+            MOV E,A; MVI C,2; CALL 5
+            MVI C,11; CALL 5
+            STA status
+            RET
+            prompt: DB "Name? $"
+            buffer: DB 8; DS 9
+            status: DB 0
+        };
+        let mut cpm = Cpm::new(&program, BufferConsole::new(b"Ada\rx")).unwrap();
+        assert_eq!(cpm.run().unwrap(), Exit::WarmBoot);
+        assert_eq!(cpm.console().text(), "Name? Ada\rxx");
+        let ram = cpm.system().ram();
+        let buffer = 0x100 + program.len() as u16 - 11;
+        assert_eq!(ram.peek(buffer + 1).unwrap(), 3);
+        assert_eq!(ram.peek(buffer + 2).unwrap(), b'A');
+        // Input was exhausted by the time of the status call.
+        assert_eq!(ram.peek(buffer + 10).unwrap(), 0);
+    }
+
+    #[test]
+    fn input_runs_out() {
+        let program = i8080! {
+            ORG 100H
+            MVI C,10; LXI D,buffer; CALL 5
+            MVI C,1; CALL 5
+            RET
+            buffer: DB 8
+        };
+        // A line cut short by the end of input is still returned.
+        let mut cpm = Cpm::new(&program, BufferConsole::new(b"Ada")).unwrap();
+        assert_eq!(cpm.run().unwrap(), Exit::EndOfInput);
+        assert_eq!(cpm.console().text(), "Ada\r");
+        let count = 0x100 + program.len() as u16;
+        assert_eq!(cpm.system().ram().peek(count).unwrap(), 3);
+
+        let mut cpm = Cpm::new(&program, BufferConsole::new(b"")).unwrap();
+        assert_eq!(cpm.run().unwrap(), Exit::EndOfInput);
+        assert_eq!(cpm.console().text(), "");
+    }
+}
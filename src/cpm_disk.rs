@@ -0,0 +1,340 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::cpm_files::RECORD;
+
+/// Content of freshly formatted sectors, also used to pad short images.
+const FORMATTED: u8 = 0xe5;
+
+#[derive(Error, Debug)]
+pub enum DiskError {
+    #[error("{0}: the image is {1} bytes long, more than the {2} bytes of its format.")]
+    TooLarge(PathBuf, usize, usize),
+
+    #[error("Invalid disk format: {0}.")]
+    InvalidFormat(&'static str),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Geometry and disk parameter block of a disk with 128 byte sectors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskFormat {
+    tracks: u16,
+    sectors_per_track: u16,
+    /// Physical sector, counted from 1, of each logical sector; empty when sectors are
+    /// not interleaved.
+    skew: Vec<u8>,
+    /// Allocation block size is 128 << `block_shift` bytes.
+    block_shift: u8,
+    extent_mask: u8,
+    /// Blocks on the disk, DSM + 1.
+    blocks: u16,
+    /// Directory entries, DRM + 1.
+    directory_entries: u16,
+    /// Directory entries checked for disk changes, 0 for fixed disks.
+    checked_entries: u16,
+    /// Tracks holding the system, before the directory.
+    reserved_tracks: u16,
+}
+
+impl DiskFormat {
+    /// A removable disk format, checked against the limits of CP/M 2.2: blocks of 1K to
+    /// 16K, a directory of at most 16 blocks, and the blocks fitting in the tracks after
+    /// the reserved ones. `skew` is empty or lists every sector once.
+    pub fn new(
+        tracks: u16,
+        sectors_per_track: u16,
+        skew: Vec<u8>,
+        block_shift: u8,
+        blocks: u16,
+        directory_entries: u16,
+        reserved_tracks: u16,
+    ) -> Result<Self, DiskError> {
+        let invalid = |reason| Err(DiskError::InvalidFormat(reason));
+        if tracks == 0 || sectors_per_track == 0 {
+            return invalid("no sectors");
+        }
+        let mut sectors = skew.clone();
+        sectors.sort_unstable();
+        if !skew.is_empty() && !sectors.iter().copied().eq(1..=sectors_per_track as u8) {
+            return invalid("the skew does not list every sector once");
+        }
+        if !(3..=7).contains(&block_shift) {
+            return invalid("blocks must be of 1K to 16K");
+        }
+        if blocks == 0 || directory_entries == 0 {
+            return invalid("no blocks or directory entries");
+        }
+        if block_shift == 3 && blocks > 256 {
+            return invalid("disks of more than 256 blocks need blocks of 2K or more");
+        }
+        let directory_bytes = directory_entries as u32 * 32;
+        let directory_blocks = directory_bytes.div_ceil((RECORD as u32) << block_shift);
+        if directory_blocks > 16.min(blocks as u32) {
+            return invalid("the directory takes more than 16 blocks");
+        }
+        let data_tracks = tracks.saturating_sub(reserved_tracks) as u64;
+        let capacity = data_tracks * sectors_per_track as u64 * RECORD as u64;
+        if ((blocks as u64) << block_shift) * RECORD as u64 > capacity {
+            return invalid("the blocks do not fit on the disk");
+        }
+        // Each directory entry maps 16K, or 16 blocks of disks of more than 256 blocks.
+        let extent_mask = match blocks > 256 {
+            false => (1 << (block_shift - 3)) - 1,
+            true => (1 << (block_shift - 4)) - 1,
+        };
+        Ok(DiskFormat {
+            tracks,
+            sectors_per_track,
+            skew,
+            block_shift,
+            extent_mask,
+            blocks,
+            directory_entries,
+            checked_entries: directory_entries,
+            reserved_tracks,
+        })
+    }
+
+    /// The same format for a fixed disk, whose directory is not checked for changes.
+    pub fn fixed(self) -> Self {
+        DiskFormat {
+            checked_entries: 0,
+            ..self
+        }
+    }
+
+    /// 8" single sided, single density disks, the CP/M 2.2 distribution format.
+    pub fn ibm_3740() -> Self {
+        DiskFormat {
+            tracks: 77,
+            sectors_per_track: 26,
+            skew: vec![
+                1, 7, 13, 19, 25, 5, 11, 17, 23, 3, 9, 15, 21, 2, 8, 14, 20, 26, 6, 12, 18, 24, 4,
+                10, 16, 22,
+            ],
+            block_shift: 3,
+            extent_mask: 0,
+            blocks: 243,
+            directory_entries: 64,
+            checked_entries: 64,
+            reserved_tracks: 2,
+        }
+    }
+
+    /// The 4 MB hard disk of z80pack and most CP/M emulators.
+    pub fn z80pack_hd() -> Self {
+        DiskFormat {
+            tracks: 255,
+            sectors_per_track: 128,
+            skew: Vec::new(),
+            block_shift: 4,
+            extent_mask: 0,
+            blocks: 2040,
+            directory_entries: 1024,
+            checked_entries: 0,
+            reserved_tracks: 0,
+        }
+    }
+
+    /// Format of an image of `size` bytes: the hard disk for images of exactly its size,
+    /// the 3740 otherwise.
+    pub fn for_image_size(size: usize) -> Self {
+        let hd = Self::z80pack_hd();
+        if size == hd.size() {
+            hd
+        } else {
+            Self::ibm_3740()
+        }
+    }
+
+    pub fn sectors_per_track(&self) -> u16 {
+        self.sectors_per_track
+    }
+
+    pub fn skew(&self) -> &[u8] {
+        &self.skew
+    }
+
+    pub fn reserved_tracks(&self) -> u16 {
+        self.reserved_tracks
+    }
+
+    pub fn size(&self) -> usize {
+        self.tracks as usize * self.sectors_per_track as usize * RECORD
+    }
+
+    /// The 15 byte disk parameter block, as read by the BDOS.
+    pub fn dpb(&self) -> [u8; 15] {
+        let block_mask = (1u8 << self.block_shift) - 1;
+        // The directory takes the first blocks, marked as allocated from the top bit down.
+        let directory_blocks =
+            (self.directory_entries as u32 * 32).div_ceil((RECORD as u32) << self.block_shift);
+        let allocation = (!0u16)
+            .checked_shl(16 - directory_blocks.min(16))
+            .unwrap_or_default();
+        let mut dpb = [0; 15];
+        dpb[0..2].copy_from_slice(&self.sectors_per_track.to_le_bytes());
+        dpb[2] = self.block_shift;
+        dpb[3] = block_mask;
+        dpb[4] = self.extent_mask;
+        dpb[5..7].copy_from_slice(&(self.blocks - 1).to_le_bytes());
+        dpb[7..9].copy_from_slice(&(self.directory_entries - 1).to_le_bytes());
+        dpb[9..11].copy_from_slice(&allocation.to_be_bytes());
+        dpb[11..13].copy_from_slice(&self.check_vector_size().to_le_bytes());
+        dpb[13..15].copy_from_slice(&self.reserved_tracks.to_le_bytes());
+        dpb
+    }
+
+    /// Bytes of the allocation vector, one bit per block.
+    pub fn allocation_vector_size(&self) -> u16 {
+        self.blocks.div_ceil(8)
+    }
+
+    /// Bytes of the directory check vector, one per 4 entries checked.
+    pub fn check_vector_size(&self) -> u16 {
+        self.checked_entries / 4
+    }
+}
+
+/// Disk image laid out track after track, sectors in physical order.
+#[derive(Debug, Clone)]
+pub struct Disk {
+    format: DiskFormat,
+    data: Vec<u8>,
+    /// File sectors are written back to.
+    file: Option<PathBuf>,
+    /// Whether the file is shorter than `data`, and needs to be written whole first.
+    short: bool,
+}
+
+impl Disk {
+    /// A formatted disk that only lives in memory.
+    pub fn blank(format: DiskFormat) -> Self {
+        Disk {
+            data: vec![FORMATTED; format.size()],
+            format,
+            file: None,
+            short: false,
+        }
+    }
+
+    /// Opens the image in `path`, whose format is guessed from its size. Writes go to the
+    /// file as they happen.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, DiskError> {
+        let data = fs::read(&path)?;
+        let format = DiskFormat::for_image_size(data.len());
+        Self::with_data(path.as_ref(), data, format)
+    }
+
+    /// Opens the image in `path` as a `format` disk; short images are padded.
+    pub fn open_as(path: impl AsRef<Path>, format: DiskFormat) -> Result<Self, DiskError> {
+        let data = fs::read(&path)?;
+        Self::with_data(path.as_ref(), data, format)
+    }
+
+    fn with_data(path: &Path, mut data: Vec<u8>, format: DiskFormat) -> Result<Self, DiskError> {
+        if data.len() > format.size() {
+            return Err(DiskError::TooLarge(
+                path.to_path_buf(),
+                data.len(),
+                format.size(),
+            ));
+        }
+        let short = data.len() < format.size();
+        data.resize(format.size(), FORMATTED);
+        Ok(Disk {
+            format,
+            data,
+            file: Some(path.to_path_buf()),
+            short,
+        })
+    }
+
+    pub fn format(&self) -> &DiskFormat {
+        &self.format
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Offset of physical `sector`, counted from 1, of `track`.
+    fn offset(&self, track: u16, sector: u16) -> Option<usize> {
+        let format = &self.format;
+        let valid = track < format.tracks && (1..=format.sectors_per_track).contains(&sector);
+        valid.then(|| {
+            (track as usize * format.sectors_per_track as usize + sector as usize - 1) * RECORD
+        })
+    }
+
+    pub fn read_sector(&self, track: u16, sector: u16) -> Option<&[u8]> {
+        let offset = self.offset(track, sector)?;
+        Some(&self.data[offset..offset + RECORD])
+    }
+
+    /// Writes `data` to a sector, returning `false` if there is no such sector.
+    pub fn write_sector(&mut self, track: u16, sector: u16, data: &[u8]) -> io::Result<bool> {
+        let Some(offset) = self.offset(track, sector) else {
+            return Ok(false);
+        };
+        let data = &data[..RECORD.min(data.len())];
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+        if let (Some(path), true) = (&self.file, self.short) {
+            fs::write(path, &self.data)?;
+            self.short = false;
+        } else if let Some(path) = &self.file {
+            let mut file = OpenOptions::new().write(true).open(path)?;
+            file.seek(SeekFrom::Start(offset as u64))?;
+            file.write_all(data)?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DiskError, DiskFormat};
+
+    #[test]
+    fn formats_are_checked() {
+        let skew = DiskFormat::ibm_3740().skew().to_vec();
+        assert_eq!(
+            DiskFormat::new(77, 26, skew.clone(), 3, 243, 64, 2).unwrap(),
+            DiskFormat::ibm_3740()
+        );
+        assert_eq!(
+            DiskFormat::new(255, 128, Vec::new(), 4, 2040, 1024, 0)
+                .unwrap()
+                .fixed(),
+            DiskFormat::z80pack_hd()
+        );
+
+        let invalid = [
+            DiskFormat::new(77, 26, skew[1..].to_vec(), 3, 243, 64, 2),
+            DiskFormat::new(77, 26, Vec::new(), 8, 15, 64, 2),
+            DiskFormat::new(77, 26, Vec::new(), 2, 243, 64, 2),
+            DiskFormat::new(77, 26, Vec::new(), 3, 0, 64, 2),
+            DiskFormat::new(77, 26, Vec::new(), 3, 243, 0, 2),
+            DiskFormat::new(77, 26, Vec::new(), 3, 243, 1024, 2),
+            DiskFormat::new(77, 26, Vec::new(), 3, 244, 64, 2),
+            DiskFormat::new(0, 26, Vec::new(), 3, 243, 64, 2),
+        ];
+        for format in invalid {
+            assert!(
+                matches!(format, Err(DiskError::InvalidFormat(_))),
+                "{:?}",
+                format
+            );
+        }
+    }
+}
@@ -0,0 +1,578 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cpu_state::{MemoryError, Ram};
+
+/// Bytes per CP/M record.
+pub const RECORD: usize = 128;
+/// Records per extent, with one logical extent per directory entry.
+const EXTENT_RECORDS: u32 = 128;
+/// Drives A to P.
+const DRIVES: u8 = 16;
+
+/// Filler of the last record of files whose size is not a multiple of [`RECORD`].
+const CTRL_Z: u8 = 0x1a;
+/// Directory code of failures.
+const FAILED: u16 = 0xff;
+
+// Offsets in a file control block.
+const EX: u16 = 12;
+const S2: u16 = 14;
+const RC: u16 = 15;
+const NEW_NAME: u16 = 16;
+const CR: u16 = 32;
+const R0: u16 = 33;
+
+/// File name and type as stored in FCBs and directory entries, padded with spaces.
+type Name = [u8; 11];
+
+/// BDOS file functions, for files in host directories mounted as drives.
+#[derive(Debug, Clone)]
+pub struct HostFiles {
+    drives: BTreeMap<u8, PathBuf>,
+    current: u8,
+    user: u8,
+    dma: u16,
+    /// Directory entries left for "search next".
+    found: VecDeque<[u8; 32]>,
+}
+
+impl Default for HostFiles {
+    fn default() -> Self {
+        HostFiles {
+            drives: BTreeMap::new(),
+            current: 0,
+            user: 0,
+            dma: 0x80,
+            found: VecDeque::new(),
+        }
+    }
+}
+
+impl HostFiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes the files in `dir` those of `drive`, 0 being A. Files whose names do not fit
+    /// CP/M's 8.3 are not visible; names are matched regardless of case and new files get
+    /// upper case names.
+    pub fn mount(&mut self, drive: u8, dir: impl Into<PathBuf>) {
+        if drive < DRIVES {
+            self.drives.insert(drive, dir.into());
+        }
+    }
+
+    /// Performs file function `function` with parameter `de`, returning the value of HL, or
+    /// `None` if `function` is not a file function.
+    pub fn call(&mut self, function: u8, de: u16, ram: &mut Ram) -> io::Result<Option<u16>> {
+        let e = de as u8;
+        let result = match function {
+            12 => 0x0022,
+            13 => {
+                self.current = 0;
+                self.dma = 0x80;
+                0
+            }
+            14 => {
+                self.current = e % DRIVES;
+                0
+            }
+            15 => self.open(ram, de)?,
+            16 => match self.lookup(ram, de)?.is_empty() {
+                true => FAILED,
+                false => 0,
+            },
+            17 => self.search(ram, de)?,
+            18 => self.next_entry(ram)?,
+            19 => self.delete(ram, de)?,
+            20 => self.read_sequential(ram, de)?,
+            21 => self.write_sequential(ram, de)?,
+            22 => self.make(ram, de)?,
+            23 => self.rename(ram, de)?,
+            24 => self
+                .drives
+                .keys()
+                .fold(0, |vector, drive| vector | 1 << drive),
+            25 => self.current as u16,
+            26 => {
+                self.dma = de;
+                0
+            }
+            // Write protection and attributes are not kept.
+            28 | 29 => 0,
+            30 => match self.lookup(ram, de)?.is_empty() {
+                true => FAILED,
+                false => 0,
+            },
+            32 if e == 0xff => self.user as u16,
+            32 => {
+                self.user = e & 0x0f;
+                0
+            }
+            33 => self.read_random(ram, de)?,
+            34 | 40 => self.write_random(ram, de)?,
+            35 => {
+                let records = match self.lookup(ram, de)?.first() {
+                    Some((_, path)) => records(path)?,
+                    None => return Ok(Some(FAILED)),
+                };
+                set_random(ram, de, records)?;
+                0
+            }
+            36 => {
+                let record = position(ram, de)?;
+                set_random(ram, de, record)?;
+                0
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(result))
+    }
+
+    /// Directory of the drive `fcb` refers to.
+    fn dir(&self, ram: &Ram, fcb: u16) -> io::Result<Option<&Path>> {
+        let drive = match peek(ram, fcb)? {
+            0 | b'?' => self.current,
+            drive => drive - 1,
+        };
+        Ok(self.drives.get(&drive).map(PathBuf::as_path))
+    }
+
+    /// Files matching the name in `fcb`, `?` matching any character, sorted by name.
+    fn lookup(&self, ram: &Ram, fcb: u16) -> io::Result<Vec<(Name, PathBuf)>> {
+        let pattern = name_at(ram, fcb.wrapping_add(1))?;
+        let Some(dir) = self.dir(ram, fcb)? else {
+            return Ok(Vec::new());
+        };
+        let entries = match fs::read_dir(dir) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            entries => entries?,
+        };
+        let mut found = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let Some(name) = entry.file_name().to_str().and_then(cpm_name) else {
+                continue;
+            };
+            if pattern
+                .iter()
+                .zip(&name)
+                .all(|(p, c)| *p == b'?' || p.eq_ignore_ascii_case(c))
+            {
+                found.push((name, entry.path()));
+            }
+        }
+        found.sort();
+        Ok(found)
+    }
+
+    fn open(&mut self, ram: &mut Ram, fcb: u16) -> io::Result<u16> {
+        let Some((name, path)) = self.lookup(ram, fcb)?.into_iter().next() else {
+            return Ok(FAILED);
+        };
+        poke_all(ram, fcb.wrapping_add(1), &name)?;
+        let extent = extent(ram, fcb)?;
+        poke(
+            ram,
+            fcb.wrapping_add(RC),
+            extent_records(records(&path)?, extent),
+        )?;
+        Ok(0)
+    }
+
+    fn make(&mut self, ram: &mut Ram, fcb: u16) -> io::Result<u16> {
+        let name = name_at(ram, fcb.wrapping_add(1))?;
+        let Some(dir) = self.dir(ram, fcb)? else {
+            return Ok(FAILED);
+        };
+        if !valid_name(&name) || File::create(dir.join(host_name(&name))).is_err() {
+            return Ok(FAILED);
+        }
+        poke(ram, fcb.wrapping_add(RC), 0)?;
+        Ok(0)
+    }
+
+    fn delete(&mut self, ram: &mut Ram, fcb: u16) -> io::Result<u16> {
+        let found = self.lookup(ram, fcb)?;
+        let mut deleted = !found.is_empty();
+        for (_, path) in &found {
+            deleted &= fs::remove_file(path).is_ok();
+        }
+        Ok(if deleted { 0 } else { FAILED })
+    }
+
+    fn rename(&mut self, ram: &mut Ram, fcb: u16) -> io::Result<u16> {
+        let new = name_at(ram, fcb.wrapping_add(NEW_NAME + 1))?;
+        let found = self.lookup(ram, fcb)?;
+        let (Some((_, path)), Some(dir)) = (found.first(), self.dir(ram, fcb)?) else {
+            return Ok(FAILED);
+        };
+        if !valid_name(&new) || fs::rename(path, dir.join(host_name(&new))).is_err() {
+            return Ok(FAILED);
+        }
+        Ok(0)
+    }
+
+    /// Search first: queues the directory entries of the matching files and returns the
+    /// first. Each file is described by the entry of its last extent.
+    fn search(&mut self, ram: &mut Ram, fcb: u16) -> io::Result<u16> {
+        self.found.clear();
+        for (name, path) in self.lookup(ram, fcb)? {
+            let records = records(&path)?;
+            let extent = records.saturating_sub(1) / EXTENT_RECORDS;
+            let mut entry = [0; 32];
+            entry[0] = self.user;
+            entry[1..12].copy_from_slice(&name);
+            entry[EX as usize] = (extent % 32) as u8;
+            entry[S2 as usize] = (extent / 32) as u8;
+            entry[RC as usize] = extent_records(records, extent);
+            self.found.push_back(entry);
+        }
+        self.next_entry(ram)
+    }
+
+    /// Search next: copies the next entry found to the start of the DMA buffer.
+    fn next_entry(&mut self, ram: &mut Ram) -> io::Result<u16> {
+        let Some(entry) = self.found.pop_front() else {
+            return Ok(FAILED);
+        };
+        poke_all(ram, self.dma, &entry)?;
+        Ok(0)
+    }
+
+    fn read_sequential(&mut self, ram: &mut Ram, fcb: u16) -> io::Result<u16> {
+        let record = position(ram, fcb)?;
+        let result = self.read(ram, fcb, record)?;
+        if result == 0 {
+            self.seek(ram, fcb, record + 1)?;
+        }
+        Ok(result)
+    }
+
+    fn write_sequential(&mut self, ram: &mut Ram, fcb: u16) -> io::Result<u16> {
+        let record = position(ram, fcb)?;
+        let result = self.write(ram, fcb, record)?;
+        if result == 0 {
+            self.seek(ram, fcb, record + 1)?;
+        }
+        Ok(result)
+    }
+
+    /// Random access leaves the sequential position on the record accessed.
+    fn read_random(&mut self, ram: &mut Ram, fcb: u16) -> io::Result<u16> {
+        let Some(record) = random(ram, fcb)? else {
+            return Ok(6);
+        };
+        self.seek(ram, fcb, record)?;
+        self.read(ram, fcb, record)
+    }
+
+    fn write_random(&mut self, ram: &mut Ram, fcb: u16) -> io::Result<u16> {
+        let Some(record) = random(ram, fcb)? else {
+            return Ok(6);
+        };
+        self.seek(ram, fcb, record)?;
+        let result = self.write(ram, fcb, record)?;
+        self.seek(ram, fcb, record)?;
+        Ok(result)
+    }
+
+    /// Reads `record` into the DMA buffer; 1 past the end of the file.
+    fn read(&mut self, ram: &mut Ram, fcb: u16, record: u32) -> io::Result<u16> {
+        let Some((_, path)) = self.lookup(ram, fcb)?.into_iter().next() else {
+            return Ok(FAILED);
+        };
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(record as u64 * RECORD as u64))?;
+        let mut buffer = Vec::with_capacity(RECORD);
+        file.take(RECORD as u64).read_to_end(&mut buffer)?;
+        if buffer.is_empty() {
+            return Ok(1);
+        }
+        buffer.resize(RECORD, CTRL_Z);
+        poke_all(ram, self.dma, &buffer)?;
+        Ok(0)
+    }
+
+    /// Writes the DMA buffer to `record`.
+    fn write(&mut self, ram: &mut Ram, fcb: u16, record: u32) -> io::Result<u16> {
+        let Some((_, path)) = self.lookup(ram, fcb)?.into_iter().next() else {
+            return Ok(FAILED);
+        };
+        let buffer = (0..RECORD as u16)
+            .map(|offset| peek(ram, self.dma.wrapping_add(offset)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start(record as u64 * RECORD as u64))?;
+        file.write_all(&buffer)?;
+        Ok(0)
+    }
+
+    /// Points the sequential position of `fcb` at `record`, updating the record count of
+    /// the extent it falls in.
+    fn seek(&mut self, ram: &mut Ram, fcb: u16, record: u32) -> io::Result<()> {
+        let extent = record / EXTENT_RECORDS;
+        poke(ram, fcb.wrapping_add(EX), (extent % 32) as u8)?;
+        poke(ram, fcb.wrapping_add(S2), (extent / 32) as u8)?;
+        poke(ram, fcb.wrapping_add(CR), (record % EXTENT_RECORDS) as u8)?;
+        let total = match self.lookup(ram, fcb)?.first() {
+            Some((_, path)) => records(path)?,
+            None => 0,
+        };
+        poke(ram, fcb.wrapping_add(RC), extent_records(total, extent))
+    }
+}
+
+fn to_io(error: MemoryError) -> io::Error {
+    io::Error::other(error)
+}
+
+fn peek(ram: &Ram, addr: u16) -> io::Result<u8> {
+    ram.peek(addr).map_err(to_io)
+}
+
+fn poke(ram: &mut Ram, addr: u16, value: u8) -> io::Result<()> {
+    ram.poke(addr, value).map_err(to_io)
+}
+
+fn poke_all(ram: &mut Ram, addr: u16, bytes: &[u8]) -> io::Result<()> {
+    for (offset, byte) in bytes.iter().enumerate() {
+        poke(ram, addr.wrapping_add(offset as u16), *byte)?;
+    }
+    Ok(())
+}
+
+/// Name at `addr`, attribute bits cleared.
+fn name_at(ram: &Ram, addr: u16) -> io::Result<Name> {
+    let mut name = [b' '; 11];
+    for (i, c) in name.iter_mut().enumerate() {
+        *c = peek(ram, addr.wrapping_add(i as u16))? & 0x7f;
+    }
+    Ok(name)
+}
+
+/// Extent selected in `fcb`, counting S2 as its high bits.
+fn extent(ram: &Ram, fcb: u16) -> io::Result<u32> {
+    Ok(peek(ram, fcb.wrapping_add(S2))? as u32 * 32
+        + (peek(ram, fcb.wrapping_add(EX))? & 0x1f) as u32)
+}
+
+/// Sequential position of `fcb`, in records.
+fn position(ram: &Ram, fcb: u16) -> io::Result<u32> {
+    Ok(extent(ram, fcb)? * EXTENT_RECORDS + peek(ram, fcb.wrapping_add(CR))? as u32)
+}
+
+/// Random record number of `fcb`, `None` past the 8 MB a file can hold.
+fn random(ram: &Ram, fcb: u16) -> io::Result<Option<u32>> {
+    let record = u16::from_le_bytes([
+        peek(ram, fcb.wrapping_add(R0))?,
+        peek(ram, fcb.wrapping_add(R0 + 1))?,
+    ]);
+    Ok((peek(ram, fcb.wrapping_add(R0 + 2))? == 0).then_some(record as u32))
+}
+
+fn set_random(ram: &mut Ram, fcb: u16, record: u32) -> io::Result<()> {
+    poke_all(ram, fcb.wrapping_add(R0), &record.to_le_bytes()[..3])
+}
+
+/// Records in `path`, counting a partial last one.
+fn records(path: &Path) -> io::Result<u32> {
+    Ok(fs::metadata(path)?.len().div_ceil(RECORD as u64) as u32)
+}
+
+/// Records of a `total` records long file that fall in `extent`.
+fn extent_records(total: u32, extent: u32) -> u8 {
+    total
+        .saturating_sub(extent * EXTENT_RECORDS)
+        .min(EXTENT_RECORDS) as u8
+}
+
+/// CP/M name of the host file `name`, if it has one.
+fn cpm_name(name: &str) -> Option<Name> {
+    let (base, kind) = name.rsplit_once('.').unwrap_or((name, ""));
+    let valid = |part: &str, max: usize| {
+        part.len() <= max
+            && part
+                .bytes()
+                .all(|c| c.is_ascii_graphic() && !b"<>.,;:=?*[]/\\".contains(&c))
+    };
+    if base.is_empty() || !valid(base, 8) || !valid(kind, 3) {
+        return None;
+    }
+    let mut cpm = [b' '; 11];
+    cpm[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    cpm[8..8 + kind.len()].copy_from_slice(kind.to_ascii_uppercase().as_bytes());
+    Some(cpm)
+}
+
+/// Whether the CP/M `name` is one [`cpm_name`] gives a host file, so that it can be created
+/// in the drive's directory and nowhere else.
+fn valid_name(name: &Name) -> bool {
+    cpm_name(&host_name(name)).is_some_and(|cpm| cpm[..] == name.to_ascii_uppercase())
+}
+
+/// Host file name of the CP/M `name`.
+fn host_name(name: &Name) -> String {
+    let base = String::from_utf8_lossy(&name[..8]).trim_end().to_string();
+    let kind = String::from_utf8_lossy(&name[8..]).trim_end().to_string();
+    if kind.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, kind)
+    }
+}
+
+/// First 12 bytes of the FCB the CCP builds for a command line argument such as `B:*.ASM`:
+/// drive (0 for the current one), then name and type, `*` expanded to `?`s.
+pub fn parse_fcb(argument: &str) -> [u8; 12] {
+    let mut fcb = [b' '; 12];
+    fcb[0] = 0;
+    let argument = argument.to_ascii_uppercase();
+    let argument = match argument.as_bytes() {
+        [drive @ b'A'..=b'P', b':', ..] => {
+            fcb[0] = drive - b'A' + 1;
+            &argument[2..]
+        }
+        _ => &argument[..],
+    };
+    let (base, kind) = argument.split_once('.').unwrap_or((argument, ""));
+    for (part, range) in [(base, 1..9), (kind, 9..12)] {
+        let field = &mut fcb[range];
+        for (i, c) in part.bytes().take(field.len()).enumerate() {
+            if c == b'*' {
+                field[i..].fill(b'?');
+                break;
+            }
+            field[i] = c;
+        }
+    }
+    fcb
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        cpm::{BufferConsole, Cpm},
+        cpu_state::Ram,
+        i8080,
+    };
+
+    use super::HostFiles;
+
+    #[test]
+    fn file_functions() {
+        let dir = std::env::temp_dir().join(format!("cpm-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hello.txt"), b"Hello, CP/M").unwrap();
+        fs::write(dir.join("not a cpm name.txt"), b"").unwrap();
+
+        // Copies the file named on the command line to COPY.TXT, renames that to NEW.TXT,
+        // then looks for *.TXT files and reads record 0 of NEW.TXT at random.
+        let program = i8080! {
+            results EQU 2000H
+            entry EQU 2010H
+            buffer EQU 2080H
+            ORG 100H
+            MVI C,26; LXI D,buffer; CALL 5
+            MVI C,15; LXI D,5CH; CALL 5
+            MVI C,22; LXI D,copy; CALL 5
+            MVI C,20; LXI D,5CH; CALL 5; STA results
+            MVI C,21; LXI D,copy; CALL 5
+            MVI C,20; LXI D,5CH; CALL 5; STA results+1
+            MVI C,16; LXI D,copy; CALL 5
+            MVI C,23; LXI D,copy; CALL 5
+            MVI C,26; LXI D,entry; CALL 5
+            MVI C,17; LXI D,any; CALL 5; STA results+2
+            MVI C,18; LXI D,any; CALL 5; STA results+3
+            MVI C,18; LXI D,any; CALL 5; STA results+4
+            MVI C,26; LXI D,buffer; CALL 5
+            MVI C,35; LXI D,renamed; CALL 5
+            LXI H,0; SHLD renamed+33
+            MVI C,33; LXI D,renamed; CALL 5; STA results+5
+            RET
+            copy: DB 0,"COPY    TXT",0,0,0,0
+            renamed: DB 0,"NEW     TXT"; DS 24
+            any: DB 0,"????????TXT"; DS 24
+        };
+        let results = 0x2000;
+        let mut cpm = Cpm::new(&program, BufferConsole::default()).unwrap();
+        cpm.files_mut().mount(0, &dir);
+        cpm.set_command_line("a:hello.txt").unwrap();
+        cpm.run().unwrap();
+
+        let ram = cpm.system().ram();
+        let byte = |addr: u16| ram.peek(addr).unwrap();
+        assert_eq!(
+            (0..6).map(|i| byte(results + i)).collect::<Vec<_>>(),
+            [0, 1, 0, 0, 0xff, 0]
+        );
+        let mut copied = b"Hello, CP/M".to_vec();
+        copied.resize(128, 0x1a);
+        assert_eq!(fs::read(dir.join("NEW.TXT")).unwrap(), copied);
+        assert!(!dir.join("COPY.TXT").exists());
+        // Search next found NEW.TXT last, after HELLO.TXT.
+        assert_eq!(
+            (0x10..0x20).map(|i| byte(results + i)).collect::<Vec<_>>(),
+            b"\0NEW     TXT\0\0\0\x01"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fcb_wrapping_around_memory() {
+        let dir = std::env::temp_dir().join(format!("cpm-wrap-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut files = HostFiles::new();
+        files.mount(0, &dir);
+        // The random record field of an FCB at fff0 is at 0011.
+        let mut ram = Ram::new(0x10000, false);
+        let fcb = 0xfff0;
+        for (offset, byte) in b"\0WRAP    TXT".iter().enumerate() {
+            ram.poke(fcb + offset as u16, *byte).unwrap();
+        }
+        assert_eq!(files.call(22, fcb, &mut ram).unwrap(), Some(0));
+        assert_eq!(files.call(21, fcb, &mut ram).unwrap(), Some(0));
+        assert_eq!(files.call(35, fcb, &mut ram).unwrap(), Some(0));
+        assert_eq!(ram.peek(0x11).unwrap(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_names_and_host_failures() {
+        let dir = std::env::temp_dir().join(format!("cpm-fail-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("drive")).unwrap();
+        fs::create_dir_all(dir.join("drive/TAKEN.TXT")).unwrap();
+        fs::write(dir.join("drive/OLD.TXT"), b"").unwrap();
+        let mut files = HostFiles::new();
+        files.mount(0, dir.join("drive"));
+        files.mount(1, dir.join("missing"));
+        let mut ram = Ram::new(0x10000, false);
+        let mut call = |function: u8, fcb: &[u8; 12], new: &[u8; 11]| {
+            ram.poke(0x5c, fcb[0]).unwrap();
+            for (offset, byte) in fcb[1..].iter().chain(new).enumerate() {
+                ram.poke(0x5d + offset as u16, *byte).unwrap();
+            }
+            files.call(function, 0x5c, &mut ram).unwrap()
+        };
+
+        assert_eq!(call(22, b"\0../ESC     ", b"           "), Some(0xff));
+        assert_eq!(call(22, b"\0A\x01         ", b"           "), Some(0xff));
+        assert_eq!(call(23, b"\0OLD     TXT", b"../ESC     "), Some(0xff));
+        assert_eq!(call(23, b"\0OLD     TXT", b"A.B     TXT"), Some(0xff));
+        assert!(!dir.join("ESC").exists());
+        // Creating in a directory that does not exist and renaming onto a directory fail on
+        // the host.
+        assert_eq!(call(22, b"\x02NEW     TXT", b"           "), Some(0xff));
+        assert_eq!(call(23, b"\0OLD     TXT", b"TAKEN   TXT"), Some(0xff));
+        assert!(dir.join("drive/OLD.TXT").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
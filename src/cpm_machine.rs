@@ -0,0 +1,366 @@
+use crate::{
+    cpm::{Console, CpmError, Exit},
+    cpm_disk::Disk,
+    cpm_files::RECORD,
+    cpu_state::{Ram, System},
+    in_out::PortLatch,
+    op_code::{Instruction, Register, RegisterPair},
+};
+
+/// Address of the CCP in a 64K system, used when the system tracks do not tell.
+pub const DEFAULT_CCP: u16 = 0xe400;
+/// Bytes of CCP and BDOS, read from the system tracks after the boot sector.
+const SYSTEM_SIZE: u16 = 0x1600;
+const BDOS_OFFSET: u16 = 0x800;
+/// Offset in the BDOS of its entry point, which jumps 0x11 bytes into the BDOS.
+const BDOS_ENTRY: u16 = 6;
+const BIOS_ENTRIES: u16 = 17;
+/// Offset in the BIOS of the RET each jump table entry leads to, where calls are trapped.
+const TRAPS: u16 = BIOS_ENTRIES * 3;
+/// Offset in the BIOS of the directory buffer shared by all drives, followed by the tables
+/// of each drive.
+const DIRECTORY_BUFFER: u16 = 0x50;
+const DRIVES: usize = 16;
+/// Stack used by the boot code, below the default DMA buffer.
+const BOOT_STACK: u16 = 0x80;
+
+/// A CP/M 2.2 computer: the CCP and BDOS are read from the system tracks of the disk in
+/// drive A, and run on top of a BIOS emulated by the host, whose jump table is the only
+/// part in memory.
+pub struct CpmMachine<C> {
+    system: System,
+    console: C,
+    disks: Vec<Option<Disk>>,
+    /// Address of the disk parameter header of each drive, 0 when empty.
+    headers: Vec<u16>,
+    ccp: u16,
+    drive: u8,
+    track: u16,
+    sector: u16,
+    dma: u16,
+    io: PortLatch,
+}
+
+impl<C: Console> CpmMachine<C> {
+    pub fn new(console: C) -> Self {
+        CpmMachine {
+            system: System::new(Ram::new(0x10000, false), 0),
+            console,
+            disks: vec![None; DRIVES],
+            headers: vec![0; DRIVES],
+            ccp: DEFAULT_CCP,
+            drive: 0,
+            track: 0,
+            sector: 1,
+            dma: 0x80,
+            io: PortLatch::default(),
+        }
+    }
+
+    /// Inserts `disk` in `drive`, 0 being A. Disks are only seen by the system if inserted
+    /// before [`CpmMachine::boot`].
+    pub fn insert(&mut self, drive: u8, disk: Disk) {
+        if let Some(slot) = self.disks.get_mut(drive as usize) {
+            *slot = Some(disk);
+        }
+    }
+
+    pub fn disk(&self, drive: u8) -> Option<&Disk> {
+        self.disks.get(drive as usize)?.as_ref()
+    }
+
+    pub fn system(&self) -> &System {
+        &self.system
+    }
+
+    pub fn system_mut(&mut self) -> &mut System {
+        &mut self.system
+    }
+
+    pub fn console(&self) -> &C {
+        &self.console
+    }
+
+    pub fn console_mut(&mut self) -> &mut C {
+        &mut self.console
+    }
+
+    fn bios(&self) -> u16 {
+        self.ccp + SYSTEM_SIZE
+    }
+
+    /// Cold boot: lays out the BIOS for the disks inserted, loads the system from drive A
+    /// and starts the CCP on it.
+    pub fn boot(&mut self) -> Result<(), CpmError> {
+        let image = self.system_image()?;
+        // The BDOS entry jumps to its own address + 0x11, which gives away where the
+        // system was built to run.
+        let entry = BDOS_OFFSET + BDOS_ENTRY;
+        if let [0xc3, l, h] = image[entry as usize..entry as usize + 3] {
+            let ccp = u16::from_le_bytes([l, h]).wrapping_sub(BDOS_OFFSET + 0x11);
+            if ccp > 0x100 && ccp as u32 + (SYSTEM_SIZE as u32) < 0x10000 {
+                self.ccp = ccp;
+            }
+        }
+        self.lay_out_bios()?;
+        let ram = self.system.ram_mut();
+        ram.poke(3, 0)?;
+        ram.poke(4, 0)?;
+        self.start_ccp(self.ccp)
+    }
+
+    /// CCP and BDOS as found on the disk in drive A.
+    fn system_image(&self) -> Result<Vec<u8>, CpmError> {
+        let disk = self.disk(0).ok_or(CpmError::NoSystemDisk)?;
+        let format = disk.format();
+        let reserved =
+            format.reserved_tracks() as usize * format.sectors_per_track() as usize * RECORD;
+        if reserved < RECORD + SYSTEM_SIZE as usize {
+            return Err(CpmError::NoSystemDisk);
+        }
+        Ok(disk.data()[RECORD..RECORD + SYSTEM_SIZE as usize].to_vec())
+    }
+
+    /// Writes the jump table, the traps it leads to, and the disk parameter headers and
+    /// blocks, translation tables and vectors of every drive with a disk.
+    fn lay_out_bios(&mut self) -> Result<(), CpmError> {
+        let bios = self.bios();
+        let mut tables = Vec::new();
+        let mut next = bios as u32 + DIRECTORY_BUFFER as u32 + RECORD as u32;
+        for (drive, disk) in self.disks.iter().enumerate() {
+            let Some(disk) = disk else {
+                self.headers[drive] = 0;
+                continue;
+            };
+            let format = disk.format();
+            let header = next;
+            let dpb = header + 16;
+            let skew = dpb + 15;
+            let check = skew + format.skew().len() as u32;
+            let allocation = check + format.check_vector_size() as u32;
+            next = allocation + format.allocation_vector_size() as u32;
+            if next > 0x10000 {
+                return Err(CpmError::BiosTooLarge);
+            }
+            let translation = if format.skew().is_empty() { 0 } else { skew };
+            let mut bytes = Vec::new();
+            for word in [
+                translation,
+                0,
+                0,
+                0,
+                bios as u32 + DIRECTORY_BUFFER as u32,
+                dpb,
+                check,
+                allocation,
+            ] {
+                bytes.extend((word as u16).to_le_bytes());
+            }
+            bytes.extend(format.dpb());
+            bytes.extend(format.skew());
+            self.headers[drive] = header as u16;
+            tables.push((header as u16, bytes));
+        }
+        let ram = self.system.ram_mut();
+        for entry in 0..BIOS_ENTRIES {
+            let [l, h] = (bios + TRAPS + entry).to_le_bytes();
+            for (offset, byte) in [0xc3, l, h].into_iter().enumerate() {
+                ram.poke(bios + entry * 3 + offset as u16, byte)?;
+            }
+            ram.poke(bios + TRAPS + entry, 0xc9)?;
+        }
+        for (addr, bytes) in tables {
+            for (offset, byte) in bytes.iter().enumerate() {
+                ram.poke(addr + offset as u16, *byte)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the CCP and BDOS, points page zero at the BIOS and BDOS, and jumps to `entry`
+    /// with the current drive in C.
+    fn start_ccp(&mut self, entry: u16) -> Result<(), CpmError> {
+        let image = self.system_image()?;
+        let (bios, bdos) = (self.bios(), self.ccp + BDOS_OFFSET + BDOS_ENTRY);
+        let ram = self.system.ram_mut();
+        for (offset, byte) in image.iter().enumerate() {
+            ram.poke(self.ccp + offset as u16, *byte)?;
+        }
+        for (addr, target) in [(0, bios + 3), (5, bdos)] {
+            let [l, h] = target.to_le_bytes();
+            for (offset, byte) in [0xc3, l, h].into_iter().enumerate() {
+                ram.poke(addr + offset as u16, byte)?;
+            }
+        }
+        self.dma = 0x80;
+        let drive = self.system.ram().peek(4)?;
+        *self.system.get_mut(Register::C)? = drive;
+        let [l, h] = BOOT_STACK.to_le_bytes();
+        self.system
+            .execute(Instruction::Lxi(RegisterPair::SP, l, h), &self.io)?;
+        self.system.execute(Instruction::Jmp(entry), &self.io)?;
+        Ok(())
+    }
+
+    /// Executes one instruction, or the BIOS function called if a program entered one.
+    /// Returns why the machine stopped, if it did.
+    pub fn step(&mut self) -> Result<Option<Exit>, CpmError> {
+        let pc = self.system.cpu().pc();
+        let traps = self.bios() + TRAPS;
+        if (traps..traps + BIOS_ENTRIES).contains(&pc) {
+            if let Some(exit) = self.bios_call(pc - traps)? {
+                return Ok(Some(exit));
+            }
+            // Boots jump to the CCP rather than return.
+            if self.system.cpu().pc() != pc {
+                return Ok(None);
+            }
+        }
+        let instruction = self.system.next_instruction()?;
+        match self.system.execute(instruction, &self.io)? {
+            Some(_) => Ok(None),
+            None => Ok(Some(Exit::Halt)),
+        }
+    }
+
+    /// Runs until the machine stops.
+    pub fn run(&mut self) -> Result<Exit, CpmError> {
+        loop {
+            if let Some(exit) = self.step()? {
+                return Ok(exit);
+            }
+        }
+    }
+
+    /// Performs BIOS function `function`, numbered in jump table order.
+    fn bios_call(&mut self, function: u16) -> Result<Option<Exit>, CpmError> {
+        let bc = self.system.cpu().get_rp(RegisterPair::B);
+        let c = bc as u8;
+        match function {
+            0 => self.boot()?,
+            // Warm boot reloads the CCP, entering it where its command buffer is cleared.
+            1 => self.start_ccp(self.ccp + 3)?,
+            2 => {
+                let status = if self.console.ready() { 0xff } else { 0 };
+                *self.system.get_mut(Register::A)? = status;
+            }
+            3 => match self.console.read() {
+                Some(byte) => *self.system.get_mut(Register::A)? = byte,
+                None => return Ok(Some(Exit::EndOfInput)),
+            },
+            4 => self.console.write(c),
+            // Nothing is attached to the list, punch and reader devices.
+            5 | 6 => {}
+            7 => *self.system.get_mut(Register::A)? = 0x1a,
+            8 => self.track = 0,
+            9 => {
+                let header = self.headers.get(c as usize).copied().unwrap_or(0);
+                if header != 0 {
+                    self.drive = c;
+                }
+                self.set_hl(header)?;
+            }
+            10 => self.track = bc,
+            11 => self.sector = bc,
+            12 => self.dma = bc,
+            13 => {
+                let sector = self
+                    .disk(self.drive)
+                    .and_then(|disk| disk.read_sector(self.track, self.sector))
+                    .map(<[u8]>::to_vec);
+                let status = match sector {
+                    Some(sector) => {
+                        let ram = self.system.ram_mut();
+                        for (offset, byte) in sector.iter().enumerate() {
+                            ram.poke(self.dma.wrapping_add(offset as u16), *byte)?;
+                        }
+                        0
+                    }
+                    None => 1,
+                };
+                *self.system.get_mut(Register::A)? = status;
+            }
+            14 => {
+                let ram = self.system.ram();
+                let sector = (0..RECORD as u16)
+                    .map(|offset| ram.peek(self.dma.wrapping_add(offset)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let written = match self.disks.get_mut(self.drive as usize) {
+                    Some(Some(disk)) => disk.write_sector(self.track, self.sector, &sector)?,
+                    _ => false,
+                };
+                *self.system.get_mut(Register::A)? = if written { 0 } else { 1 };
+            }
+            15 => *self.system.get_mut(Register::A)? = 0xff,
+            _ => {
+                let table = self.system.cpu().get_rp(RegisterPair::D);
+                let sector = match table {
+                    0 => bc + 1,
+                    table => self.system.ram().peek(table.wrapping_add(bc))? as u16,
+                };
+                self.set_hl(sector)?;
+            }
+        }
+        Ok(None)
+    }
+
+    fn set_hl(&mut self, value: u16) -> Result<(), CpmError> {
+        let [l, h] = value.to_le_bytes();
+        *self.system.get_mut(Register::L)? = l;
+        *self.system.get_mut(Register::H)? = h;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cpm::{BufferConsole, Exit},
+        cpm_disk::{Disk, DiskFormat},
+        i8080,
+    };
+
+    use super::CpmMachine;
+
+    #[test]
+    fn boots_and_uses_the_bios() {
+        assert_eq!(
+            DiskFormat::ibm_3740().dpb(),
+            [26, 0, 3, 7, 0, 242, 0, 63, 0, 0xc0, 0, 16, 0, 2, 0]
+        );
+
+        // A stand-in for the CCP, reading logical sector 1 of track 2 and copying it to
+        // track 3 through the BIOS.
+        let system = i8080! {
+            BIOS EQU 0FA00H
+            ORG 0E400H
+            MVI C,'O'; CALL BIOS+12
+            MVI C,0; CALL BIOS+27
+            MOV E,M; INX H; MOV D,M
+            LXI B,1; CALL BIOS+48
+            MOV B,H; MOV C,L; CALL BIOS+33
+            LXI B,2; CALL BIOS+30
+            LXI B,1000H; CALL BIOS+36
+            CALL BIOS+39
+            LDA 1000H; MOV C,A; CALL BIOS+12
+            LXI B,3; CALL BIOS+30
+            CALL BIOS+42
+            CALL BIOS+9
+            HLT
+        };
+        let mut disk = Disk::blank(DiskFormat::ibm_3740());
+        disk.data_mut()[128..128 + system.len()].copy_from_slice(&system);
+        // Logical sector 1 is physical sector 7.
+        let sector = (2 * 26 + 6) * 128;
+        disk.data_mut()[sector] = b'K';
+        let mut machine = CpmMachine::new(BufferConsole::default());
+        machine.insert(0, disk);
+        machine.boot().unwrap();
+        assert_eq!(machine.run().unwrap(), Exit::EndOfInput);
+        assert_eq!(machine.console().text(), "OK");
+        let data = machine.disk(0).unwrap().data();
+        assert_eq!(data[sector + 26 * 128], b'K');
+        assert_eq!(machine.system().ram().peek(6).unwrap(), 0x06);
+        assert_eq!(machine.system().ram().peek(7).unwrap(), 0xec);
+    }
+}
@@ -1,7 +1,14 @@
 use crate::{
+    call_stack::{CallStack, Mismatch},
+    coverage::CoverageMap,
+    disassembler::{Analysis, ListingOptions},
     in_out::InOut,
     op_code::{Instruction, OpCodeError, Register, RegisterPair},
+    profiler::Profiler,
+    symbols::SymbolTable,
+    syntax::Syntax,
 };
+use std::{cell::RefCell, fmt};
 use thiserror::Error;
 
 #[derive(Error, Debug, PartialEq, Eq)]
@@ -30,6 +37,7 @@ pub struct Cpu {
     inte: bool,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Flag {
     S = 7,
     Z = 6,
@@ -55,6 +63,12 @@ fn to_u8(x: u16) -> (u8, u8) {
     (h, l)
 }
 
+/// Whether the stack pointer `sp` is above `mark`, for stacks that may wrap around 0: it
+/// is when less than half the address space higher.
+pub(crate) fn stack_above(sp: u16, mark: u16) -> bool {
+    (sp.wrapping_sub(mark) as i16) > 0
+}
+
 impl Cpu {
     pub fn new(pc: u16) -> Self {
         Cpu {
@@ -73,6 +87,10 @@ impl Cpu {
         self.pc
     }
 
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
     pub fn psw(&self) -> u16 {
         to_u16(self.flags(), self.get(Register::A))
     }
@@ -81,6 +99,14 @@ impl Cpu {
         self.sp
     }
 
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp = sp;
+    }
+
+    pub fn set_inte(&mut self, inte: bool) {
+        self.inte = inte;
+    }
+
     pub fn a(&self) -> u8 {
         self.get(Register::A)
     }
@@ -93,6 +119,10 @@ impl Cpu {
         self.get_mut(Register::F)
     }
 
+    pub fn flag(&self, bit: Flag) -> bool {
+        (self.flags() & (1 << bit as usize)) != 0
+    }
+
     fn z(&self) -> bool {
         (self.flags() & (1 << Flag::Z as usize)) != 0
     }
@@ -116,7 +146,7 @@ impl Cpu {
     fn update_flags(&mut self, byte: u8) {
         self.toggle(Flag::S, (byte as i8) < 0);
         self.toggle(Flag::Z, byte == 0);
-        self.toggle(Flag::P, byte.count_ones() % 2 == 0);
+        self.toggle(Flag::P, byte.count_ones().is_multiple_of(2));
     }
 
     fn update_flags_with_carry(&mut self, byte: u8, cy: bool) {
@@ -174,6 +204,47 @@ impl Cpu {
             r => &mut self.registers[r as usize],
         }
     }
+
+    /// Sets `register`, F included; M is not a register of the CPU.
+    pub fn set_register(&mut self, register: Register, value: u8) {
+        *self.get_mut(register) = value;
+    }
+}
+
+/// A data access performed by an instruction, as recorded by [`Ram::log_accesses`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccess {
+    Read(u16),
+    Write(u16),
+}
+
+impl MemoryAccess {
+    pub fn addr(self) -> u16 {
+        match self {
+            MemoryAccess::Read(addr) | MemoryAccess::Write(addr) => addr,
+        }
+    }
+}
+
+impl fmt::Display for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Registers:")?;
+        writeln!(f, "\tA: {:#04x}", self.get(Register::A))?;
+        writeln!(f, "\tF: {:#04x}", self.flags())?;
+        writeln!(f, "\tB: {:#04x}", self.get(Register::B))?;
+        writeln!(f, "\tC: {:#04x}", self.get(Register::C))?;
+        writeln!(f, "\tD: {:#04x}", self.get(Register::D))?;
+        writeln!(f, "\tE: {:#04x}", self.get(Register::E))?;
+        writeln!(f, "\tH: {:#04x}", self.get(Register::H))?;
+        writeln!(f, "\tL: {:#04x}", self.get(Register::L))?;
+        writeln!(f, "Register pairs:")?;
+        writeln!(f, "\tA: {:#06x}", self.psw())?;
+        writeln!(f, "\tB: {:#06x}", self.get_rp(RegisterPair::B))?;
+        writeln!(f, "\tD: {:#06x}", self.get_rp(RegisterPair::D))?;
+        writeln!(f, "\tH: {:#06x}", self.get_rp(RegisterPair::H))?;
+        writeln!(f, "SP: {:#06x}", self.sp())?;
+        writeln!(f, "Inte: {}", self.inte)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -181,6 +252,7 @@ pub struct Ram {
     ram: Vec<u8>,
     rom_ranges: Vec<(usize, usize)>,
     allow_rom_write: bool,
+    accesses: Option<RefCell<Vec<MemoryAccess>>>,
 }
 
 impl Ram {
@@ -189,6 +261,52 @@ impl Ram {
             ram: vec![0; ram_size],
             rom_ranges: Vec::new(),
             allow_rom_write,
+            accesses: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ram.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ram.is_empty()
+    }
+
+    /// Starts (or stops) recording every data read and write performed by the CPU.
+    /// Instruction fetches are not recorded.
+    pub fn log_accesses(&mut self, enabled: bool) {
+        self.accesses = enabled.then(|| RefCell::new(Vec::new()));
+    }
+
+    /// Returns the accesses recorded since the last call, oldest first.
+    pub fn take_accesses(&self) -> Vec<MemoryAccess> {
+        self.accesses
+            .as_ref()
+            .map(|accesses| accesses.take())
+            .unwrap_or_default()
+    }
+
+    /// Reads a byte without going through the access log.
+    pub fn peek(&self, addr: u16) -> Result<u8> {
+        self.ram
+            .get(addr as usize)
+            .ok_or(MemoryError::OutOfBoundRead(addr as usize))
+            .copied()
+    }
+
+    /// Writes a byte regardless of ROM protection, e.g. for debuggers and loaders.
+    pub fn poke(&mut self, addr: u16, value: u8) -> Result<()> {
+        *self
+            .ram
+            .get_mut(addr as usize)
+            .ok_or(MemoryError::OutOfBoundRead(addr as usize))? = value;
+        Ok(())
+    }
+
+    fn record(&self, access: MemoryAccess) {
+        if let Some(accesses) = &self.accesses {
+            accesses.borrow_mut().push(access);
         }
     }
 
@@ -221,10 +339,9 @@ impl Ram {
     }
 
     fn get(&self, addr: u16) -> Result<u8> {
-        self.ram
-            .get(addr as usize)
-            .ok_or(MemoryError::OutOfBoundRead(addr as usize))
-            .copied()
+        let value = self.peek(addr)?;
+        self.record(MemoryAccess::Read(addr));
+        Ok(value)
     }
 
     fn get_slice(&self, addr: u16) -> Result<&[u8]> {
@@ -241,6 +358,7 @@ impl Ram {
                 return Err(MemoryError::ReadOnlyWrite(addr as u16));
             }
         }
+        self.record(MemoryAccess::Write(addr as u16));
         self.ram
             .get_mut(addr)
             .ok_or(MemoryError::OutOfBoundRead(addr))
@@ -251,43 +369,125 @@ impl Ram {
 pub struct System {
     cpu: Cpu,
     ram: Ram,
+    symbols: SymbolTable,
+    profiler: Option<Profiler>,
+    coverage: Option<CoverageMap>,
+    call_stack: Option<CallStack>,
 }
 
 impl System {
-    pub fn disassembly(rom: &[u8]) -> Result<(), OpCodeError> {
-        let mut pc = 0;
-        loop {
-            let instruction = Instruction::read_at(rom, pc)?;
-            println!("{:04x}  {:x?}", pc, instruction);
-            pc += instruction.size();
-        }
+    /// Prints a linear listing of `rom`, see [`Analysis::linear`].
+    pub fn disassembly(
+        rom: &[u8],
+        symbols: &SymbolTable,
+        coverage: Option<&CoverageMap>,
+        syntax: Syntax,
+    ) -> Result<(), OpCodeError> {
+        let options = ListingOptions {
+            syntax,
+            ..ListingOptions::default()
+        };
+        let analysis = Analysis::linear(rom, 0, coverage);
+        print!("{}", analysis.listing(rom, symbols, &options));
+        Ok(())
     }
 
     pub fn new(ram: Ram, pc: u16) -> Self {
         System {
             cpu: Cpu::new(pc),
             ram,
+            symbols: SymbolTable::new(),
+            profiler: None,
+            coverage: None,
+            call_stack: None,
+        }
+    }
+
+    /// Labels used when printing addresses, e.g. in [`System::dump_state`].
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// Starts accumulating per address and per subroutine statistics in a [`Profiler`].
+    pub fn enable_profiling(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    /// Starts recording which bytes are executed, read and written in a [`CoverageMap`].
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(CoverageMap::new(self.ram.len()));
+        if self.ram.accesses.is_none() {
+            self.ram.log_accesses(true);
         }
     }
 
+    pub fn coverage(&self) -> Option<&CoverageMap> {
+        self.coverage.as_ref()
+    }
+
+    pub fn take_coverage(&mut self) -> Option<CoverageMap> {
+        self.coverage.take()
+    }
+
+    /// Starts following calls, returns and interrupts in a shadow [`CallStack`].
+    pub fn enable_call_stack(&mut self) {
+        self.call_stack = Some(CallStack::new());
+    }
+
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.call_stack.as_ref()
+    }
+
+    pub fn call_stack_mut(&mut self) -> Option<&mut CallStack> {
+        self.call_stack.as_mut()
+    }
+
+    /// Backtrace from the current PC, if the call stack is tracked.
+    pub fn backtrace(&self) -> Option<String> {
+        self.call_stack
+            .as_ref()
+            .map(|call_stack| call_stack.backtrace(self.cpu.pc, &self.symbols))
+    }
+
+    /// Stack mismatches seen by the call stack since last taken, see [`CallStack`].
+    pub fn take_stack_mismatches(&mut self) -> Vec<Mismatch> {
+        self.call_stack
+            .as_mut()
+            .map(CallStack::take_mismatches)
+            .unwrap_or_default()
+    }
+
     pub fn dump_state(&self) {
         println!("Dumping CPU state during execution error.");
-        println!("Registers:");
-        println!("\tA: {:#04x}", self.cpu.get(Register::A));
-        println!("\tF: {:#04x}", self.cpu.flags());
-        println!("\tB: {:#04x}", self.cpu.get(Register::B));
-        println!("\tC: {:#04x}", self.cpu.get(Register::C));
-        println!("\tD: {:#04x}", self.cpu.get(Register::D));
-        println!("\tE: {:#04x}", self.cpu.get(Register::E));
-        println!("\tH: {:#04x}", self.cpu.get(Register::H));
-        println!("\tL: {:#04x}", self.cpu.get(Register::L));
-        println!("Register pairs:");
-        println!("\tA: {:#06x}", self.cpu.psw());
-        println!("\tB: {:#06x}", self.cpu.get_rp(RegisterPair::B));
-        println!("\tD: {:#06x}", self.cpu.get_rp(RegisterPair::D));
-        println!("\tH: {:#06x}", self.cpu.get_rp(RegisterPair::H));
-        println!("SP: {:#06x}", self.cpu.sp());
-        println!("Inte: {}", self.cpu.inte);
+        println!(
+            "PC: {:#06x} ({})",
+            self.cpu.pc,
+            self.symbols.format(self.cpu.pc)
+        );
+        print!("{}", self.cpu);
+        if let Some(backtrace) = self.backtrace() {
+            println!("Backtrace:");
+            print!("{}", backtrace);
+        }
+        let mismatches = self.call_stack.as_ref().map(CallStack::mismatches);
+        if let Some(mismatches @ [_, ..]) = mismatches {
+            println!("Stack mismatches:");
+            for mismatch in mismatches {
+                println!("  {}", mismatch);
+            }
+        }
     }
 
     pub fn next_instruction(&self) -> Result<Instruction, OpCodeError> {
@@ -295,8 +495,54 @@ impl System {
     }
 
     pub fn execute(&mut self, instruction: Instruction, io: &dyn InOut) -> Result<Option<u8>> {
+        self.execute_traced(instruction, io, false)
+    }
+
+    fn execute_traced(
+        &mut self,
+        instruction: Instruction,
+        io: &dyn InOut,
+        interrupt: bool,
+    ) -> Result<Option<u8>> {
+        let (pc, sp) = (self.cpu.pc, self.cpu.sp);
+        if self.coverage.is_some() {
+            // Only this instruction's accesses are of interest; whoever else reads the log
+            // (e.g. the debugger) takes it after execution.
+            self.ram.take_accesses();
+        }
+        let cycles = self.execute_instruction(instruction, io)?;
+        if let Some(call_stack) = &mut self.call_stack {
+            if interrupt {
+                // `process` moved PC back over the instruction, which the device supplied.
+                let interrupted = pc.wrapping_add(instruction.size());
+                call_stack.record_interrupt(interrupted, self.cpu.pc, self.cpu.sp);
+            } else {
+                call_stack.record(pc, instruction, sp, self.cpu.sp, self.cpu.pc);
+            }
+        }
+        if let (Some(coverage), Some(accesses)) = (&mut self.coverage, &self.ram.accesses) {
+            if !interrupt {
+                coverage.mark_instruction(pc, instruction.size());
+            }
+            coverage.mark_accesses(&accesses.borrow());
+        }
+        if let (Some(profiler), Some(cycles)) = (&mut self.profiler, cycles) {
+            if interrupt {
+                profiler.record_interrupt(self.cpu.pc, cycles, self.cpu.sp);
+            } else {
+                profiler.record(pc, instruction, cycles, sp, self.cpu.sp, self.cpu.pc);
+            }
+        }
+        Ok(cycles)
+    }
+
+    fn execute_instruction(
+        &mut self,
+        instruction: Instruction,
+        io: &dyn InOut,
+    ) -> Result<Option<u8>> {
         use Instruction::*;
-        let mut pc = self.cpu.pc + instruction.size();
+        let mut pc = self.cpu.pc.wrapping_add(instruction.size());
         let mut cycles = instruction.cycles();
         match instruction {
             Nop => {}
@@ -388,8 +634,8 @@ impl System {
 
     pub fn process(&mut self, instruction: Instruction, io: &dyn InOut) -> Result<Option<u8>> {
         if self.cpu.inte {
-            self.cpu.pc -= instruction.size();
-            self.execute(instruction, io)
+            self.cpu.pc = self.cpu.pc.wrapping_sub(instruction.size());
+            self.execute_traced(instruction, io, true)
         } else {
             Ok(Some(0))
         }
@@ -405,33 +651,33 @@ impl System {
 
     fn call_test(&mut self, addr: u16, pc: u16, test: bool) -> Result<(u16, u8)> {
         if test {
-            Ok((self.call(addr, pc)?, 5))
+            Ok((self.call(addr, pc)?, 17))
         } else {
-            Ok((pc, 0))
+            Ok((pc, 11))
         }
     }
 
     fn ret_test(&mut self, pc: u16, test: bool) -> Result<(u16, u8)> {
         if test {
-            Ok((self.ret()?, 5))
+            Ok((self.ret()?, 11))
         } else {
-            Ok((pc, 0))
+            Ok((pc, 5))
         }
     }
 
     fn push(&mut self, rp: RegisterPair) -> Result<()> {
         let (h, l) = to_u8(self.get_rp(rp));
-        *self.ram.get_mut(self.cpu.sp - 2)? = l;
-        *self.ram.get_mut(self.cpu.sp - 1)? = h;
-        self.cpu.sp -= 2;
+        *self.ram.get_mut(self.cpu.sp.wrapping_sub(2))? = l;
+        *self.ram.get_mut(self.cpu.sp.wrapping_sub(1))? = h;
+        self.cpu.sp = self.cpu.sp.wrapping_sub(2);
         Ok(())
     }
 
     fn pop(&mut self, rp: RegisterPair) -> Result<()> {
         let (h, l) = rp.split();
         *self.cpu.get_mut(l) = self.ram.get(self.cpu.sp)?;
-        *self.cpu.get_mut(h) = self.ram.get(self.cpu.sp + 1)?;
-        self.cpu.sp += 2;
+        *self.cpu.get_mut(h) = self.ram.get(self.cpu.sp.wrapping_add(1))?;
+        self.cpu.sp = self.cpu.sp.wrapping_add(2);
         Ok(())
     }
 
@@ -508,7 +754,7 @@ impl System {
 
     fn lhld(&mut self, addr: u16) -> Result<()> {
         let l = self.ram.get(addr)?;
-        let h = self.ram.get(addr + 1)?;
+        let h = self.ram.get(addr.wrapping_add(1))?;
         *self.cpu.get_mut(Register::L) = l;
         *self.cpu.get_mut(Register::H) = h;
         Ok(())
@@ -516,7 +762,7 @@ impl System {
 
     fn shld(&mut self, addr: u16) -> Result<()> {
         *self.ram.get_mut(addr)? = self.cpu.get(Register::L);
-        *self.ram.get_mut(addr + 1)? = self.cpu.get(Register::H);
+        *self.ram.get_mut(addr.wrapping_add(1))? = self.cpu.get(Register::H);
         Ok(())
     }
 
@@ -583,20 +829,20 @@ impl System {
 
     fn ret(&mut self) -> Result<u16> {
         let l = self.ram.get(self.cpu.sp)?;
-        let h = self.ram.get(self.cpu.sp + 1)?;
-        self.cpu.sp += 2;
+        let h = self.ram.get(self.cpu.sp.wrapping_add(1))?;
+        self.cpu.sp = self.cpu.sp.wrapping_add(2);
         Ok(to_u16(l, h))
     }
 
     fn inx(&mut self, rp: RegisterPair) {
         match rp {
-            RegisterPair::SP => self.cpu.sp += 1,
+            RegisterPair::SP => self.cpu.sp = self.cpu.sp.wrapping_add(1),
             rp => {
                 let (h, l) = rp.split();
                 let l = self.cpu.get_mut(l);
                 if *l == 255 {
                     *l = 0;
-                    *self.cpu.get_mut(h) += 1;
+                    *self.cpu.get_mut(h) = self.cpu.get(h).wrapping_add(1);
                 } else {
                     *l += 1;
                 }
@@ -606,7 +852,7 @@ impl System {
 
     fn dcx(&mut self, rp: RegisterPair) {
         match rp {
-            RegisterPair::SP => self.cpu.sp -= 1,
+            RegisterPair::SP => self.cpu.sp = self.cpu.sp.wrapping_sub(1),
             rp => {
                 let (h, l) = rp.split();
                 let l = self.cpu.get_mut(l);
@@ -661,9 +907,9 @@ impl System {
     fn call(&mut self, addr: u16, pc: u16) -> Result<u16> {
         let l = (pc & 0xff) as u8;
         let h = (pc >> 8) as u8;
-        *self.ram.get_mut(self.cpu.sp - 1)? = h;
-        *self.ram.get_mut(self.cpu.sp - 2)? = l;
-        self.cpu.sp -= 2;
+        *self.ram.get_mut(self.cpu.sp.wrapping_sub(1))? = h;
+        *self.ram.get_mut(self.cpu.sp.wrapping_sub(2))? = l;
+        self.cpu.sp = self.cpu.sp.wrapping_sub(2);
         Ok(addr)
     }
 
@@ -688,9 +934,9 @@ impl System {
 
     fn xthl(&mut self) -> Result<()> {
         let sp = self.ram.get(self.cpu.sp)?;
-        let sp1 = self.ram.get(self.cpu.sp + 1)?;
+        let sp1 = self.ram.get(self.cpu.sp.wrapping_add(1))?;
         *self.ram.get_mut(self.cpu.sp)? = self.cpu.get(Register::L);
-        *self.ram.get_mut(self.cpu.sp + 1)? = self.cpu.get(Register::H);
+        *self.ram.get_mut(self.cpu.sp.wrapping_add(1))? = self.cpu.get(Register::H);
         *self.cpu.get_mut(Register::L) = sp;
         *self.cpu.get_mut(Register::H) = sp1;
         Ok(())
@@ -743,10 +989,20 @@ impl System {
         &self.cpu
     }
 
+    /// Direct access to the registers, e.g. to set up a test; the call stack and
+    /// profiler are not told about changes made this way.
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
     pub fn ram(&self) -> &Ram {
         &self.ram
     }
 
+    pub fn ram_mut(&mut self) -> &mut Ram {
+        &mut self.ram
+    }
+
     pub fn a(&self) -> u8 {
         self.cpu.get(Register::A)
     }
@@ -811,8 +1067,10 @@ impl BinarytOp for SubOp {
 #[cfg(test)]
 mod tests {
     use crate::{
+        i8080,
         in_out::DummyInOut,
         op_code::{Instruction, Register, RegisterPair},
+        testing,
     };
 
     use super::{MemoryError, Ram, System};
@@ -878,21 +1136,36 @@ mod tests {
         assert!(!s.cpu().cy());
     }
 
+    /// Runs `program`, loaded at 0, until it halts.
+    fn run(program: &[u8]) -> System {
+        let mut s = testing::load(program, 0);
+        testing::run(&mut s);
+        s
+    }
+
     #[test]
     fn daa_auto_test_5a3() {
-        let a = 0x88;
-        let cpi = 0x76;
-
-        let mut s = system();
-        // 1
-        s.execute(Instruction::Mvi(Register::A, a), &DummyInOut)
-            .unwrap();
-        s.execute(Instruction::Add(Register::A), &DummyInOut)
-            .unwrap();
-        s.execute(Instruction::Daa, &DummyInOut).unwrap();
-        s.execute(Instruction::Cpi(cpi), &DummyInOut).unwrap();
+        let s = run(&i8080! {
+            MVI A,88H; ADD A; DAA
+            CPI 76H
+            HLT
+        });
         //assert!(s.cpu().z());
-        assert_eq!(s.cpu().a(), cpi);
+        assert_eq!(s.cpu().a(), 0x76);
+    }
+
+    #[test]
+    fn assembled_subroutine() {
+        let s = run(&i8080! {
+            LXI SP,0FF0H
+            MVI B,5; XRA A
+            loop: CALL double   // A = A * 2 + 1, five times
+            DCR B; JNZ loop
+            HLT
+            double: ADD A; INR A; RET
+        });
+        assert_eq!(s.cpu().a(), 0x1f);
+        assert_eq!(s.cpu().get(Register::B), 0);
     }
 
     #[test]
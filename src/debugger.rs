@@ -0,0 +1,337 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+use thiserror::Error;
+
+use crate::{
+    cpu_state::{MemoryAccess, MemoryError, System},
+    in_out::InOut,
+    op_code::{Instruction, OpCodeError},
+};
+
+#[derive(Error, Debug)]
+pub enum DebuggerError {
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+
+    #[error(transparent)]
+    OpCode(#[from] OpCodeError),
+}
+
+type Result<T, E = DebuggerError> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    Access,
+}
+
+impl Watch {
+    fn matches(self, access: MemoryAccess) -> bool {
+        matches!(
+            (self, access),
+            (Watch::Access, _)
+                | (Watch::Read, MemoryAccess::Read(_))
+                | (Watch::Write, MemoryAccess::Write(_))
+        )
+    }
+}
+
+/// Why execution handed control back to the debugger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(u16),
+    Watchpoint(MemoryAccess),
+    Halted,
+    InstructionLimit,
+    CycleLimit,
+}
+
+/// Drives a [`System`] one instruction at a time, stopping on breakpoints and watchpoints.
+pub struct Debugger {
+    system: System,
+    io: Rc<dyn InOut>,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: BTreeMap<u16, Watch>,
+    instructions: u64,
+    cycles: u64,
+}
+
+impl Debugger {
+    pub fn new(mut system: System, io: Rc<dyn InOut>) -> Self {
+        system.ram_mut().log_accesses(true);
+        system.enable_call_stack();
+        Debugger {
+            system,
+            io,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            instructions: 0,
+            cycles: 0,
+        }
+    }
+
+    pub fn system(&self) -> &System {
+        &self.system
+    }
+
+    pub fn system_mut(&mut self) -> &mut System {
+        &mut self.system
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u16> {
+        &self.breakpoints
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn watchpoints(&self) -> &BTreeMap<u16, Watch> {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, addr: u16, watch: Watch) {
+        self.watchpoints.insert(addr, watch);
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u16) -> bool {
+        self.watchpoints.remove(&addr).is_some()
+    }
+
+    /// Executes exactly one instruction.
+    pub fn step(&mut self) -> Result<StopReason> {
+        let instruction = self.system.next_instruction()?;
+        let executed = self.system.execute(instruction, self.io.as_ref());
+        let accesses = self.system.ram().take_accesses();
+        let Some(cycles) = executed? else {
+            return Ok(StopReason::Halted);
+        };
+        self.instructions += 1;
+        self.cycles += cycles as u64;
+
+        let watched = accesses.into_iter().find(|access| {
+            self.watchpoints
+                .get(&access.addr())
+                .is_some_and(|watch| watch.matches(*access))
+        });
+        Ok(match watched {
+            Some(access) => StopReason::Watchpoint(access),
+            None => StopReason::Step,
+        })
+    }
+
+    /// Executes one instruction, running subroutines called by `CALL`/`RST` to completion.
+    pub fn next(&mut self, limit: Option<u64>) -> Result<StopReason> {
+        let instruction = self.system.next_instruction()?;
+        if !instruction.is_call() {
+            return self.step();
+        }
+        let return_address = self.system.cpu().pc().wrapping_add(instruction.size());
+        let sp = self.system.cpu().sp();
+        self.run_until(
+            limit,
+            |system| system.cpu().pc() == return_address && system.cpu().sp() >= sp,
+            u64::MAX,
+        )
+    }
+
+    /// Runs until the current subroutine returns to its caller.
+    pub fn finish(&mut self, limit: Option<u64>) -> Result<StopReason> {
+        let sp = self.system.cpu().sp();
+        self.run_until(limit, |system| system.cpu().sp() > sp, u64::MAX)
+    }
+
+    /// Runs until a breakpoint, a watchpoint, a `HLT` or `limit` instructions.
+    pub fn cont(&mut self, limit: Option<u64>) -> Result<StopReason> {
+        self.run_until(limit, |_| false, u64::MAX)
+    }
+
+    /// Like [`Debugger::cont`], but stops once at least `cycles` cycles have elapsed.
+    pub fn cont_cycles(&mut self, cycles: u64) -> Result<StopReason> {
+        let target = self.cycles + cycles;
+        match self.run_until(None, |_| false, target)? {
+            StopReason::Step => Ok(StopReason::CycleLimit),
+            reason => Ok(reason),
+        }
+    }
+
+    fn run_until(
+        &mut self,
+        limit: Option<u64>,
+        mut done: impl FnMut(&System) -> bool,
+        cycle_target: u64,
+    ) -> Result<StopReason> {
+        let mut executed = 0;
+        loop {
+            if self.cycles >= cycle_target {
+                return Ok(StopReason::Step);
+            }
+            if limit.is_some_and(|limit| executed >= limit) {
+                return Ok(StopReason::InstructionLimit);
+            }
+            let reason = self.step()?;
+            executed += 1;
+            if reason != StopReason::Step || done(&self.system) {
+                return Ok(reason);
+            }
+            let pc = self.system.cpu().pc();
+            if self.breakpoints.contains(&pc) {
+                return Ok(StopReason::Breakpoint(pc));
+            }
+        }
+    }
+
+    /// Requests interrupt `RST n`; returns `false` if interrupts are disabled.
+    pub fn interrupt(&mut self, n: u8) -> Result<bool> {
+        if !self.system.cpu().inte() {
+            return Ok(false);
+        }
+        let cycles = self
+            .system
+            .process(Instruction::Rst(n), self.io.as_ref())?
+            .unwrap_or(0);
+        self.system.ram().take_accesses();
+        self.cycles += cycles as u64;
+        Ok(true)
+    }
+
+    /// Decodes instructions around `addr`, trying to resynchronise up to `before` bytes
+    /// earlier so that `addr` falls on an instruction boundary.
+    pub fn disassemble_around(
+        &self,
+        addr: u16,
+        before: u16,
+        count: usize,
+    ) -> Vec<(u16, Instruction)> {
+        let memory = self.system.get_slice(0).unwrap_or(&[]);
+        let start = (1..=before)
+            .rev()
+            .filter_map(|offset| addr.checked_sub(offset))
+            .find(|&start| {
+                let mut pc = start;
+                while pc < addr {
+                    let next = Instruction::read_at(memory, pc)
+                        .ok()
+                        .and_then(|instruction| pc.checked_add(instruction.size()));
+                    match next {
+                        Some(next) => pc = next,
+                        None => return false,
+                    }
+                }
+                pc == addr
+            })
+            .unwrap_or(addr);
+
+        let mut pc = start;
+        let mut out = Vec::with_capacity(count);
+        while out.len() < count {
+            let Ok(instruction) = Instruction::read_at(memory, pc) else {
+                break;
+            };
+            out.push((pc, instruction));
+            pc = pc.wrapping_add(instruction.size());
+            if pc == 0 {
+                break;
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        cpu_state::{MemoryAccess, Ram, System},
+        in_out::PortLatch,
+    };
+
+    use super::{Debugger, StopReason, Watch};
+
+    fn debugger(program: &[u8]) -> Debugger {
+        let mut ram = Ram::new(0x1000, false);
+        ram.register_rom(program, 0).unwrap();
+        Debugger::new(System::new(ram, 0), Rc::new(PortLatch::default()))
+    }
+
+    // 0x0000: LXI SP, 0x0800
+    // 0x0003: CALL 0x000a
+    // 0x0006: STA 0x0900
+    // 0x0009: HLT
+    // 0x000a: MVI A, 0x42
+    // 0x000c: RET
+    const PROGRAM: [u8; 13] = [
+        0x31, 0x00, 0x08, 0xcd, 0x0a, 0x00, 0x32, 0x00, 0x09, 0x76, 0x3e, 0x42, 0xc9,
+    ];
+
+    #[test]
+    fn breakpoint_and_next() {
+        let mut d = debugger(&PROGRAM);
+        d.add_breakpoint(0x0a);
+        assert_eq!(d.cont(None).unwrap(), StopReason::Breakpoint(0x0a));
+        assert_eq!(d.system().cpu().pc(), 0x0a);
+
+        let mut d = debugger(&PROGRAM);
+        d.step().unwrap();
+        assert_eq!(d.next(None).unwrap(), StopReason::Step);
+        assert_eq!(d.system().cpu().pc(), 0x06);
+        assert_eq!(d.system().a(), 0x42);
+
+        let mut d = debugger(&PROGRAM);
+        d.step().unwrap();
+        d.step().unwrap();
+        assert_eq!(d.system().cpu().pc(), 0x0a);
+        assert_eq!(d.finish(None).unwrap(), StopReason::Step);
+        assert_eq!(d.system().cpu().pc(), 0x06);
+    }
+
+    #[test]
+    fn watchpoint_and_halt() {
+        let mut d = debugger(&PROGRAM);
+        d.add_watchpoint(0x0900, Watch::Write);
+        assert_eq!(
+            d.cont(None).unwrap(),
+            StopReason::Watchpoint(MemoryAccess::Write(0x0900))
+        );
+        assert_eq!(d.cont(None).unwrap(), StopReason::Halted);
+        assert_eq!(d.cont(Some(0)).unwrap(), StopReason::InstructionLimit);
+    }
+
+    #[test]
+    fn disassemble_around_resynchronises() {
+        let d = debugger(&PROGRAM);
+        let listing = d.disassemble_around(0x06, 8, 2);
+        assert_eq!(listing.first().map(|(pc, _)| *pc), Some(0x00));
+        assert!(listing.iter().any(|(pc, _)| *pc == 0x03));
+    }
+
+    #[test]
+    fn disassemble_around_the_end_of_memory() {
+        // LXI B at 0xfffd ends exactly at the end of memory.
+        let mut ram = Ram::new(0x10000, false);
+        ram.poke(0xfffd, 0x01).unwrap();
+        let d = Debugger::new(System::new(ram, 0), Rc::new(PortLatch::default()));
+        let listing = d.disassemble_around(0xffff, 4, 1);
+        assert_eq!(listing.first().map(|(pc, _)| *pc), Some(0xfffe));
+    }
+}
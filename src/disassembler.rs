@@ -0,0 +1,598 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::{
+    coverage::{CoverageMap, Usage},
+    op_code::{is_undocumented, Instruction, Register, RegisterPair},
+    semantics::{Flow, Registers},
+    symbols::SymbolTable,
+    syntax::{HexStyle, Syntax},
+};
+
+/// The reset vector and the eight `RST` vectors.
+pub const DEFAULT_ENTRY_POINTS: [u16; 8] = [0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38];
+
+/// How an address is referred to by the code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reference {
+    Jump,
+    Call,
+    /// Address operand of `LDA`/`LHLD`.
+    Read,
+    /// Address operand of `STA`/`SHLD`.
+    Write,
+    /// Operand of an `LXI`, which may or may not be an address.
+    Immediate,
+    /// Read through `M` or `LDAX` with a pointer set by an earlier `LXI`.
+    IndirectRead,
+    /// Write through `M` or `STAX` with a pointer set by an earlier `LXI`.
+    IndirectWrite,
+}
+
+/// Direction of an `IN`/`OUT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PortAccess {
+    In,
+    Out,
+}
+
+/// Values of BC, DE and HL known along the flow being followed, to resolve accesses through
+/// `M`, `LDAX` and `STAX`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Pointers {
+    bc: Option<u16>,
+    de: Option<u16>,
+    hl: Option<u16>,
+}
+
+impl Pointers {
+    fn get(&self, pair: RegisterPair) -> Option<u16> {
+        match pair {
+            RegisterPair::B => self.bc,
+            RegisterPair::D => self.de,
+            RegisterPair::H => self.hl,
+            _ => None,
+        }
+    }
+
+    fn get_mut(&mut self, pair: RegisterPair) -> Option<&mut Option<u16>> {
+        match pair {
+            RegisterPair::B => Some(&mut self.bc),
+            RegisterPair::D => Some(&mut self.de),
+            RegisterPair::H => Some(&mut self.hl),
+            _ => None,
+        }
+    }
+
+    fn update(&mut self, instruction: Instruction) {
+        use Instruction::*;
+        match instruction {
+            Lxi(pair, ..) => {
+                if let Some(value) = self.get_mut(pair) {
+                    *value = instruction.address_operand();
+                }
+            }
+            Inx(pair) | Dcx(pair) => {
+                let delta = if matches!(instruction, Inx(_)) {
+                    1
+                } else {
+                    0xffff
+                };
+                if let Some(Some(value)) = self.get_mut(pair) {
+                    *value = value.wrapping_add(delta);
+                }
+            }
+            Xchg => std::mem::swap(&mut self.de, &mut self.hl),
+            // The callee may use any register.
+            instruction if instruction.is_call() => *self = Pointers::default(),
+            instruction => {
+                let written = instruction.effects().writes;
+                for pair in [RegisterPair::B, RegisterPair::D, RegisterPair::H] {
+                    if written.intersects(Registers::pair(pair)) {
+                        if let Some(value) = self.get_mut(pair) {
+                            *value = None;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How [`Analysis::listing`] renders a ROM.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ListingOptions {
+    pub syntax: Syntax,
+    /// Show the raw bytes of each line next to its address.
+    pub bytes: bool,
+    /// First address listed, the start of the ROM by default.
+    pub start: Option<u16>,
+    /// Last address listed, inclusive, the end of the ROM by default.
+    pub end: Option<u16>,
+}
+
+/// Split of a ROM into code and data, either following the control flow from entry points
+/// or decoding it linearly.
+///
+/// Every byte not part of a decoded instruction is considered data.
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    origin: u16,
+    len: usize,
+    instructions: BTreeMap<u16, Instruction>,
+    /// Bytes covered by an instruction, opcode or operand, relative to `origin`.
+    code: Vec<bool>,
+    references: BTreeMap<u16, BTreeSet<(u16, Reference)>>,
+    ports: BTreeMap<u8, BTreeSet<(u16, PortAccess)>>,
+    entry_points: BTreeSet<u16>,
+    unresolved: BTreeSet<u16>,
+    undocumented: BTreeSet<u16>,
+    /// Data known to be accessed as 16 bit words, rendered as `DW`.
+    words: BTreeSet<u16>,
+}
+
+impl Analysis {
+    /// Recursive descent disassembly of `rom`, loaded at `origin`, from `entry_points`.
+    /// Entry points outside of the ROM are ignored.
+    ///
+    /// Conditional branches and calls are assumed to fall through, flow stops after `JMP`,
+    /// `RET` and `PCHL`. Undocumented opcodes are assumed to be data the flow ran into.
+    pub fn new(rom: &[u8], origin: u16, entry_points: &[u16]) -> Self {
+        let mut analysis = Self::empty(rom, origin);
+        let mut pending = entry_points.iter().rev().copied().collect::<Vec<_>>();
+        while let Some(addr) = pending.pop() {
+            analysis.follow(rom, addr, &mut pending);
+        }
+        analysis.entry_points = entry_points
+            .iter()
+            .copied()
+            .filter(|addr| analysis.instructions.contains_key(addr))
+            .collect();
+        analysis
+    }
+
+    /// Linear sweep of `rom`, loaded at `origin`, decoding every byte that `coverage` did not
+    /// see accessed as data only. Undocumented opcodes and a truncated instruction at the end
+    /// are left as data.
+    pub fn linear(rom: &[u8], origin: u16, coverage: Option<&CoverageMap>) -> Self {
+        let mut analysis = Self::empty(rom, origin);
+        let mut offset = 0;
+        while offset < rom.len() {
+            let addr = origin.wrapping_add(offset as u16);
+            let data = coverage.is_some_and(|c| c.get(addr).is_data());
+            let instruction = Instruction::read_at(rom, offset as u16);
+            match instruction {
+                Ok(instruction) if !data && !is_undocumented(rom[offset]) => {
+                    let size = instruction.size() as usize;
+                    analysis.code[offset..offset + size].fill(true);
+                    analysis.instructions.insert(addr, instruction);
+                    offset += size;
+                }
+                _ => offset += 1,
+            }
+        }
+        if let Some(coverage) = coverage {
+            analysis.apply_coverage(coverage);
+        }
+        analysis
+    }
+
+    fn empty(rom: &[u8], origin: u16) -> Self {
+        Analysis {
+            origin,
+            len: rom.len(),
+            instructions: BTreeMap::new(),
+            code: vec![false; rom.len()],
+            references: BTreeMap::new(),
+            ports: BTreeMap::new(),
+            entry_points: BTreeSet::new(),
+            unresolved: BTreeSet::new(),
+            undocumented: BTreeSet::new(),
+            words: BTreeSet::new(),
+        }
+    }
+
+    /// Renders data that `coverage` saw read as 16 bit words with `DW`.
+    pub fn apply_coverage(&mut self, coverage: &CoverageMap) {
+        for offset in 0..self.len.saturating_sub(1) {
+            let addr = self.origin.wrapping_add(offset as u16);
+            if !self.code[offset]
+                && !self.code[offset + 1]
+                && coverage.get(addr).contains(Usage::WORD)
+            {
+                self.words.insert(addr);
+            }
+        }
+    }
+
+    fn offset(&self, addr: u16) -> Option<usize> {
+        let offset = addr.wrapping_sub(self.origin) as usize;
+        (offset < self.len).then_some(offset)
+    }
+
+    fn follow(&mut self, rom: &[u8], mut addr: u16, pending: &mut Vec<u16>) {
+        use Instruction::*;
+        let mut pointers = Pointers::default();
+        while let Some(offset) = self.offset(addr) {
+            if self.code[offset] {
+                return;
+            }
+            if is_undocumented(rom[offset]) {
+                self.undocumented.insert(addr);
+                return;
+            }
+            let Ok(instruction) = Instruction::read_at(rom, offset as u16) else {
+                return;
+            };
+            let size = instruction.size() as usize;
+            if self.code[offset..offset + size].iter().any(|code| *code) {
+                return;
+            }
+            self.code[offset..offset + size].fill(true);
+            self.instructions.insert(addr, instruction);
+
+            let next = addr.wrapping_add(instruction.size());
+            self.indirect_accesses(addr, instruction, &pointers);
+            pointers.update(instruction);
+            let flow = instruction.flow();
+            if let Some(target) = instruction.branch_target() {
+                let kind = if instruction.is_call() {
+                    Reference::Call
+                } else {
+                    Reference::Jump
+                };
+                self.reference(addr, target, kind);
+                pending.push(target);
+            }
+            if flow == Flow::IndirectJump {
+                self.unresolved.insert(addr);
+            }
+            if !flow.falls_through() {
+                return;
+            }
+            match instruction {
+                Lda(target) | Lhld(target) => self.reference(addr, target, Reference::Read),
+                Sta(target) | Shld(target) => self.reference(addr, target, Reference::Write),
+                In(port) => self.port(addr, port, PortAccess::In),
+                Out(port) => self.port(addr, port, PortAccess::Out),
+                Lxi(..) => {
+                    let target = instruction.address_operand().unwrap_or_default();
+                    self.reference(addr, target, Reference::Immediate);
+                }
+                _ => {}
+            }
+            addr = next;
+        }
+    }
+
+    fn reference(&mut self, from: u16, to: u16, kind: Reference) {
+        self.references.entry(to).or_default().insert((from, kind));
+    }
+
+    fn port(&mut self, from: u16, port: u8, access: PortAccess) {
+        self.ports.entry(port).or_default().insert((from, access));
+    }
+
+    fn indirect_accesses(&mut self, addr: u16, instruction: Instruction, pointers: &Pointers) {
+        use Instruction::*;
+        use Register::M;
+        let (pair, read, write) = match instruction {
+            Mov(M, _) | Mvi(M, _) => (RegisterPair::H, false, true),
+            Mov(_, M) | Add(M) | Adc(M) | Sub(M) | Sbb(M) | Ana(M) | Xra(M) | Ora(M) | Cmp(M) => {
+                (RegisterPair::H, true, false)
+            }
+            Inr(M) | Dcr(M) => (RegisterPair::H, true, true),
+            Ldax(pair) => (pair, true, false),
+            Stax(pair) => (pair, false, true),
+            _ => return,
+        };
+        if let Some(target) = pointers.get(pair) {
+            if read {
+                self.reference(addr, target, Reference::IndirectRead);
+            }
+            if write {
+                self.reference(addr, target, Reference::IndirectWrite);
+            }
+        }
+    }
+
+    pub fn origin(&self) -> u16 {
+        self.origin
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether `addr` is the opcode or an operand of a reachable instruction.
+    pub fn is_code(&self, addr: u16) -> bool {
+        self.offset(addr).is_some_and(|offset| self.code[offset])
+    }
+
+    pub fn instruction_at(&self, addr: u16) -> Option<Instruction> {
+        self.instructions.get(&addr).copied()
+    }
+
+    pub fn instructions(&self) -> impl Iterator<Item = (u16, Instruction)> + '_ {
+        self.instructions
+            .iter()
+            .map(|(addr, instruction)| (*addr, *instruction))
+    }
+
+    /// Addresses referred to by the code, with the address and kind of each reference. May
+    /// include addresses outside of the ROM.
+    pub fn references(&self) -> &BTreeMap<u16, BTreeSet<(u16, Reference)>> {
+        &self.references
+    }
+
+    /// Entry points the analysis started from that turned out to be code.
+    pub fn entry_points(&self) -> &BTreeSet<u16> {
+        &self.entry_points
+    }
+
+    /// I/O ports accessed by the code, with the address and direction of each access.
+    pub fn ports(&self) -> &BTreeMap<u8, BTreeSet<(u16, PortAccess)>> {
+        &self.ports
+    }
+
+    /// Addresses of the `PCHL`s whose targets could not be followed.
+    pub fn unresolved(&self) -> &BTreeSet<u16> {
+        &self.unresolved
+    }
+
+    /// Addresses where the flow ran into an undocumented opcode.
+    pub fn undocumented(&self) -> &BTreeSet<u16> {
+        &self.undocumented
+    }
+
+    /// Listing of the ROM, with data rendered as `DB`/`DW` directives.
+    pub fn listing(&self, rom: &[u8], symbols: &SymbolTable, options: &ListingOptions) -> String {
+        const BYTES_PER_LINE: usize = 8;
+        let offset_of = |addr: u16| addr.wrapping_sub(self.origin) as usize;
+        let last = options
+            .end
+            .map_or(self.len, |end| (offset_of(end) + 1).min(self.len));
+        let mut out = String::new();
+        let mut offset = options.start.map_or(0, offset_of);
+        while offset < last {
+            let addr = self.origin.wrapping_add(offset as u16);
+            if let Some(label) = symbols.label(addr) {
+                let _ = writeln!(out, "{}:", label);
+            }
+            let (size, text) = if let Some(instruction) = self.instruction_at(addr) {
+                let mut text = symbols.annotate(instruction.display().syntax(options.syntax));
+                if self.unresolved.contains(&addr) {
+                    text.push_str("  ; unresolved jump");
+                }
+                (instruction.size() as usize, text)
+            } else if self.words.contains(&addr) && symbols.label(addr.wrapping_add(1)).is_none() {
+                let word = u16::from_le_bytes([rom[offset], rom[offset + 1]]);
+                (2, format!("DW {}", HexStyle::Intel.word(word)))
+            } else {
+                let mut end = offset + 1;
+                while end < last
+                    && end - offset < BYTES_PER_LINE
+                    && !self.code[end]
+                    && !self.words.contains(&self.origin.wrapping_add(end as u16))
+                    && symbols
+                        .label(self.origin.wrapping_add(end as u16))
+                        .is_none()
+                {
+                    end += 1;
+                }
+                let bytes = rom[offset..end]
+                    .iter()
+                    .map(|b| HexStyle::Intel.byte(*b))
+                    .collect::<Vec<_>>();
+                (end - offset, format!("DB {}", bytes.join(",")))
+            };
+            if options.bytes {
+                let bytes = rom[offset..offset + size]
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<Vec<_>>();
+                let _ = writeln!(out, "{:04x}  {:<8}  {}", addr, bytes.join(" "), text);
+            } else {
+                let _ = writeln!(out, "{:04x}  {}", addr, text);
+            }
+            offset += size;
+        }
+        out
+    }
+
+    /// Name of every address the re-assemblable source refers to: jump and call targets,
+    /// load and store addresses, `LXI` operands pointing into the ROM, and the symbols
+    /// defined inside the ROM.
+    fn labels(&self, symbols: &SymbolTable) -> BTreeMap<u16, String> {
+        let name = |addr: u16| match symbols.label(addr) {
+            Some(label) => label.to_string(),
+            None => format!("L{:04X}", addr),
+        };
+        let in_rom = |addr: u16| self.offset(addr).is_some();
+        let mut labels = BTreeMap::new();
+        for (addr, references) in &self.references {
+            if in_rom(*addr)
+                || references
+                    .iter()
+                    .any(|(_, kind)| *kind != Reference::Immediate)
+            {
+                labels.insert(*addr, name(*addr));
+            }
+        }
+        for (addr, label) in symbols.iter().filter(|(addr, _)| in_rom(*addr)) {
+            labels.insert(addr, label.to_string());
+        }
+        labels
+    }
+
+    /// Source that assembles back to the exact bytes of `rom`: labels for every referenced
+    /// address, an `ORG`, `DB` for data and for any instruction whose encoding would not
+    /// round-trip, and the address and bytes of each line as a comment.
+    pub fn source(&self, rom: &[u8], symbols: &SymbolTable, syntax: Syntax) -> String {
+        const BYTES_PER_LINE: usize = 8;
+        const COMMENT_COLUMN: usize = 32;
+        let labels = self.labels(symbols);
+
+        // (offset, size, instruction) of each line, `None` for data.
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < self.len {
+            let addr = self.origin.wrapping_add(offset as u16);
+            let instruction = self.instruction_at(addr).filter(|instruction| {
+                let size = instruction.size() as usize;
+                instruction.encode().as_deref() == rom.get(offset..offset + size)
+            });
+            if let Some(instruction) = instruction {
+                lines.push((offset, instruction.size() as usize, Some(instruction)));
+                offset += instruction.size() as usize;
+                continue;
+            }
+            let mut end = offset + 1;
+            while end < self.len
+                && end - offset < BYTES_PER_LINE
+                && !self.code[end]
+                && !labels.contains_key(&self.origin.wrapping_add(end as u16))
+            {
+                end += 1;
+            }
+            lines.push((offset, end - offset, None));
+            offset = end;
+        }
+
+        let mut out = String::new();
+        let line_starts = lines
+            .iter()
+            .map(|(offset, _, _)| self.origin.wrapping_add(*offset as u16))
+            .collect::<BTreeSet<_>>();
+        for (addr, label) in labels
+            .iter()
+            .filter(|(addr, _)| !line_starts.contains(addr))
+        {
+            let _ = writeln!(out, "{:<7} EQU {}", label, HexStyle::Intel.word(*addr));
+        }
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        let _ = writeln!(out, "        ORG {}\n", HexStyle::Intel.word(self.origin));
+
+        for (offset, size, instruction) in lines {
+            let addr = self.origin.wrapping_add(offset as u16);
+            if let Some(label) = labels.get(&addr) {
+                let _ = writeln!(out, "{}:", label);
+            }
+            let text = match instruction {
+                Some(instruction) => {
+                    let display = instruction.display().syntax(syntax);
+                    match instruction.address_operand().and_then(|a| labels.get(&a)) {
+                        Some(label) => display.symbol(label.as_str()).to_string(),
+                        None => display.to_string(),
+                    }
+                }
+                None => {
+                    let bytes = rom[offset..offset + size]
+                        .iter()
+                        .map(|b| HexStyle::Intel.byte(*b))
+                        .collect::<Vec<_>>();
+                    format!("DB {}", bytes.join(","))
+                }
+            };
+            let bytes = rom[offset..offset + size]
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>();
+            let _ = writeln!(
+                out,
+                "        {:<width$}; {:04X}  {}",
+                text,
+                addr,
+                bytes.join(" "),
+                width = COMMENT_COLUMN - 8
+            );
+        }
+        let _ = writeln!(out, "\n        END");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{symbols::SymbolTable, syntax::Syntax};
+
+    use super::{Analysis, ListingOptions, Reference, DEFAULT_ENTRY_POINTS};
+
+    #[test]
+    fn follows_control_flow() {
+        // 0x0000: JMP 0x0006
+        // 0x0003: DB 0x01, 0x02, 0x03
+        // 0x0006: CALL 0x000d
+        // 0x0009: JNZ 0x0000
+        // 0x000c: HLT
+        // 0x000d: PCHL
+        // 0x000e: DB 0xff
+        let rom = [
+            0xc3, 0x06, 0x00, 0x01, 0x02, 0x03, 0xcd, 0x0d, 0x00, 0xc2, 0x00, 0x00, 0x76, 0xe9,
+            0xff,
+        ];
+        let analysis = Analysis::new(&rom, 0, &[0]);
+        assert!(analysis.is_code(0x00) && analysis.is_code(0x02));
+        assert!(!analysis.is_code(0x03) && !analysis.is_code(0x05));
+        assert!(analysis.is_code(0x0c) && !analysis.is_code(0x0e));
+        assert_eq!(
+            analysis.unresolved().iter().copied().collect::<Vec<_>>(),
+            [0x0d]
+        );
+        assert!(analysis.references()[&0x0d].contains(&(0x06, Reference::Call)));
+
+        let listing = analysis.listing(&rom, &SymbolTable::new(), &ListingOptions::default());
+        assert_eq!(
+            listing,
+            "0000  JMP 0006H\n0003  DB 01H,02H,03H\n0006  CALL 000DH\n0009  JNZ 0000H\n\
+             000c  HLT\n000d  PCHL  ; unresolved jump\n000e  DB 0FFH\n"
+        );
+
+        let source = analysis.source(
+            &rom,
+            &SymbolTable::parse("000d jump").unwrap(),
+            Syntax::Intel,
+        );
+        assert_eq!(
+            source,
+            "        ORG 0000H\n\n\
+             L0000:\n\
+             \x20       JMP L0006               ; 0000  C3 06 00\n\
+             \x20       DB 01H,02H,03H          ; 0003  01 02 03\n\
+             L0006:\n\
+             \x20       CALL jump               ; 0006  CD 0D 00\n\
+             \x20       JNZ L0000               ; 0009  C2 00 00\n\
+             \x20       HLT                     ; 000C  76\n\
+             jump:\n\
+             \x20       PCHL                    ; 000D  E9\n\
+             \x20       DB 0FFH                 ; 000E  FF\n\
+             \n        END\n"
+        );
+
+        // Entry points out of the ROM are ignored.
+        let analysis = Analysis::new(&rom[3..], 0x100, &DEFAULT_ENTRY_POINTS);
+        assert_eq!(analysis.instructions().count(), 0);
+    }
+
+    #[test]
+    fn linear_sweep_with_options() {
+        // NOP, an undocumented opcode, MVI A,0x12, then a truncated JMP.
+        let rom = [0x00, 0x08, 0x3e, 0x12, 0xc3, 0x01];
+        let analysis = Analysis::linear(&rom, 0x100, None);
+        let options = ListingOptions {
+            bytes: true,
+            start: Some(0x101),
+            ..ListingOptions::default()
+        };
+        assert_eq!(
+            analysis.listing(&rom, &SymbolTable::new(), &options),
+            "0101  08        DB 08H\n0102  3e 12     MVI A,12H\n0104  c3 01     DB 0C3H,01H\n"
+        );
+    }
+}
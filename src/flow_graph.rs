@@ -0,0 +1,326 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::{
+    disassembler::{Analysis, Reference},
+    op_code::Instruction,
+    semantics::Flow,
+    symbols::SymbolTable,
+    syntax::Syntax,
+};
+
+/// Straight-line run of instructions, entered only at its first one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub instructions: Vec<(u16, Instruction)>,
+    /// Blocks control may continue to, not counting calls.
+    pub successors: Vec<u16>,
+    /// Subroutines called from the block.
+    pub calls: Vec<u16>,
+}
+
+/// Basic blocks, subroutines and call graph of an [`Analysis`].
+#[derive(Debug, Clone)]
+pub struct FlowGraph {
+    blocks: BTreeMap<u16, BasicBlock>,
+    /// Entry point of each subroutine, with the blocks reachable from it.
+    subroutines: BTreeMap<u16, BTreeSet<u16>>,
+}
+
+impl FlowGraph {
+    /// Splits the code found by `analysis` into basic blocks. Subroutines start at call
+    /// targets and at the analysis entry points, and own every block reachable from there
+    /// without going through a call; blocks reached by several subroutines belong to each.
+    pub fn new(analysis: &Analysis) -> Self {
+        let targets = analysis
+            .references()
+            .iter()
+            .filter(|(_, refs)| {
+                refs.iter()
+                    .any(|(_, kind)| matches!(kind, Reference::Jump | Reference::Call))
+            })
+            .map(|(addr, _)| *addr);
+        let mut leaders = targets
+            .chain(analysis.entry_points().iter().copied())
+            .collect::<BTreeSet<_>>();
+        // In ROM order, which is not address order when the ROM wraps around 0xffff.
+        let mut instructions = analysis.instructions().collect::<Vec<_>>();
+        instructions.sort_by_key(|(addr, _)| addr.wrapping_sub(analysis.origin()));
+        let mut previous: Option<(u16, Instruction)> = None;
+        for &(addr, instruction) in &instructions {
+            let falls_into = previous.is_some_and(|(prev, prev_instruction)| {
+                prev.wrapping_add(prev_instruction.size()) == addr && !ends_block(prev_instruction)
+            });
+            if !falls_into {
+                leaders.insert(addr);
+            }
+            previous = Some((addr, instruction));
+        }
+
+        let mut blocks = BTreeMap::new();
+        let mut current: Option<BasicBlock> = None;
+        for (addr, instruction) in instructions {
+            if leaders.contains(&addr) {
+                if let Some(block) = current.take() {
+                    blocks.insert(block.start, close(block, Some(addr)));
+                }
+            }
+            let block = current.get_or_insert_with(|| BasicBlock {
+                start: addr,
+                instructions: Vec::new(),
+                successors: Vec::new(),
+                calls: Vec::new(),
+            });
+            block.instructions.push((addr, instruction));
+            if let Some(target) = call_target(instruction) {
+                block.calls.push(target);
+            }
+            if ends_block(instruction) {
+                if let Some(block) = current.take() {
+                    blocks.insert(block.start, close(block, None));
+                }
+            }
+        }
+        if let Some(block) = current.take() {
+            blocks.insert(block.start, close(block, None));
+        }
+        // Targets outside the decoded code have no block to point to.
+        let starts = blocks.keys().copied().collect::<BTreeSet<_>>();
+        for block in blocks.values_mut() {
+            block.successors.retain(|succ| starts.contains(succ));
+        }
+
+        let entries = analysis
+            .references()
+            .iter()
+            .filter(|(_, refs)| refs.iter().any(|(_, kind)| *kind == Reference::Call))
+            .map(|(addr, _)| *addr)
+            .chain(analysis.entry_points().iter().copied())
+            .filter(|addr| blocks.contains_key(addr))
+            .collect::<BTreeSet<_>>();
+        let subroutines = entries
+            .into_iter()
+            .map(|entry| {
+                let mut reached = BTreeSet::new();
+                let mut pending = vec![entry];
+                while let Some(addr) = pending.pop() {
+                    if let Some(block) = blocks.get(&addr) {
+                        if reached.insert(addr) {
+                            pending.extend(&block.successors);
+                        }
+                    }
+                }
+                (entry, reached)
+            })
+            .collect();
+        FlowGraph {
+            blocks,
+            subroutines,
+        }
+    }
+
+    pub fn blocks(&self) -> &BTreeMap<u16, BasicBlock> {
+        &self.blocks
+    }
+
+    pub fn subroutines(&self) -> &BTreeMap<u16, BTreeSet<u16>> {
+        &self.subroutines
+    }
+
+    /// Calls between subroutines, as (caller, callee) entry points.
+    pub fn calls(&self) -> BTreeSet<(u16, u16)> {
+        let mut calls = BTreeSet::new();
+        for (entry, blocks) in &self.subroutines {
+            for block in blocks.iter().filter_map(|addr| self.blocks.get(addr)) {
+                calls.extend(block.calls.iter().map(|callee| (*entry, *callee)));
+            }
+        }
+        calls
+    }
+
+    /// DOT graph of the subroutine starting at `entry`, one node per basic block.
+    pub fn subroutine_dot(&self, entry: u16, symbols: &SymbolTable, syntax: Syntax) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph \"{}\" {{", escape(&name(entry, symbols)));
+        let _ = writeln!(out, "    node [shape=box, fontname=monospace];");
+        let blocks = self.subroutines.get(&entry).cloned().unwrap_or_default();
+        for block in blocks.iter().filter_map(|addr| self.blocks.get(addr)) {
+            let mut label = String::new();
+            if let Some(symbol) = symbols.label(block.start) {
+                let _ = write!(label, "{}:\\l", escape(symbol));
+            }
+            for (addr, instruction) in &block.instructions {
+                let text = symbols.annotate(instruction.display().syntax(syntax));
+                let _ = write!(label, "{:04x}  {}\\l", addr, escape(&text));
+            }
+            let _ = writeln!(out, "    b{:04x} [label=\"{}\"];", block.start, label);
+            for successor in &block.successors {
+                let _ = writeln!(out, "    b{:04x} -> b{:04x};", block.start, successor);
+            }
+        }
+        let _ = writeln!(out, "}}");
+        out
+    }
+
+    /// DOT graph of the calls between subroutines.
+    pub fn call_graph_dot(&self, symbols: &SymbolTable) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph calls {{");
+        let _ = writeln!(out, "    node [shape=box, fontname=monospace];");
+        let calls = self.calls();
+        let nodes = self
+            .subroutines
+            .keys()
+            .copied()
+            .chain(calls.iter().map(|(_, callee)| *callee))
+            .collect::<BTreeSet<_>>();
+        for node in nodes {
+            let _ = writeln!(
+                out,
+                "    s{:04x} [label=\"{}\"];",
+                node,
+                escape(&name(node, symbols))
+            );
+        }
+        for (caller, callee) in calls {
+            let _ = writeln!(out, "    s{:04x} -> s{:04x};", caller, callee);
+        }
+        let _ = writeln!(out, "}}");
+        out
+    }
+}
+
+/// Name used for the subroutine at `entry` in graphs.
+pub fn name(entry: u16, symbols: &SymbolTable) -> String {
+    match symbols.label(entry) {
+        Some(label) => label.to_string(),
+        None => format!("sub_{:04x}", entry),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn ends_block(instruction: Instruction) -> bool {
+    matches!(
+        instruction.flow(),
+        Flow::Jump
+            | Flow::ConditionalJump
+            | Flow::IndirectJump
+            | Flow::Return
+            | Flow::ConditionalReturn
+    )
+}
+
+fn call_target(instruction: Instruction) -> Option<u16> {
+    instruction
+        .branch_target()
+        .filter(|_| instruction.is_call())
+}
+
+/// Fills in the successors of `block`, `next` being the leader that interrupted it, if any.
+fn close(mut block: BasicBlock, next: Option<u16>) -> BasicBlock {
+    let Some(&(addr, last)) = block.instructions.last() else {
+        return block;
+    };
+    let fallthrough = addr.wrapping_add(last.size());
+    let flow = last.flow();
+    block.successors = match flow {
+        Flow::Jump | Flow::ConditionalJump => last.branch_target().into_iter().collect(),
+        _ => Vec::new(),
+    };
+    if flow.is_conditional() {
+        block.successors.push(fallthrough);
+    } else if flow.falls_through() {
+        block.successors.extend(next);
+    }
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{i8080, symbols::SymbolTable, syntax::Syntax, testing};
+
+    use super::FlowGraph;
+
+    #[test]
+    fn blocks_and_calls() {
+        let rom = i8080! {
+            start: CALL delay
+            JMP start
+            delay: DCR A
+            JNZ delay
+            RET
+        };
+        let graph = FlowGraph::new(&testing::analyse(&rom, 0));
+        assert_eq!(
+            graph.blocks().keys().copied().collect::<Vec<_>>(),
+            [0x00, 0x06, 0x0a]
+        );
+        assert_eq!(graph.blocks()[&0x06].successors, [0x06, 0x0a]);
+        assert_eq!(graph.blocks()[&0x00].calls, [0x06]);
+        assert_eq!(
+            graph.subroutines()[&0x06]
+                .iter()
+                .copied()
+                .collect::<Vec<_>>(),
+            [0x06, 0x0a]
+        );
+        assert_eq!(
+            graph.calls().into_iter().collect::<Vec<_>>(),
+            [(0x00, 0x06)]
+        );
+
+        let symbols = SymbolTable::parse("0006 delay").unwrap();
+        let dot = graph.subroutine_dot(0x06, &symbols, Syntax::Intel);
+        assert!(
+            dot.contains("b0006 [label=\"delay:\\l0006  DCR A\\l0007  JNZ 0006H  ; delay\\l\"];")
+        );
+        assert!(dot.contains("b0006 -> b000a;"));
+        let calls = graph.call_graph_dot(&symbols);
+        assert!(calls.contains("s0000 -> s0006;"));
+        assert!(calls.contains("s0006 [label=\"delay\"];"));
+    }
+
+    #[test]
+    fn empty_rom() {
+        let graph = FlowGraph::new(&testing::analyse(&[], 0));
+        let symbols = SymbolTable::new();
+        assert!(graph.blocks().is_empty());
+        assert!(graph.subroutines().is_empty());
+        assert_eq!(
+            graph.call_graph_dot(&symbols),
+            "digraph calls {\n    node [shape=box, fontname=monospace];\n}\n"
+        );
+        assert_eq!(
+            graph.subroutine_dot(0, &symbols, Syntax::Intel),
+            "digraph \"sub_0000\" {\n    node [shape=box, fontname=monospace];\n}\n"
+        );
+    }
+
+    #[test]
+    fn rst_across_the_end_of_memory() {
+        // LXI starts at 0xffff and ends at 0x0001.
+        let rom = i8080! {
+            NOP
+            LXI H,0
+            RST 1
+            JMP 0FFFEH
+        };
+        let graph = FlowGraph::new(&testing::analyse(&rom, 0xfffe));
+        assert_eq!(graph.blocks().keys().copied().collect::<Vec<_>>(), [0xfffe]);
+        let block = &graph.blocks()[&0xfffe];
+        assert_eq!(block.instructions.len(), 4);
+        assert_eq!(block.successors, [0xfffe]);
+        assert_eq!(block.calls, [0x08]);
+        assert_eq!(
+            graph.calls().into_iter().collect::<Vec<_>>(),
+            [(0xfffe, 0x08)]
+        );
+        let calls = graph.call_graph_dot(&SymbolTable::new());
+        assert!(calls.contains("s0008 [label=\"sub_0008\"];"));
+        assert!(calls.contains("sfffe -> s0008;"));
+    }
+}
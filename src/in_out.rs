@@ -1,3 +1,5 @@
+use std::cell::Cell;
+
 #[derive(Debug, Clone, Copy)]
 pub enum OutPort {
     One = 1,
@@ -35,3 +37,43 @@ impl InOut for DummyInOut {
         panic!("This is a dummy implementation, this should not actually be called!");
     }
 }
+
+/// Port handler that latches the last value written to each output port and answers
+/// reads with values set by the host, useful when no real hardware is attached.
+pub struct PortLatch {
+    inputs: Vec<Cell<u8>>,
+    outputs: Vec<Cell<u8>>,
+}
+
+impl Default for PortLatch {
+    fn default() -> Self {
+        PortLatch {
+            inputs: vec![Cell::new(0); 256],
+            outputs: vec![Cell::new(0); 256],
+        }
+    }
+}
+
+impl PortLatch {
+    pub fn set_input(&self, port: u8, value: u8) {
+        self.inputs[port as usize].set(value);
+    }
+
+    pub fn input(&self, port: u8) -> u8 {
+        self.inputs[port as usize].get()
+    }
+
+    pub fn output(&self, port: u8) -> u8 {
+        self.outputs[port as usize].get()
+    }
+}
+
+impl InOut for PortLatch {
+    fn write(&self, port: u8, value: u8) {
+        self.outputs[port as usize].set(value);
+    }
+
+    fn read(&self, port: u8) -> u8 {
+        self.input(port)
+    }
+}
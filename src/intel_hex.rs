@@ -0,0 +1,365 @@
+use std::fmt::{self, Write};
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::cpu_state::{MemoryError, Ram};
+
+/// Data bytes per record written.
+const RECORD_LENGTH: usize = 16;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+#[derive(Error, Debug)]
+pub enum HexError {
+    #[error(
+        "Line {0}: a record starts with ':' followed by an even number of hexadecimal digits."
+    )]
+    InvalidRecord(usize),
+
+    #[error("Line {0}: the record holds {1} data bytes, but announces {2}.")]
+    Length(usize, usize, usize),
+
+    #[error("Line {0}: the checksum is {1:02X}H, {2:02X}H was expected.")]
+    Checksum(usize, u8, u8),
+
+    #[error("Line {0}: unknown record type {1:02X}H.")]
+    RecordType(usize, u8),
+
+    #[error("No end of file record.")]
+    MissingEnd,
+
+    #[error("Data at {0:#x} is outside of the 64K address space.")]
+    OutOfRange(u32),
+
+    #[error("Start address {0:#x} is outside of the 64K address space.")]
+    StartOutOfRange(u32),
+
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Contiguous bytes of an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+/// Contents of an Intel HEX file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HexImage {
+    /// In file order; consecutive records are merged.
+    pub segments: Vec<Segment>,
+    /// Start address record, if any.
+    pub start: Option<u32>,
+}
+
+impl HexImage {
+    /// Image of `data` loaded at `origin`.
+    pub fn new(origin: u16, data: &[u8]) -> Self {
+        HexImage {
+            segments: vec![Segment {
+                address: origin as u32,
+                data: data.to_vec(),
+            }],
+            start: None,
+        }
+    }
+
+    /// Parses data, extended address (segment and linear), start address and end of file
+    /// records, checking every checksum. Anything after the end of file record is ignored.
+    pub fn parse(text: &str) -> Result<Self, HexError> {
+        let mut image = HexImage::default();
+        let mut base = 0u32;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_matches(|c: char| c.is_whitespace() || c == '\x1a');
+            if line.is_empty() {
+                continue;
+            }
+            let record = record(i + 1, line)?;
+            let (kind, address, data) = (
+                record[3],
+                u16::from_be_bytes([record[1], record[2]]),
+                &record[4..],
+            );
+            let length = |expected: usize| {
+                if data.len() == expected {
+                    Ok(())
+                } else {
+                    Err(HexError::Length(i + 1, data.len(), expected))
+                }
+            };
+            match kind {
+                DATA => image.push(base.wrapping_add(address as u32), data),
+                END_OF_FILE => return Ok(image),
+                EXTENDED_SEGMENT_ADDRESS => {
+                    length(2)?;
+                    base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+                }
+                EXTENDED_LINEAR_ADDRESS => {
+                    length(2)?;
+                    base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+                }
+                START_SEGMENT_ADDRESS => {
+                    length(4)?;
+                    let segment = u16::from_be_bytes([data[0], data[1]]) as u32;
+                    let offset = u16::from_be_bytes([data[2], data[3]]) as u32;
+                    image.start = Some((segment << 4) + offset);
+                }
+                START_LINEAR_ADDRESS => {
+                    length(4)?;
+                    image.start = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+                }
+                kind => return Err(HexError::RecordType(i + 1, kind)),
+            }
+        }
+        Err(HexError::MissingEnd)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, HexError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn push(&mut self, address: u32, data: &[u8]) {
+        match self.segments.last_mut() {
+            Some(last) if last.address.wrapping_add(last.data.len() as u32) == address => {
+                last.data.extend_from_slice(data)
+            }
+            _ => self.segments.push(Segment {
+                address,
+                data: data.to_vec(),
+            }),
+        }
+    }
+
+    /// Segments with their 16-bit address, failing if one does not fit in 64K.
+    fn segments16(&self) -> Result<Vec<(u16, &[u8])>, HexError> {
+        self.segments
+            .iter()
+            .map(|segment| {
+                let end = segment.address as usize + segment.data.len();
+                if end > 0x10000 {
+                    Err(HexError::OutOfRange(segment.address.max(0x10000)))
+                } else {
+                    Ok((segment.address as u16, segment.data.as_slice()))
+                }
+            })
+            .collect()
+    }
+
+    /// Start address, failing if it does not fit in 64K.
+    pub fn start16(&self) -> Result<Option<u16>, HexError> {
+        match self.start {
+            Some(start) if start > 0xffff => Err(HexError::StartOutOfRange(start)),
+            start => Ok(start.map(|start| start as u16)),
+        }
+    }
+
+    /// Registers every segment as ROM.
+    pub fn register_rom(&self, ram: &mut Ram) -> Result<(), HexError> {
+        for (address, data) in self.segments16()? {
+            ram.register_rom(data, address as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Writes every segment into writable memory.
+    pub fn write_to(&self, ram: &mut Ram) -> Result<(), HexError> {
+        for (address, data) in self.segments16()? {
+            for (offset, byte) in data.iter().enumerate() {
+                ram.poke(address.wrapping_add(offset as u16), *byte)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The image as one block starting at its lowest address, gaps filled with zeros.
+    pub fn flatten(&self) -> Result<(u16, Vec<u8>), HexError> {
+        let segments = self.segments16()?;
+        let Some(origin) = segments.iter().map(|(address, _)| *address).min() else {
+            return Ok((0, Vec::new()));
+        };
+        let end = segments
+            .iter()
+            .map(|(address, data)| *address as usize + data.len())
+            .max()
+            .unwrap_or_default();
+        let mut flat = vec![0; end - origin as usize];
+        for (address, data) in segments {
+            let offset = (address - origin) as usize;
+            flat[offset..offset + data.len()].copy_from_slice(data);
+        }
+        Ok((origin, flat))
+    }
+}
+
+/// Bytes of the record on `line`, checksum checked and excluded.
+fn record(line_number: usize, line: &str) -> Result<Vec<u8>, HexError> {
+    let invalid = || HexError::InvalidRecord(line_number);
+    let digits = line.strip_prefix(':').ok_or_else(invalid)?;
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return Err(invalid());
+    }
+    let mut bytes = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if bytes.len() < 5 {
+        return Err(invalid());
+    }
+    let announced = bytes[0] as usize;
+    if bytes.len() != announced + 5 {
+        return Err(HexError::Length(line_number, bytes.len() - 5, announced));
+    }
+    let found = bytes.pop().unwrap_or_default();
+    let expected = checksum(&bytes);
+    if found != expected {
+        return Err(HexError::Checksum(line_number, found, expected));
+    }
+    Ok(bytes)
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+fn write_record(f: &mut fmt::Formatter, kind: u8, address: u16, data: &[u8]) -> fmt::Result {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    let mut line = String::from(":");
+    for byte in bytes.iter().chain([checksum(&bytes)].iter()) {
+        let _ = write!(line, "{:02X}", byte);
+    }
+    writeln!(f, "{}", line)
+}
+
+/// Writes the image as Intel HEX, 16 data bytes per record, with extended linear address
+/// records only when data lies above 64K.
+impl fmt::Display for HexImage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut base = 0u32;
+        for segment in &self.segments {
+            let mut address = segment.address;
+            let mut data = segment.data.as_slice();
+            while !data.is_empty() {
+                if address >> 16 != base >> 16 {
+                    base = address & 0xffff_0000;
+                    write_record(
+                        f,
+                        EXTENDED_LINEAR_ADDRESS,
+                        0,
+                        &((base >> 16) as u16).to_be_bytes(),
+                    )?;
+                }
+                // Records neither cross a 64K boundary nor get longer than RECORD_LENGTH.
+                let room = 0x10000 - (address & 0xffff) as usize;
+                let (chunk, rest) = data.split_at(data.len().min(RECORD_LENGTH).min(room));
+                write_record(f, DATA, address as u16, chunk)?;
+                address = address.wrapping_add(chunk.len() as u32);
+                data = rest;
+            }
+        }
+        match self.start {
+            Some(start) if start <= 0xffff => {
+                let mut data = vec![0, 0];
+                data.extend((start as u16).to_be_bytes());
+                write_record(f, START_SEGMENT_ADDRESS, 0, &data)?;
+            }
+            Some(start) => write_record(f, START_LINEAR_ADDRESS, 0, &start.to_be_bytes())?,
+            None => {}
+        }
+        write_record(f, END_OF_FILE, 0, &[])
+    }
+}
+
+/// Whether `path` names an Intel HEX file, going by its extension.
+pub fn is_hex_file(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ["hex", "ihx", "ihex"].contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu_state::Ram;
+
+    use super::{HexError, HexImage, Segment};
+
+    #[test]
+    fn reads_records() {
+        let text = "\
+:040100002100207743
+:02010400C90030
+:020000040000FA
+:0400000300000100F8
+:00000001FF
+garbage after the end
+";
+        let image = HexImage::parse(text).unwrap();
+        assert_eq!(
+            image.segments,
+            [Segment {
+                address: 0x100,
+                data: vec![0x21, 0x00, 0x20, 0x77, 0xc9, 0x00],
+            }]
+        );
+        assert_eq!(image.start, Some(0x100));
+
+        let mut ram = Ram::new(0x1000, false);
+        image.register_rom(&mut ram).unwrap();
+        assert_eq!(ram.peek(0x103).unwrap(), 0x77);
+        assert_eq!(image.flatten().unwrap().0, 0x100);
+
+        assert!(matches!(
+            HexImage::parse(":040100002100207744\n:00000001FF"),
+            Err(HexError::Checksum(1, 0x44, 0x43))
+        ));
+        assert!(matches!(
+            HexImage::parse(":040100002100207743"),
+            Err(HexError::MissingEnd)
+        ));
+        assert!(matches!(
+            HexImage::parse(":020000040001F9\n:0100000000FF\n:00000001FF")
+                .unwrap()
+                .flatten(),
+            Err(HexError::OutOfRange(0x10000))
+        ));
+        let mut image = HexImage::new(0, &[0]);
+        image.start = Some(0x12345);
+        assert!(matches!(
+            image.start16(),
+            Err(HexError::StartOutOfRange(0x12345))
+        ));
+    }
+
+    #[test]
+    fn round_trips() {
+        let data = (0..40).collect::<Vec<u8>>();
+        let mut image = HexImage::new(0xfff0, &data[..16]);
+        image.segments.push(Segment {
+            address: 0x20000,
+            data: data[16..].to_vec(),
+        });
+        image.start = Some(0xfff0);
+        let text = image.to_string();
+        assert!(text.starts_with(":10FFF000000102030405060708090A0B0C0D0E0F"));
+        assert!(text.contains(":020000040002F8\n"));
+        assert!(text.ends_with(":040000030000FFF00A\n:00000001FF\n"));
+        assert_eq!(HexImage::parse(&text).unwrap(), image);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt::Write;
+
+use serde_json::{json, Value};
+
+use crate::{
+    disassembler::{Analysis, ListingOptions},
+    op_code::{Instruction, Register, RegisterPair},
+    semantics::Flow,
+    symbols::SymbolTable,
+    syntax::Syntax,
+};
+
+fn register(register: Register) -> Value {
+    json!({ "type": "register", "value": register.to_string() })
+}
+
+fn pair(pair: RegisterPair) -> Value {
+    json!({ "type": "register_pair", "value": pair.to_string() })
+}
+
+fn immediate(value: u16) -> Value {
+    json!({ "type": "immediate", "value": value })
+}
+
+fn address(addr: u16, symbols: &SymbolTable) -> Value {
+    json!({ "type": "address", "value": addr, "symbol": symbols.label(addr) })
+}
+
+/// Operands of `instruction` in Intel order, e.g. destination before source for `MOV`.
+fn operands(instruction: Instruction, symbols: &SymbolTable) -> Vec<Value> {
+    use Instruction::*;
+    match instruction {
+        Mov(d, s) => vec![register(d), register(s)],
+        Mvi(r, n) => vec![register(r), immediate(n as u16)],
+        Lxi(rp, l, h) => vec![pair(rp), immediate(u16::from_le_bytes([l, h]))],
+        Inr(r) | Dcr(r) | Add(r) | Adc(r) | Sub(r) | Sbb(r) | Ana(r) | Xra(r) | Ora(r) | Cmp(r) => {
+            vec![register(r)]
+        }
+        Ldax(rp) | Stax(rp) | Inx(rp) | Dcx(rp) | Dad(rp) | Push(rp) | Pop(rp) => vec![pair(rp)],
+        Adi(n) | Aci(n) | Sui(n) | Sbi(n) | Ani(n) | Xri(n) | Ori(n) | Cpi(n) | In(n) | Out(n) => {
+            vec![immediate(n as u16)]
+        }
+        Rst(n) => vec![address(n as u16 * 8, symbols)],
+        instruction => instruction
+            .address_operand()
+            .map(|addr| address(addr, symbols))
+            .into_iter()
+            .collect(),
+    }
+}
+
+fn flow_name(flow: Flow) -> &'static str {
+    match flow {
+        Flow::Jump | Flow::ConditionalJump | Flow::IndirectJump => "jump",
+        Flow::Call | Flow::ConditionalCall => "call",
+        Flow::Return | Flow::ConditionalReturn => "ret",
+        Flow::Fallthrough | Flow::Halt => "fallthrough",
+    }
+}
+
+/// JSON description of `instruction`, whose encoding `bytes` starts at `addr`. Mnemonic and
+/// operands are Intel's; `syntax` only applies to the rendered `text`.
+pub fn instruction(
+    addr: u16,
+    instruction: Instruction,
+    bytes: &[u8],
+    symbols: &SymbolTable,
+    syntax: Syntax,
+) -> Value {
+    let flow = instruction.flow();
+    // Conditional calls and returns take 6 more cycles when the condition holds.
+    let taken = match flow {
+        Flow::ConditionalCall | Flow::ConditionalReturn => instruction.cycles() + 6,
+        _ => instruction.cycles(),
+    };
+    json!({
+        "address": addr,
+        "symbol": symbols.label(addr),
+        "bytes": bytes,
+        "mnemonic": instruction.mnemonic(),
+        "operands": operands(instruction, symbols),
+        "text": symbols.annotate(instruction.display().syntax(syntax)),
+        "size": instruction.size(),
+        "cycles": instruction.cycles(),
+        "cycles_taken": taken,
+        "flow": flow_name(flow),
+        "conditional": flow.is_conditional(),
+        "target": instruction.branch_target(),
+    })
+}
+
+/// One JSON object per line for every instruction `analysis` decoded between
+/// `options.start` and `options.end`, in the order of the ROM.
+pub fn listing(
+    analysis: &Analysis,
+    rom: &[u8],
+    symbols: &SymbolTable,
+    options: &ListingOptions,
+) -> String {
+    // In ROM order, as the text listing, which is not address order when the ROM wraps
+    // around 0xffff.
+    let offset_of = |addr: u16| addr.wrapping_sub(analysis.origin()) as usize;
+    let first = options.start.map_or(0, offset_of);
+    let last = options.end.map_or(usize::MAX, offset_of);
+    let mut instructions = analysis
+        .instructions()
+        .filter(|(addr, _)| (first..=last).contains(&offset_of(*addr)))
+        .collect::<Vec<_>>();
+    instructions.sort_by_key(|(addr, _)| offset_of(*addr));
+    let mut out = String::new();
+    for (addr, decoded) in instructions {
+        let offset = offset_of(addr);
+        let bytes = &rom[offset..offset + decoded.size() as usize];
+        let _ = writeln!(
+            out,
+            "{}",
+            instruction(addr, decoded, bytes, symbols, options.syntax)
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::{disassembler::ListingOptions, i8080, symbols::SymbolTable, testing};
+
+    fn parse(listing: &str) -> Vec<Value> {
+        listing
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn typed_operands_and_flow() {
+        let rom = i8080! {
+            start: LXI H,2000H
+            MOV M,A
+            CNZ start
+            RET
+        };
+        let analysis = testing::analyse(&rom, 0);
+        let symbols = SymbolTable::parse("0000 start").unwrap();
+        let listing = super::listing(&analysis, &rom, &symbols, &ListingOptions::default());
+        let lines = parse(&listing);
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0]["operands"],
+            json!([
+                { "type": "register_pair", "value": "H" },
+                { "type": "immediate", "value": 0x2000 },
+            ])
+        );
+        assert_eq!(lines[0]["bytes"], json!([0x21, 0x00, 0x20]));
+        assert_eq!(lines[1]["flow"], "fallthrough");
+        assert_eq!(
+            lines[2],
+            json!({
+                "address": 4,
+                "symbol": null,
+                "bytes": [0xc4, 0x00, 0x00],
+                "mnemonic": "CNZ",
+                "operands": [{ "type": "address", "value": 0, "symbol": "start" }],
+                "text": "CNZ 0000H  ; start",
+                "size": 3,
+                "cycles": 11,
+                "cycles_taken": 17,
+                "flow": "call",
+                "conditional": true,
+                "target": 0,
+            })
+        );
+        assert_eq!(lines[3]["flow"], "ret");
+        assert_eq!(lines[3]["conditional"], false);
+    }
+
+    #[test]
+    fn empty_rom() {
+        let analysis = testing::analyse(&[], 0);
+        let listing = super::listing(
+            &analysis,
+            &[],
+            &SymbolTable::new(),
+            &ListingOptions::default(),
+        );
+        assert_eq!(listing, "");
+    }
+
+    #[test]
+    fn rst_across_the_end_of_memory() {
+        // LXI starts at 0xffff and ends at 0x0001.
+        let rom = i8080! {
+            NOP
+            LXI H,0
+            RST 1
+        };
+        let analysis = testing::analyse(&rom, 0xfffe);
+        let symbols = SymbolTable::new();
+        let listing = super::listing(&analysis, &rom, &symbols, &ListingOptions::default());
+        let lines = parse(&listing);
+        let addresses = lines
+            .iter()
+            .map(|line| &line["address"])
+            .collect::<Vec<_>>();
+        assert_eq!(addresses, [0xfffe, 0xffff, 0x0002]);
+        assert_eq!(lines[1]["bytes"], json!([0x21, 0x00, 0x00]));
+        assert_eq!(
+            lines[2]["operands"],
+            json!([{ "type": "address", "value": 8, "symbol": null }])
+        );
+        assert_eq!(lines[2]["flow"], "call");
+        assert_eq!(lines[2]["target"], 8);
+
+        let options = ListingOptions {
+            start: Some(0xffff),
+            end: Some(0x0001),
+            ..ListingOptions::default()
+        };
+        let listing = super::listing(&analysis, &rom, &symbols, &options);
+        assert_eq!(parse(&listing).len(), 1);
+    }
+}
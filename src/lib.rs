@@ -1,9 +1,32 @@
-#![feature(generic_arg_infer)]
-
+pub mod assembler;
+pub mod call_stack;
+pub mod cli;
+pub mod coverage;
+pub mod cpm;
+pub mod cpm_disk;
+pub mod cpm_files;
+pub mod cpm_machine;
 pub mod cpu_state;
+pub mod debugger;
+pub mod disassembler;
+pub mod flow_graph;
 pub mod in_out;
+pub mod intel_hex;
 pub mod interrupts;
+pub mod json_listing;
 pub mod op_code;
+pub mod profiler;
+pub mod semantics;
+pub mod single_step;
+pub mod space_invaders;
+pub mod symbols;
+pub mod syntax;
+pub mod xref;
+
+pub use i8080_macro::i8080;
+
+#[cfg(test)]
+mod testing;
 
 #[cfg(target_arch = "wasm32")]
 mod wasm;
@@ -312,6 +312,169 @@ impl Instruction {
             | Rst(_) => 1,
         }
     }
+
+    /// The 16 bit address or immediate encoded in the instruction, if any.
+    pub fn address_operand(self) -> Option<u16> {
+        use Instruction::*;
+        match self {
+            Lxi(_, l, h) => Some(((h as u16) << 8) | (l as u16)),
+            Shld(addr) | Lhld(addr) | Sta(addr) | Lda(addr) | Jnz(addr) | Jmp(addr) | Cnz(addr)
+            | Jz(addr) | Cz(addr) | Call(addr) | Jnc(addr) | Cnc(addr) | Jc(addr) | Cc(addr)
+            | Jpo(addr) | Cpo(addr) | Jpe(addr) | Cpe(addr) | Jp(addr) | Cp(addr) | Jm(addr)
+            | Cm(addr) => Some(addr),
+            _ => None,
+        }
+    }
+}
+
+impl Instruction {
+    /// Machine code of the instruction, or `None` for operands the 8080 cannot encode (e.g.
+    /// `PUSH SP` or register `F`).
+    pub fn encode(self) -> Option<Vec<u8>> {
+        use Instruction::*;
+        let r = register_code;
+        let op_code = match self {
+            // Would be `HLT`.
+            Mov(Register::M, Register::M) => return None,
+            Mov(d, s) => 0x40 | r(d)? << 3 | r(s)?,
+            Mvi(d, _) => 0x06 | r(d)? << 3,
+            Inr(d) => 0x04 | r(d)? << 3,
+            Dcr(d) => 0x05 | r(d)? << 3,
+            Add(s) => 0x80 | r(s)?,
+            Adc(s) => 0x88 | r(s)?,
+            Sub(s) => 0x90 | r(s)?,
+            Sbb(s) => 0x98 | r(s)?,
+            Ana(s) => 0xa0 | r(s)?,
+            Xra(s) => 0xa8 | r(s)?,
+            Ora(s) => 0xb0 | r(s)?,
+            Cmp(s) => 0xb8 | r(s)?,
+            Lxi(rp, _, _) => 0x01 | pair_code(rp, RegisterPair::SP)? << 4,
+            Inx(rp) => 0x03 | pair_code(rp, RegisterPair::SP)? << 4,
+            Dcx(rp) => 0x0b | pair_code(rp, RegisterPair::SP)? << 4,
+            Dad(rp) => 0x09 | pair_code(rp, RegisterPair::SP)? << 4,
+            Push(rp) => 0xc5 | pair_code(rp, RegisterPair::PSW)? << 4,
+            Pop(rp) => 0xc1 | pair_code(rp, RegisterPair::PSW)? << 4,
+            Stax(rp @ (RegisterPair::B | RegisterPair::D)) => 0x02 | pair_code(rp, rp)? << 4,
+            Ldax(rp @ (RegisterPair::B | RegisterPair::D)) => 0x0a | pair_code(rp, rp)? << 4,
+            Stax(_) | Ldax(_) => return None,
+            Rst(n) if n < 8 => 0xc7 | n << 3,
+            Rst(_) => return None,
+            Adi(_) => 0xc6,
+            Aci(_) => 0xce,
+            Sui(_) => 0xd6,
+            Sbi(_) => 0xde,
+            Ani(_) => 0xe6,
+            Xri(_) => 0xee,
+            Ori(_) => 0xf6,
+            Cpi(_) => 0xfe,
+            Out(_) => 0xd3,
+            In(_) => 0xdb,
+            Shld(_) => 0x22,
+            Lhld(_) => 0x2a,
+            Sta(_) => 0x32,
+            Lda(_) => 0x3a,
+            Jmp(_) => 0xc3,
+            Jnz(_) => 0xc2,
+            Jz(_) => 0xca,
+            Jnc(_) => 0xd2,
+            Jc(_) => 0xda,
+            Jpo(_) => 0xe2,
+            Jpe(_) => 0xea,
+            Jp(_) => 0xf2,
+            Jm(_) => 0xfa,
+            Call(_) => 0xcd,
+            Cnz(_) => 0xc4,
+            Cz(_) => 0xcc,
+            Cnc(_) => 0xd4,
+            Cc(_) => 0xdc,
+            Cpo(_) => 0xe4,
+            Cpe(_) => 0xec,
+            Cp(_) => 0xf4,
+            Cm(_) => 0xfc,
+            Ret => 0xc9,
+            Rnz => 0xc0,
+            Rz => 0xc8,
+            Rnc => 0xd0,
+            Rc => 0xd8,
+            Rpo => 0xe0,
+            Rpe => 0xe8,
+            Rp => 0xf0,
+            Rm => 0xf8,
+            Nop => 0x00,
+            Rlc => 0x07,
+            Rrc => 0x0f,
+            Ral => 0x17,
+            Rar => 0x1f,
+            Daa => 0x27,
+            Cma => 0x2f,
+            Stc => 0x37,
+            Cmc => 0x3f,
+            Hlt => 0x76,
+            Xthl => 0xe3,
+            Pchl => 0xe9,
+            Xchg => 0xeb,
+            Di => 0xf3,
+            Sphl => 0xf9,
+            Ei => 0xfb,
+        };
+
+        let mut bytes = vec![op_code];
+        match self {
+            Mvi(_, n)
+            | Adi(n)
+            | Aci(n)
+            | Sui(n)
+            | Sbi(n)
+            | Ani(n)
+            | Xri(n)
+            | Ori(n)
+            | Cpi(n)
+            | Out(n)
+            | In(n) => bytes.push(n),
+            Lxi(_, lo, hi) => bytes.extend([lo, hi]),
+            instruction => {
+                if let Some(addr) = instruction.address_operand() {
+                    bytes.extend(addr.to_le_bytes());
+                }
+            }
+        }
+        Some(bytes)
+    }
+}
+
+fn register_code(register: Register) -> Option<u8> {
+    Some(match register {
+        Register::B => 0,
+        Register::C => 1,
+        Register::D => 2,
+        Register::E => 3,
+        Register::H => 4,
+        Register::L => 5,
+        Register::M => 6,
+        Register::A => 7,
+        Register::F => return None,
+    })
+}
+
+/// Encoding of a register pair, `last` being the pair allowed in the fourth slot (`SP` or
+/// `PSW` depending on the instruction).
+fn pair_code(pair: RegisterPair, last: RegisterPair) -> Option<u8> {
+    Some(match pair {
+        RegisterPair::B => 0,
+        RegisterPair::D => 1,
+        RegisterPair::H => 2,
+        pair if pair == last => 3,
+        _ => return None,
+    })
+}
+
+/// Opcodes missing from the 8080 documentation. They decode as `Nop` here, although the
+/// hardware runs some of them as aliases of `JMP`, `CALL` and `RET`.
+pub fn is_undocumented(op_code: u8) -> bool {
+    matches!(
+        op_code,
+        0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xcb | 0xd9 | 0xdd | 0xed | 0xfd
+    )
 }
 
 fn two_arg_op_code(op_code: u8, arg1: u8, arg2: u8) -> Instruction {
@@ -830,3 +993,23 @@ fn op_code_to_argsize(op_code: u8) -> Result<usize, OpCodeError> {
         //x => return Err(OpCodeError::WrongInstruction(x)),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{is_undocumented, Instruction};
+
+    #[test]
+    fn encode_round_trips() {
+        for op_code in (0..=0xffu8).filter(|op_code| !is_undocumented(*op_code)) {
+            let bytes = [op_code, 0x34, 0x12];
+            let instruction = Instruction::read_at(&bytes, 0).unwrap();
+            let encoded = instruction.encode().unwrap();
+            assert_eq!(
+                encoded,
+                bytes[..instruction.size() as usize],
+                "{}",
+                instruction
+            );
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::{cpu_state::stack_above, op_code::Instruction, symbols::SymbolTable};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counter {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+impl Counter {
+    fn add(&mut self, cycles: u8) {
+        self.count += 1;
+        self.cycles += cycles as u64;
+    }
+}
+
+/// Time spent in a subroutine, identified by its entry point.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubroutineStats {
+    /// Number of times the subroutine was entered.
+    pub calls: u64,
+    /// Instructions and cycles executed by the subroutine itself.
+    pub own: Counter,
+    /// Cycles executed by the subroutine and everything it called.
+    pub total_cycles: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    entry: u16,
+    /// Stack pointer right after the return address was pushed.
+    sp: u16,
+}
+
+/// Accumulates instruction counts and cycles per address and per subroutine.
+///
+/// Subroutines are tracked from the `CALL`s, `RST`s and interrupts that enter them; a frame
+/// is left as soon as the stack pointer moves above its return address, so returns through
+/// `POP`/`PCHL` or stack resets are handled as well as plain `RET`s. Code running outside of
+/// any call is attributed to the entry point the profiler was started from.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    by_addr: Vec<Counter>,
+    subroutines: BTreeMap<u16, SubroutineStats>,
+    stack: Vec<Frame>,
+    root: Option<u16>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            by_addr: vec![Counter::default(); 0x10000],
+            subroutines: BTreeMap::new(),
+            stack: Vec::new(),
+            root: None,
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an instruction executed at `pc`. `sp_before`/`sp_after` and `pc_after` are the
+    /// CPU state around its execution, and are used to follow calls and returns.
+    pub fn record(
+        &mut self,
+        pc: u16,
+        instruction: Instruction,
+        cycles: u8,
+        sp_before: u16,
+        sp_after: u16,
+        pc_after: u16,
+    ) {
+        self.by_addr[pc as usize].add(cycles);
+        let root = *self.root.get_or_insert(pc);
+        self.charge(root, cycles);
+
+        while self
+            .stack
+            .last()
+            .is_some_and(|frame| stack_above(sp_after, frame.sp))
+        {
+            self.stack.pop();
+        }
+        if instruction.is_call() && sp_after == sp_before.wrapping_sub(2) {
+            self.enter(pc_after, sp_after);
+        }
+    }
+
+    /// Records an interrupt that pushed the program counter and jumped to `vector`.
+    pub fn record_interrupt(&mut self, vector: u16, cycles: u8, sp_after: u16) {
+        let root = *self.root.get_or_insert(vector);
+        self.charge(root, cycles);
+        self.enter(vector, sp_after);
+    }
+
+    fn enter(&mut self, entry: u16, sp: u16) {
+        self.subroutines.entry(entry).or_default().calls += 1;
+        self.stack.push(Frame { entry, sp });
+    }
+
+    fn charge(&mut self, root: u16, cycles: u8) {
+        let current = self.stack.last().map_or(root, |frame| frame.entry);
+        self.subroutines.entry(current).or_default().own.add(cycles);
+
+        let mut seen = Vec::with_capacity(self.stack.len() + 1);
+        for entry in std::iter::once(root).chain(self.stack.iter().map(|frame| frame.entry)) {
+            if !seen.contains(&entry) {
+                seen.push(entry);
+                self.subroutines.entry(entry).or_default().total_cycles += cycles as u64;
+            }
+        }
+    }
+
+    pub fn at(&self, addr: u16) -> Counter {
+        self.by_addr[addr as usize]
+    }
+
+    pub fn subroutines(&self) -> &BTreeMap<u16, SubroutineStats> {
+        &self.subroutines
+    }
+
+    pub fn total(&self) -> Counter {
+        self.by_addr
+            .iter()
+            .fold(Counter::default(), |acc, c| Counter {
+                count: acc.count + c.count,
+                cycles: acc.cycles + c.cycles,
+            })
+    }
+
+    /// Addresses sorted by decreasing cycle count, at most `n` of them.
+    pub fn hot_spots(&self, n: usize) -> Vec<(u16, Counter)> {
+        let mut spots = self
+            .by_addr
+            .iter()
+            .enumerate()
+            .filter(|(_, counter)| counter.count > 0)
+            .map(|(addr, counter)| (addr as u16, *counter))
+            .collect::<Vec<_>>();
+        spots.sort_by(|(a, ca), (b, cb)| cb.cycles.cmp(&ca.cycles).then(a.cmp(b)));
+        spots.truncate(n);
+        spots
+    }
+
+    /// Text report of the `n` hottest addresses, disassembled from `memory`, and the `n`
+    /// subroutines with the most cycles spent in them or their callees.
+    pub fn report(&self, memory: &[u8], symbols: &SymbolTable, n: usize) -> String {
+        let total = self.total();
+        let percent = |cycles: u64| 100.0 * cycles as f64 / total.cycles.max(1) as f64;
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{} instructions, {} cycles.",
+            total.count, total.cycles
+        );
+
+        let _ = writeln!(out, "\nHot spots:");
+        let _ = writeln!(
+            out,
+            "{:>6} {:>12} {:>12}  {:<20} instruction",
+            "%", "cycles", "count", "address"
+        );
+        for (addr, counter) in self.hot_spots(n) {
+            let instruction = match Instruction::read_at(memory, addr) {
+                Ok(instruction) => symbols.annotate(instruction),
+                Err(e) => e.to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "{:>6.2} {:>12} {:>12}  {:<20} {}",
+                percent(counter.cycles),
+                counter.cycles,
+                counter.count,
+                format!("{:04x} {}", addr, symbols.label(addr).unwrap_or_default()),
+                instruction
+            );
+        }
+
+        let mut subroutines = self.subroutines.iter().collect::<Vec<_>>();
+        subroutines
+            .sort_by(|(a, sa), (b, sb)| sb.total_cycles.cmp(&sa.total_cycles).then(a.cmp(b)));
+        let _ = writeln!(out, "\nSubroutines:");
+        let _ = writeln!(
+            out,
+            "{:>6} {:>12} {:>6} {:>12} {:>10}  entry",
+            "%", "total", "self%", "self", "calls"
+        );
+        for (entry, stats) in subroutines.into_iter().take(n) {
+            let _ = writeln!(
+                out,
+                "{:>6.2} {:>12} {:>6.2} {:>12} {:>10}  {:04x} {}",
+                percent(stats.total_cycles),
+                stats.total_cycles,
+                percent(stats.own.cycles),
+                stats.own.cycles,
+                stats.calls,
+                entry,
+                symbols.label(*entry).unwrap_or_default()
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{i8080, in_out::DummyInOut, op_code::Instruction, symbols::SymbolTable, testing};
+
+    use super::{Counter, Profiler};
+
+    #[test]
+    fn per_address_and_subroutine() {
+        let program = i8080! {
+            LXI SP,0800H
+            CALL work; CALL work
+            HLT
+            work: NOP; RET
+        };
+        let mut system = testing::load(&program, 0);
+        system.enable_profiling();
+        testing::run(&mut system);
+
+        let profiler = system.profiler().unwrap();
+        assert_eq!(profiler.at(0x0a).count, 2);
+        assert_eq!(profiler.at(0x03).cycles, 17);
+        assert_eq!(profiler.total().count, 7);
+
+        let subroutine = profiler.subroutines()[&0x0a];
+        assert_eq!(subroutine.calls, 2);
+        assert_eq!(subroutine.own.cycles, 2 * (4 + 10));
+        let root = profiler.subroutines()[&0x00];
+        assert_eq!(root.total_cycles, profiler.total().cycles);
+        assert_eq!(
+            profiler.hot_spots(1)[0],
+            (
+                0x0b,
+                Counter {
+                    count: 2,
+                    cycles: 20
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn nothing_recorded() {
+        let profiler = Profiler::new();
+        assert_eq!(profiler.total(), Counter::default());
+        assert!(profiler.hot_spots(10).is_empty());
+        assert!(profiler.subroutines().is_empty());
+        let report = profiler.report(&[], &SymbolTable::new(), 10);
+        assert!(report.starts_with("0 instructions, 0 cycles."));
+    }
+
+    #[test]
+    fn wraps_around_memory() {
+        // The program runs from 0xfffc into 0, and its stack from 0 down to 0xfffe.
+        let program = i8080! {
+            LXI SP,0
+            NOP
+            CALL 0200H
+            NOP
+            HLT
+        };
+        let mut system = testing::load(&program, 0xfffc);
+        system.ram_mut().poke(0x0200, 0x00).unwrap();
+        system.ram_mut().poke(0x0201, 0xc9).unwrap();
+        system.enable_profiling();
+        testing::run(&mut system);
+
+        let profiler = system.profiler().unwrap();
+        assert_eq!(profiler.at(0xffff).count, 1);
+        assert_eq!(profiler.at(0x0000).count, 1);
+        let subroutine = profiler.subroutines()[&0x0200];
+        assert_eq!(subroutine.calls, 1);
+        assert_eq!(subroutine.own.count, 2);
+        // RET took SP from 0xfffe back to 0, so the last NOP runs in the caller again.
+        assert_eq!(profiler.subroutines()[&0xfffc].own.count, 4);
+    }
+
+    #[test]
+    fn rst_and_interrupts_enter_subroutines() {
+        let program = i8080! {
+            JMP start
+            ORG 8
+            RET
+            ORG 10H
+            EI; RET
+            start: LXI SP,0800H
+            RST 1
+            EI
+            HLT
+        };
+        let mut system = testing::load(&program, 0);
+        system.enable_profiling();
+        testing::step(&mut system, 5);
+        system.process(Instruction::Rst(2), &DummyInOut).unwrap();
+        testing::run(&mut system);
+
+        let profiler = system.profiler().unwrap();
+        assert_eq!(profiler.subroutines()[&0x08].calls, 1);
+        let handler = profiler.subroutines()[&0x10];
+        assert_eq!(handler.calls, 1);
+        assert_eq!(handler.own.count, 2);
+        // The interrupt's own cycles are charged to the interrupted code.
+        assert_eq!(handler.total_cycles, 4 + 10);
+    }
+}
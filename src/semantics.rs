@@ -0,0 +1,432 @@
+use std::ops::BitOr;
+
+use crate::op_code::{Instruction, Register, RegisterPair};
+
+/// Set of 8080 registers, `SP` included. The flags are tracked separately by [`Flags`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers(u8);
+
+impl Registers {
+    pub const NONE: Registers = Registers(0);
+    pub const A: Registers = Registers(1 << 0);
+    pub const B: Registers = Registers(1 << 1);
+    pub const C: Registers = Registers(1 << 2);
+    pub const D: Registers = Registers(1 << 3);
+    pub const E: Registers = Registers(1 << 4);
+    pub const H: Registers = Registers(1 << 5);
+    pub const L: Registers = Registers(1 << 6);
+    pub const SP: Registers = Registers(1 << 7);
+
+    /// Registers behind `register`, `M` standing for `H` and `L` used as a pointer.
+    pub fn of(register: Register) -> Registers {
+        match register {
+            Register::A => Registers::A,
+            Register::B => Registers::B,
+            Register::C => Registers::C,
+            Register::D => Registers::D,
+            Register::E => Registers::E,
+            Register::H => Registers::H,
+            Register::L => Registers::L,
+            Register::M => Registers::H | Registers::L,
+            Register::F => Registers::NONE,
+        }
+    }
+
+    /// Registers making up `pair`; the flags half of `PSW` is left to [`Flags`].
+    pub fn pair(pair: RegisterPair) -> Registers {
+        match pair {
+            RegisterPair::PSW => Registers::A,
+            RegisterPair::B => Registers::B | Registers::C,
+            RegisterPair::D => Registers::D | Registers::E,
+            RegisterPair::H => Registers::H | Registers::L,
+            RegisterPair::SP => Registers::SP,
+        }
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, other: Registers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other: Registers) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Registers {
+    type Output = Registers;
+
+    fn bitor(self, other: Registers) -> Registers {
+        Registers(self.0 | other.0)
+    }
+}
+
+/// Set of condition flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const NONE: Flags = Flags(0);
+    pub const SIGN: Flags = Flags(1 << 0);
+    pub const ZERO: Flags = Flags(1 << 1);
+    pub const AUX_CARRY: Flags = Flags(1 << 2);
+    pub const PARITY: Flags = Flags(1 << 3);
+    pub const CARRY: Flags = Flags(1 << 4);
+    pub const ALL: Flags = Flags(0x1f);
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
+}
+
+/// Directions of a memory or I/O access.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Access(u8);
+
+impl Access {
+    pub const NONE: Access = Access(0);
+    pub const READ: Access = Access(1 << 0);
+    pub const WRITE: Access = Access(1 << 1);
+
+    pub fn contains(self, other: Access) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Access {
+    type Output = Access;
+
+    fn bitor(self, other: Access) -> Access {
+        Access(self.0 | other.0)
+    }
+}
+
+/// What an instruction may read and modify besides the program counter. Conditional calls and
+/// returns report the effects of the taken branch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Effects {
+    pub reads: Registers,
+    pub writes: Registers,
+    pub flags_read: Flags,
+    pub flags_written: Flags,
+    /// Data memory accesses, stack included; instruction fetches are not counted.
+    pub memory: Access,
+    pub io: Access,
+}
+
+impl Effects {
+    fn read(&mut self, register: Register) {
+        self.reads = self.reads | Registers::of(register);
+        if register == Register::M {
+            self.memory = self.memory | Access::READ;
+        }
+    }
+
+    fn write(&mut self, register: Register) {
+        if register == Register::M {
+            self.reads = self.reads | Registers::of(register);
+            self.memory = self.memory | Access::WRITE;
+        } else {
+            self.writes = self.writes | Registers::of(register);
+        }
+    }
+
+    fn stack(&mut self, access: Access) {
+        self.reads = self.reads | Registers::SP;
+        self.writes = self.writes | Registers::SP;
+        self.memory = self.memory | access;
+    }
+}
+
+/// How an instruction passes control on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Continues with the next instruction.
+    Fallthrough,
+    Jump,
+    ConditionalJump,
+    /// `CALL` and `RST`.
+    Call,
+    ConditionalCall,
+    Return,
+    ConditionalReturn,
+    /// `PCHL`, whose target is only known at run time.
+    IndirectJump,
+    /// `HLT`, which resumes with the next instruction after an interrupt.
+    Halt,
+}
+
+impl Flow {
+    pub fn is_conditional(self) -> bool {
+        matches!(
+            self,
+            Flow::ConditionalJump | Flow::ConditionalCall | Flow::ConditionalReturn
+        )
+    }
+
+    /// Whether the next instruction can be reached without coming back from a call.
+    pub fn falls_through(self) -> bool {
+        !matches!(self, Flow::Jump | Flow::Return | Flow::IndirectJump)
+    }
+}
+
+impl Instruction {
+    /// Registers, flags, memory and I/O read or written by the instruction.
+    pub fn effects(self) -> Effects {
+        use Instruction::*;
+        let mut effects = Effects::default();
+        let flow = self.flow();
+        match self {
+            Mov(d, s) => {
+                effects.read(s);
+                effects.write(d);
+            }
+            Mvi(r, _) => effects.write(r),
+            Lxi(rp, _, _) => effects.writes = Registers::pair(rp),
+            Lda(_) => {
+                effects.writes = Registers::A;
+                effects.memory = Access::READ;
+            }
+            Sta(_) => {
+                effects.reads = Registers::A;
+                effects.memory = Access::WRITE;
+            }
+            Lhld(_) => {
+                effects.writes = Registers::H | Registers::L;
+                effects.memory = Access::READ;
+            }
+            Shld(_) => {
+                effects.reads = Registers::H | Registers::L;
+                effects.memory = Access::WRITE;
+            }
+            Ldax(rp) => {
+                effects.reads = Registers::pair(rp);
+                effects.writes = Registers::A;
+                effects.memory = Access::READ;
+            }
+            Stax(rp) => {
+                effects.reads = Registers::pair(rp) | Registers::A;
+                effects.memory = Access::WRITE;
+            }
+            Xchg => {
+                let pairs = Registers::pair(RegisterPair::D) | Registers::pair(RegisterPair::H);
+                effects.reads = pairs;
+                effects.writes = pairs;
+            }
+            Add(r) | Adc(r) | Sub(r) | Sbb(r) | Ana(r) | Xra(r) | Ora(r) | Cmp(r) => {
+                effects.read(Register::A);
+                effects.read(r);
+                if !matches!(self, Cmp(_)) {
+                    effects.write(Register::A);
+                }
+                effects.flags_written = Flags::ALL;
+            }
+            Adi(_) | Aci(_) | Sui(_) | Sbi(_) | Ani(_) | Xri(_) | Ori(_) | Cpi(_) => {
+                effects.read(Register::A);
+                if !matches!(self, Cpi(_)) {
+                    effects.write(Register::A);
+                }
+                effects.flags_written = Flags::ALL;
+            }
+            Inr(r) | Dcr(r) => {
+                effects.read(r);
+                effects.write(r);
+                effects.flags_written =
+                    Flags::SIGN | Flags::ZERO | Flags::AUX_CARRY | Flags::PARITY;
+            }
+            Inx(rp) | Dcx(rp) => {
+                effects.reads = Registers::pair(rp);
+                effects.writes = Registers::pair(rp);
+            }
+            Dad(rp) => {
+                effects.reads = Registers::pair(rp) | Registers::pair(RegisterPair::H);
+                effects.writes = Registers::pair(RegisterPair::H);
+                effects.flags_written = Flags::CARRY;
+            }
+            Daa => {
+                effects.reads = Registers::A;
+                effects.writes = Registers::A;
+                effects.flags_read = Flags::CARRY | Flags::AUX_CARRY;
+                effects.flags_written = Flags::ALL;
+            }
+            Cma => {
+                effects.reads = Registers::A;
+                effects.writes = Registers::A;
+            }
+            Rlc | Rrc | Ral | Rar => {
+                effects.reads = Registers::A;
+                effects.writes = Registers::A;
+                effects.flags_written = Flags::CARRY;
+            }
+            Stc => effects.flags_written = Flags::CARRY,
+            Cmc => {
+                effects.flags_read = Flags::CARRY;
+                effects.flags_written = Flags::CARRY;
+            }
+            Push(rp) => {
+                effects.reads = Registers::pair(rp);
+                effects.stack(Access::WRITE);
+                if rp == RegisterPair::PSW {
+                    effects.flags_read = Flags::ALL;
+                }
+            }
+            Pop(rp) => {
+                effects.writes = Registers::pair(rp);
+                effects.stack(Access::READ);
+                if rp == RegisterPair::PSW {
+                    effects.flags_written = Flags::ALL;
+                }
+            }
+            // Exchanges HL with the top of the stack, leaving SP as it is.
+            Xthl => {
+                effects.reads = Registers::pair(RegisterPair::H) | Registers::SP;
+                effects.writes = Registers::pair(RegisterPair::H);
+                effects.memory = Access::READ | Access::WRITE;
+            }
+            Sphl => {
+                effects.reads = Registers::pair(RegisterPair::H);
+                effects.writes = Registers::SP;
+            }
+            Pchl => effects.reads = Registers::pair(RegisterPair::H),
+            In(_) => {
+                effects.writes = Registers::A;
+                effects.io = Access::READ;
+            }
+            Out(_) => {
+                effects.reads = Registers::A;
+                effects.io = Access::WRITE;
+            }
+            _ if matches!(flow, Flow::Call | Flow::ConditionalCall) => effects.stack(Access::WRITE),
+            _ if matches!(flow, Flow::Return | Flow::ConditionalReturn) => {
+                effects.stack(Access::READ)
+            }
+            _ => {}
+        }
+        effects.flags_read = effects.flags_read | self.condition();
+        if matches!(self, Adc(_) | Sbb(_) | Aci(_) | Sbi(_) | Ral | Rar) {
+            effects.flags_read = effects.flags_read | Flags::CARRY;
+        }
+        effects
+    }
+
+    /// Flag tested by a conditional jump, call or return.
+    fn condition(self) -> Flags {
+        use Instruction::*;
+        match self {
+            Jnz(_) | Jz(_) | Cnz(_) | Cz(_) | Rnz | Rz => Flags::ZERO,
+            Jnc(_) | Jc(_) | Cnc(_) | Cc(_) | Rnc | Rc => Flags::CARRY,
+            Jpo(_) | Jpe(_) | Cpo(_) | Cpe(_) | Rpo | Rpe => Flags::PARITY,
+            Jp(_) | Jm(_) | Cp(_) | Cm(_) | Rp | Rm => Flags::SIGN,
+            _ => Flags::NONE,
+        }
+    }
+
+    pub fn flow(self) -> Flow {
+        use Instruction::*;
+        match self {
+            Jmp(_) => Flow::Jump,
+            Jnz(_) | Jz(_) | Jnc(_) | Jc(_) | Jpo(_) | Jpe(_) | Jp(_) | Jm(_) => {
+                Flow::ConditionalJump
+            }
+            Call(_) | Rst(_) => Flow::Call,
+            Cnz(_) | Cz(_) | Cnc(_) | Cc(_) | Cpo(_) | Cpe(_) | Cp(_) | Cm(_) => {
+                Flow::ConditionalCall
+            }
+            Ret => Flow::Return,
+            Rnz | Rz | Rnc | Rc | Rpo | Rpe | Rp | Rm => Flow::ConditionalReturn,
+            Pchl => Flow::IndirectJump,
+            Hlt => Flow::Halt,
+            _ => Flow::Fallthrough,
+        }
+    }
+
+    /// Destination of a jump or call known without running the code, `RST` vectors included.
+    pub fn branch_target(self) -> Option<u16> {
+        match (self, self.flow()) {
+            (Instruction::Rst(n), _) => Some(n as u16 * 8),
+            (_, Flow::Jump | Flow::ConditionalJump | Flow::Call | Flow::ConditionalCall) => {
+                self.address_operand()
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction pushes a return address, conditionally or not.
+    pub fn is_call(self) -> bool {
+        matches!(self.flow(), Flow::Call | Flow::ConditionalCall)
+    }
+
+    /// Whether the instruction pops a return address, conditionally or not.
+    pub fn is_return(self) -> bool {
+        matches!(self.flow(), Flow::Return | Flow::ConditionalReturn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, Flags, Flow, Registers};
+    use crate::op_code::{Instruction, Register, RegisterPair};
+
+    #[test]
+    fn effects_and_flow() {
+        let add = Instruction::Adc(Register::M).effects();
+        assert_eq!(add.reads, Registers::A | Registers::H | Registers::L);
+        assert_eq!(add.writes, Registers::A);
+        assert_eq!(add.flags_read, Flags::CARRY);
+        assert_eq!(add.flags_written, Flags::ALL);
+        assert_eq!(add.memory, Access::READ);
+
+        let store = Instruction::Mov(Register::M, Register::B).effects();
+        assert_eq!(store.reads, Registers::B | Registers::H | Registers::L);
+        assert!(store.writes.is_empty());
+        assert_eq!(store.memory, Access::WRITE);
+
+        let pop = Instruction::Pop(RegisterPair::PSW).effects();
+        assert_eq!(pop.writes, Registers::A | Registers::SP);
+        assert_eq!(pop.flags_written, Flags::ALL);
+
+        let xthl = Instruction::Xthl.effects();
+        assert_eq!(xthl.reads, Registers::H | Registers::L | Registers::SP);
+        assert_eq!(xthl.writes, Registers::H | Registers::L);
+        assert_eq!(xthl.memory, Access::READ | Access::WRITE);
+
+        let call = Instruction::Cnz(0x1234);
+        assert_eq!(call.flow(), Flow::ConditionalCall);
+        assert_eq!(call.branch_target(), Some(0x1234));
+        assert_eq!(call.effects().flags_read, Flags::ZERO);
+        assert_eq!(call.effects().memory, Access::WRITE);
+
+        assert_eq!(Instruction::Rst(7).branch_target(), Some(0x38));
+        assert_eq!(Instruction::Out(1).effects().io, Access::WRITE);
+        assert_eq!(Instruction::Pchl.branch_target(), None);
+        assert!(!Instruction::Ret.flow().falls_through());
+        assert!(Instruction::Rz.flow().falls_through());
+    }
+}
@@ -0,0 +1,316 @@
+use std::fmt;
+use std::path::Path;
+
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    cpu_state::{Flag, MemoryError, Ram, System},
+    in_out::PortLatch,
+    op_code::{OpCodeError, Register},
+};
+
+#[derive(Error, Debug)]
+pub enum SingleStepError {
+    #[error("Test {0:?}: {1}")]
+    Format(String, String),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+
+    #[error(transparent)]
+    OpCode(#[from] OpCodeError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+const REGISTERS: [(&str, Register); 8] = [
+    ("a", Register::A),
+    ("f", Register::F),
+    ("b", Register::B),
+    ("c", Register::C),
+    ("d", Register::D),
+    ("e", Register::E),
+    ("h", Register::H),
+    ("l", Register::L),
+];
+
+const FLAGS: [(&str, u8); 8] = [
+    ("S", Flag::S as u8),
+    ("Z", Flag::Z as u8),
+    ("F5", 5),
+    ("AC", Flag::Ac as u8),
+    ("F3", 3),
+    ("P", Flag::P as u8),
+    ("F1", 1),
+    ("CY", Flag::Cy as u8),
+];
+
+/// CPU and memory state before or after a test.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct State {
+    pub pc: u16,
+    pub sp: u16,
+    /// A, F, B, C, D, E, H and L, indexed by [`Register`].
+    pub registers: [u8; 8],
+    pub inte: Option<bool>,
+    pub ram: Vec<(u16, u8)>,
+}
+
+/// A single instruction test, in the format of the community per-opcode suites:
+/// `{"name", "initial": {"pc", "sp", "a", ..., "ram": [[addr, value], ...]}, "final",
+/// "cycles", "ports"}`, where `cycles` is either a count or the list of bus cycles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCase {
+    pub name: String,
+    pub initial: State,
+    pub expected: State,
+    pub cycles: Option<usize>,
+    /// Bytes read by `IN`, and expected from `OUT`, by port.
+    pub inputs: Vec<(u8, u8)>,
+    pub outputs: Vec<(u8, u8)>,
+}
+
+/// A difference between the state reached and the expected one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// What differs: a register, flag, `ram[addr]`, `port[n]` or `cycles`.
+    pub what: String,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: expected {:#x}, got {:#x}",
+            self.what, self.expected, self.actual
+        )
+    }
+}
+
+fn field(test: &str, value: &Value, name: &str) -> Result<u64, SingleStepError> {
+    value
+        .get(name)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| SingleStepError::Format(test.to_string(), format!("no {:?} number", name)))
+}
+
+/// Reads `[[a, b, ...], ...]` as `(a, b)` pairs.
+fn pairs(test: &str, value: Option<&Value>) -> Result<Vec<(u64, u64)>, SingleStepError> {
+    let Some(value) = value else {
+        return Ok(Vec::new());
+    };
+    let invalid = || SingleStepError::Format(test.to_string(), format!("invalid list {}", value));
+    let list = value.as_array().ok_or_else(invalid)?;
+    list.iter()
+        .map(|pair| {
+            let first = pair.get(0).and_then(Value::as_u64);
+            let second = pair.get(1).and_then(Value::as_u64);
+            first.zip(second).ok_or_else(invalid)
+        })
+        .collect()
+}
+
+impl State {
+    fn parse(test: &str, value: &Value) -> Result<Self, SingleStepError> {
+        let mut registers = [0; 8];
+        for (register, (name, _)) in registers.iter_mut().zip(REGISTERS) {
+            *register = field(test, value, name)? as u8;
+        }
+        let ram = pairs(test, value.get("ram"))?
+            .into_iter()
+            .map(|(addr, byte)| (addr as u16, byte as u8))
+            .collect();
+        let inte = value
+            .get("inte")
+            .or_else(|| value.get("ie"))
+            .and_then(|inte| inte.as_bool().or(inte.as_u64().map(|n| n != 0)));
+        Ok(State {
+            pc: field(test, value, "pc")? as u16,
+            sp: field(test, value, "sp")? as u16,
+            registers,
+            inte,
+            ram,
+        })
+    }
+}
+
+impl TestCase {
+    pub fn parse(value: &Value) -> Result<Self, SingleStepError> {
+        let name = value
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let state = |key| {
+            let state = value.get(key).ok_or_else(|| {
+                SingleStepError::Format(name.clone(), format!("no {:?} state", key))
+            })?;
+            State::parse(&name, state)
+        };
+        let cycles = match value.get("cycles") {
+            Some(Value::Array(cycles)) => Some(cycles.len()),
+            Some(cycles) => cycles.as_u64().map(|n| n as usize),
+            None => None,
+        };
+        // Ports are listed as [port, value, "r" or "w"].
+        let (mut inputs, mut outputs) = (Vec::new(), Vec::new());
+        for port in value
+            .get("ports")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let access = (port.get(0).and_then(Value::as_u64))
+                .zip(port.get(1).and_then(Value::as_u64))
+                .zip(port.get(2).and_then(Value::as_str));
+            match access {
+                Some(((port, byte), "r")) => inputs.push((port as u8, byte as u8)),
+                Some(((port, byte), "w")) => outputs.push((port as u8, byte as u8)),
+                _ => {
+                    return Err(SingleStepError::Format(
+                        name,
+                        format!("invalid port access {}", port),
+                    ))
+                }
+            }
+        }
+        Ok(TestCase {
+            initial: state("initial")?,
+            expected: state("final")?,
+            name,
+            cycles,
+            inputs,
+            outputs,
+        })
+    }
+
+    /// Reads the tests of a file, which holds a JSON array of them.
+    pub fn load(path: impl AsRef<Path>) -> Result<Vec<Self>, SingleStepError> {
+        let value: Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        match value {
+            Value::Array(tests) => tests.iter().map(Self::parse).collect(),
+            test => Ok(vec![Self::parse(&test)?]),
+        }
+    }
+
+    /// Sets up the initial state, executes one instruction and compares the result with
+    /// the expected state.
+    pub fn run(&self) -> Result<Vec<Mismatch>, SingleStepError> {
+        let mut system = System::new(Ram::new(0x10000, false), self.initial.pc);
+        let cpu = system.cpu_mut();
+        cpu.set_sp(self.initial.sp);
+        for (value, (_, register)) in self.initial.registers.iter().zip(REGISTERS) {
+            cpu.set_register(register, *value);
+        }
+        if let Some(inte) = self.initial.inte {
+            cpu.set_inte(inte);
+        }
+        for (addr, byte) in &self.initial.ram {
+            system.ram_mut().poke(*addr, *byte)?;
+        }
+        let io = PortLatch::default();
+        for (port, byte) in &self.inputs {
+            io.set_input(*port, *byte);
+        }
+
+        let instruction = system.next_instruction()?;
+        let cycles = system
+            .execute(instruction, &io)?
+            .unwrap_or(instruction.cycles());
+
+        let mut mismatches = Vec::new();
+        let mut compare = |what: String, expected: u32, actual: u32| {
+            if expected != actual {
+                mismatches.push(Mismatch {
+                    what,
+                    expected,
+                    actual,
+                });
+            }
+        };
+        let (cpu, expected) = (system.cpu(), &self.expected);
+        compare("pc".to_string(), expected.pc as u32, cpu.pc() as u32);
+        compare("sp".to_string(), expected.sp as u32, cpu.sp() as u32);
+        for (value, (name, register)) in expected.registers.iter().zip(REGISTERS) {
+            if register != Register::F {
+                compare(name.to_string(), *value as u32, cpu.get(register) as u32);
+            }
+        }
+        let flags = (expected.registers[Register::F as usize], cpu.flags());
+        for (name, bit) in FLAGS {
+            let bit = |flags: u8| (flags >> bit) as u32 & 1;
+            compare(name.to_string(), bit(flags.0), bit(flags.1));
+        }
+        if let Some(inte) = expected.inte {
+            compare("inte".to_string(), inte as u32, cpu.inte() as u32);
+        }
+        for (addr, byte) in &expected.ram {
+            let actual = system.ram().peek(*addr)?;
+            compare(format!("ram[{:04x}]", addr), *byte as u32, actual as u32);
+        }
+        for (port, byte) in &self.outputs {
+            compare(
+                format!("port[{:02x}]", port),
+                *byte as u32,
+                io.output(*port) as u32,
+            );
+        }
+        if let Some(expected) = self.cycles {
+            compare("cycles".to_string(), expected as u32, cycles as u32);
+        }
+        Ok(mismatches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Mismatch, TestCase};
+
+    #[test]
+    fn runs_one_instruction() {
+        // ADI 1 at 0x100, with the carry it should leave expected set.
+        let test = json!({
+            "name": "c6 0000",
+            "initial": {
+                "pc": 256, "sp": 0x1234, "a": 0xff, "b": 0, "c": 0, "d": 0, "e": 0,
+                "f": 2, "h": 0, "l": 0, "ram": [[256, 0xc6], [257, 1]],
+            },
+            "final": {
+                "pc": 258, "sp": 0x1234, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0,
+                "f": 0x57, "h": 0, "l": 0, "ram": [[256, 0xc6], [257, 1]],
+            },
+            "cycles": [[256, 0xc6, "r"], [257, 1, "r"], [0, 0, ""], [0, 0, ""],
+                       [0, 0, ""], [0, 0, ""], [0, 0, ""]],
+        });
+        let mut test = TestCase::parse(&test).unwrap();
+        assert_eq!(test.cycles, Some(7));
+        assert_eq!(test.run().unwrap(), vec![]);
+
+        test.expected.registers[0] = 0x10;
+        test.expected.ram[1].1 = 2;
+        assert_eq!(
+            test.run().unwrap(),
+            vec![
+                Mismatch {
+                    what: "a".to_string(),
+                    expected: 0x10,
+                    actual: 0
+                },
+                Mismatch {
+                    what: "ram[0101]".to_string(),
+                    expected: 2,
+                    actual: 1
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Mutex;
+
+use crate::in_out::InOut;
+
+pub const VIDEO_RAM: u16 = 0x2400;
+pub const MEMORY_WIDTH: usize = 32;
+pub const MEMORY_HEIGHT: usize = 224;
+pub const SYSTEM_FREQUENCY: u64 = 2_000_000;
+pub const REFRESH_RATE: u64 = 60;
+
+/// Space Invaders cabinet I/O: input ports, and the external shift register on ports 2/3/4.
+#[derive(Default)]
+pub struct SpaceInvadersPorts {
+    in_ports: Mutex<[u8; 8]>,
+    out_ports: Mutex<[u8; 8]>,
+    shift_port: AtomicU16,
+}
+
+impl SpaceInvadersPorts {
+    pub fn set_input_bit(&self, port: usize, bit: u8) {
+        self.in_ports.lock().unwrap()[port] |= 1 << bit;
+    }
+
+    pub fn clear_input_bit(&self, port: usize, bit: u8) {
+        self.in_ports.lock().unwrap()[port] &= !(1 << bit);
+    }
+}
+
+impl InOut for SpaceInvadersPorts {
+    fn write(&self, port: u8, value: u8) {
+        if port == 4 {
+            let prev = self.shift_port.load(Ordering::Relaxed);
+            let new = ((value as u16) << 8) + (prev >> 8);
+            self.shift_port.store(new, Ordering::Relaxed);
+        } else {
+            self.out_ports.lock().unwrap()[port as usize] = value;
+        }
+    }
+
+    fn read(&self, port: u8) -> u8 {
+        if port == 3 {
+            let val = self.shift_port.load(Ordering::Relaxed);
+            let offset = self.out_ports.lock().unwrap()[2] & 0x07;
+            (val >> offset) as u8
+        } else {
+            self.in_ports.lock().unwrap()[port as usize]
+        }
+    }
+}
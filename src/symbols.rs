@@ -0,0 +1,215 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::syntax::InstructionDisplay;
+
+/// Addresses further than this from the closest label are not rendered relative to it.
+const MAX_OFFSET: u16 = 0x100;
+
+#[derive(Error, Debug)]
+pub enum SymbolError {
+    #[error("Line {0}: could not parse symbol definition {1:?}.")]
+    InvalidLine(usize, String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Maps addresses to labels and back.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    by_addr: BTreeMap<u16, String>,
+    by_name: HashMap<String, u16>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a symbol file. Each line may use any of the following layouts:
+    ///
+    /// - `ADDR LABEL`, optionally repeated on the same line as in the `.SYM` files written by
+    ///   the CP/M `ASM`/`MAC` assemblers; `ADDR` is hexadecimal,
+    /// - `LABEL EQU value` or `LABEL: EQU value`, as listed by zmac, z80asm and friends,
+    /// - `LABEL = value`, as in z88dk and sjasmplus maps,
+    /// - `LABEL value`, where `value` carries an explicit radix (`0x1234`, `$1234`, `1234h`).
+    ///
+    /// Values other than the bare `ADDR` follow assembler conventions: hexadecimal with a
+    /// `0x`/`$` prefix or an `h` suffix, decimal otherwise. Text after `;` is ignored, as are
+    /// empty lines and lines starting with `#`.
+    pub fn parse(text: &str) -> Result<Self, SymbolError> {
+        let mut table = SymbolTable::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default();
+            let line = line.trim_matches(|c: char| c.is_whitespace() || c == '\x1a');
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || SymbolError::InvalidLine(i + 1, line.to_string());
+            for (addr, name) in parse_line(line).ok_or_else(invalid)? {
+                table.insert(addr, name);
+            }
+        }
+        Ok(table)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SymbolError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Adds a label; the first label defined for an address is the one used for display.
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.by_addr.entry(addr).or_insert_with(|| name.to_string());
+        self.by_name.insert(name.to_string(), addr);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Label defined exactly at `addr`.
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(String::as_str)
+    }
+
+    /// Closest label at or before `addr`, with the offset from it.
+    pub fn lookup(&self, addr: u16) -> Option<(&str, u16)> {
+        self.by_addr
+            .range(..=addr)
+            .next_back()
+            .map(|(base, name)| (name.as_str(), addr - base))
+    }
+
+    /// Renders `addr` as `label`, `label+offset` or, when no label is close enough, as hex.
+    pub fn format(&self, addr: u16) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) if offset < MAX_OFFSET => format!("{}+{:#x}", name, offset),
+            _ => format!("{:04x}", addr),
+        }
+    }
+
+    /// Assembler form of `instruction`, followed by the symbolic form of its address operand
+    /// if there is one.
+    pub fn annotate(&self, instruction: impl Into<InstructionDisplay>) -> String {
+        let instruction = instruction.into();
+        match instruction.instruction().address_operand() {
+            Some(addr)
+                if self
+                    .lookup(addr)
+                    .is_some_and(|(_, offset)| offset < MAX_OFFSET) =>
+            {
+                format!("{}  ; {}", instruction, self.format(addr))
+            }
+            _ => instruction.to_string(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.by_addr
+            .iter()
+            .map(|(addr, name)| (*addr, name.as_str()))
+    }
+}
+
+fn parse_line(line: &str) -> Option<Vec<(u16, &str)>> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+
+    if let Some((name, value)) = line.split_once('=') {
+        return Some(vec![(parse_value(value.trim())?, label(name.trim()))]);
+    }
+    if let [name, equ, value, ..] = words[..] {
+        if equ.eq_ignore_ascii_case("equ") {
+            return Some(vec![(parse_value(value)?, label(name))]);
+        }
+    }
+    // `ADDR LABEL` comes first: CP/M labels such as SEARCH or EACH end with an H.
+    if let [name, value] = words[..] {
+        let address = !name.is_empty() && name.chars().all(|c| c.is_ascii_hexdigit());
+        if !address && has_radix(value) {
+            if let Some(value) = parse_value(value) {
+                return Some(vec![(value, label(name))]);
+            }
+        }
+    }
+    if words.len() % 2 == 0 {
+        return words
+            .chunks(2)
+            .map(|pair| Some((u16::from_str_radix(pair[0], 16).ok()?, label(pair[1]))))
+            .collect();
+    }
+    None
+}
+
+fn label(word: &str) -> &str {
+    word.trim_end_matches(':')
+}
+
+fn has_radix(value: &str) -> bool {
+    value.starts_with("0x") || value.starts_with('$') || value.ends_with(['h', 'H'])
+}
+
+fn parse_value(value: &str) -> Option<u16> {
+    let value = value
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .next()?;
+    if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix('$'))
+        .or_else(|| value.strip_suffix(['h', 'H']))
+    {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SymbolTable;
+
+    #[test]
+    fn parse_and_lookup() {
+        let table = SymbolTable::parse("; invaders\n0000 reset\n18dc  init_game\n\n").unwrap();
+        assert_eq!(table.resolve("init_game"), Some(0x18dc));
+        assert_eq!(table.label(0), Some("reset"));
+        assert_eq!(table.format(0x18dc), "init_game");
+        assert_eq!(table.format(0x18e0), "init_game+0x4");
+        assert_eq!(table.format(0x0010), "reset+0x10");
+        assert!(SymbolTable::parse("zzzz label").is_err());
+        assert_eq!(table.format(0x2400), "2400");
+        assert_eq!(SymbolTable::new().format(0x18dc), "18dc");
+    }
+
+    #[test]
+    fn assembler_formats() {
+        let cpm = "0100 START\t0103 LOOP\n0110 DONE\n\x1a";
+        let table = SymbolTable::parse(cpm).unwrap();
+        assert_eq!(table.resolve("LOOP"), Some(0x103));
+        assert_eq!(table.resolve("DONE"), Some(0x110));
+
+        let table = SymbolTable::parse(
+            "0100 SEARCH
+0200 EACH
+",
+        )
+        .unwrap();
+        assert_eq!(table.resolve("SEARCH"), Some(0x100));
+        assert_eq!(table.resolve("EACH"), Some(0x200));
+        assert_eq!(table.resolve("0200"), None);
+
+        let equ = "bdos:\tequ 0005h\nWBOOT EQU 0\nfcb = $005c ; addr, public\nbuf 0x0080\n";
+        let table = SymbolTable::parse(equ).unwrap();
+        assert_eq!(table.resolve("bdos"), Some(0x5));
+        assert_eq!(table.resolve("WBOOT"), Some(0));
+        assert_eq!(table.resolve("fcb"), Some(0x5c));
+        assert_eq!(table.resolve("buf"), Some(0x80));
+    }
+}
@@ -0,0 +1,431 @@
+use std::fmt;
+
+use crate::op_code::{Instruction, Register, RegisterPair};
+
+/// How numeric operands are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HexStyle {
+    /// `0FF00H`, with a leading zero when the first digit is a letter.
+    #[default]
+    Intel,
+    /// `0xff00`.
+    C,
+    /// `$FF00`.
+    Dollar,
+}
+
+impl HexStyle {
+    /// Renders `value` with `digits` hexadecimal digits.
+    pub fn format(self, value: u16, digits: usize) -> String {
+        match self {
+            HexStyle::Intel => {
+                let hex = format!("{:0digits$X}", value);
+                if hex.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                    format!("0{}H", hex)
+                } else {
+                    format!("{}H", hex)
+                }
+            }
+            HexStyle::C => format!("{:#0width$x}", value, width = digits + 2),
+            HexStyle::Dollar => format!("${:0digits$X}", value),
+        }
+    }
+
+    pub fn byte(self, value: u8) -> String {
+        self.format(value as u16, 2)
+    }
+
+    pub fn word(self, value: u16) -> String {
+        self.format(value, 4)
+    }
+}
+
+/// Mnemonic set used to render instructions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Syntax {
+    /// `MOV A,M`, `JNZ 0100H`.
+    #[default]
+    Intel,
+    /// The Z80 equivalents, `LD A,(HL)`, `JP NZ,0100H`.
+    Zilog,
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Register::A => "A",
+            Register::F => "F",
+            Register::B => "B",
+            Register::C => "C",
+            Register::D => "D",
+            Register::E => "E",
+            Register::H => "H",
+            Register::L => "L",
+            Register::M => "M",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for RegisterPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            RegisterPair::PSW => "PSW",
+            RegisterPair::B => "B",
+            RegisterPair::D => "D",
+            RegisterPair::H => "H",
+            RegisterPair::SP => "SP",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Instruction {
+    /// Intel mnemonic of the instruction.
+    pub fn mnemonic(self) -> &'static str {
+        use Instruction::*;
+        match self {
+            Aci(_) => "ACI",
+            Adc(_) => "ADC",
+            Add(_) => "ADD",
+            Adi(_) => "ADI",
+            Ana(_) => "ANA",
+            Ani(_) => "ANI",
+            Call(_) => "CALL",
+            Cc(_) => "CC",
+            Cm(_) => "CM",
+            Cma => "CMA",
+            Cmc => "CMC",
+            Cmp(_) => "CMP",
+            Cnc(_) => "CNC",
+            Cnz(_) => "CNZ",
+            Cp(_) => "CP",
+            Cpe(_) => "CPE",
+            Cpi(_) => "CPI",
+            Cpo(_) => "CPO",
+            Cz(_) => "CZ",
+            Daa => "DAA",
+            Dad(_) => "DAD",
+            Dcr(_) => "DCR",
+            Dcx(_) => "DCX",
+            Di => "DI",
+            Ei => "EI",
+            Hlt => "HLT",
+            In(_) => "IN",
+            Inr(_) => "INR",
+            Inx(_) => "INX",
+            Jc(_) => "JC",
+            Jm(_) => "JM",
+            Jmp(_) => "JMP",
+            Jnc(_) => "JNC",
+            Jnz(_) => "JNZ",
+            Jp(_) => "JP",
+            Jpe(_) => "JPE",
+            Jpo(_) => "JPO",
+            Jz(_) => "JZ",
+            Lda(_) => "LDA",
+            Ldax(_) => "LDAX",
+            Lhld(_) => "LHLD",
+            Lxi(_, _, _) => "LXI",
+            Mov(_, _) => "MOV",
+            Mvi(_, _) => "MVI",
+            Nop => "NOP",
+            Ora(_) => "ORA",
+            Ori(_) => "ORI",
+            Out(_) => "OUT",
+            Pchl => "PCHL",
+            Pop(_) => "POP",
+            Push(_) => "PUSH",
+            Ral => "RAL",
+            Rar => "RAR",
+            Rc => "RC",
+            Ret => "RET",
+            Rlc => "RLC",
+            Rm => "RM",
+            Rnc => "RNC",
+            Rnz => "RNZ",
+            Rp => "RP",
+            Rpe => "RPE",
+            Rpo => "RPO",
+            Rrc => "RRC",
+            Rst(_) => "RST",
+            Rz => "RZ",
+            Sbb(_) => "SBB",
+            Sbi(_) => "SBI",
+            Shld(_) => "SHLD",
+            Sphl => "SPHL",
+            Sta(_) => "STA",
+            Stax(_) => "STAX",
+            Stc => "STC",
+            Sub(_) => "SUB",
+            Sui(_) => "SUI",
+            Xchg => "XCHG",
+            Xra(_) => "XRA",
+            Xri(_) => "XRI",
+            Xthl => "XTHL",
+        }
+    }
+
+    /// Configurable [`fmt::Display`] of the instruction.
+    pub fn display(self) -> InstructionDisplay {
+        InstructionDisplay {
+            instruction: self,
+            syntax: Syntax::default(),
+            hex: HexStyle::default(),
+            symbol: None,
+        }
+    }
+}
+
+/// Renders an [`Instruction`] in assembler syntax, see [`Instruction::display`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstructionDisplay {
+    instruction: Instruction,
+    syntax: Syntax,
+    hex: HexStyle,
+    symbol: Option<String>,
+}
+
+impl InstructionDisplay {
+    pub fn syntax(mut self, syntax: Syntax) -> Self {
+        self.syntax = syntax;
+        self
+    }
+
+    pub fn hex(mut self, hex: HexStyle) -> Self {
+        self.hex = hex;
+        self
+    }
+
+    /// Writes `symbol` in place of the 16 bit address or immediate operand.
+    pub fn symbol(mut self, symbol: impl Into<String>) -> Self {
+        self.symbol = Some(symbol.into());
+        self
+    }
+
+    pub fn instruction(&self) -> Instruction {
+        self.instruction
+    }
+
+    fn address(&self) -> String {
+        match &self.symbol {
+            Some(symbol) => symbol.clone(),
+            None => self
+                .hex
+                .word(self.instruction.address_operand().unwrap_or_default()),
+        }
+    }
+}
+
+impl From<Instruction> for InstructionDisplay {
+    fn from(instruction: Instruction) -> Self {
+        instruction.display()
+    }
+}
+
+impl fmt::Display for InstructionDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.syntax {
+            Syntax::Intel => self.fmt_intel(f),
+            Syntax::Zilog => self.fmt_zilog(f),
+        }
+    }
+}
+
+impl InstructionDisplay {
+    fn fmt_intel(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+        let hex = self.hex;
+        let mnemonic = self.instruction.mnemonic();
+        match self.instruction {
+            Mov(d, s) => write!(f, "{} {},{}", mnemonic, d, s),
+            Mvi(r, n) => write!(f, "{} {},{}", mnemonic, r, hex.byte(n)),
+            Lxi(rp, _, _) => write!(f, "{} {},{}", mnemonic, rp, self.address()),
+            Inr(r) | Dcr(r) | Add(r) | Adc(r) | Sub(r) | Sbb(r) | Ana(r) | Xra(r) | Ora(r)
+            | Cmp(r) => write!(f, "{} {}", mnemonic, r),
+            Ldax(rp) | Stax(rp) | Inx(rp) | Dcx(rp) | Dad(rp) | Push(rp) | Pop(rp) => {
+                write!(f, "{} {}", mnemonic, rp)
+            }
+            Adi(n) | Aci(n) | Sui(n) | Sbi(n) | Ani(n) | Xri(n) | Ori(n) | Cpi(n) | In(n)
+            | Out(n) => write!(f, "{} {}", mnemonic, hex.byte(n)),
+            Rst(n) => write!(f, "{} {}", mnemonic, n),
+            instruction => match instruction.address_operand() {
+                Some(_) => write!(f, "{} {}", mnemonic, self.address()),
+                None => f.write_str(mnemonic),
+            },
+        }
+    }
+
+    fn fmt_zilog(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+        let hex = self.hex;
+        let r = zilog_register;
+        let rp = zilog_pair;
+        let addr = || self.address();
+        match self.instruction {
+            Mov(d, s) => write!(f, "LD {},{}", r(d), r(s)),
+            Mvi(d, n) => write!(f, "LD {},{}", r(d), hex.byte(n)),
+            Lxi(p, _, _) => write!(f, "LD {},{}", rp(p), addr()),
+            Lda(_) => write!(f, "LD A,({})", addr()),
+            Sta(_) => write!(f, "LD ({}),A", addr()),
+            Lhld(_) => write!(f, "LD HL,({})", addr()),
+            Shld(_) => write!(f, "LD ({}),HL", addr()),
+            Ldax(p) => write!(f, "LD A,({})", rp(p)),
+            Stax(p) => write!(f, "LD ({}),A", rp(p)),
+            Xchg => f.write_str("EX DE,HL"),
+            Xthl => f.write_str("EX (SP),HL"),
+            Sphl => f.write_str("LD SP,HL"),
+            Pchl => f.write_str("JP (HL)"),
+            Push(p) => write!(f, "PUSH {}", rp(p)),
+            Pop(p) => write!(f, "POP {}", rp(p)),
+            Inr(s) => write!(f, "INC {}", r(s)),
+            Dcr(s) => write!(f, "DEC {}", r(s)),
+            Inx(p) => write!(f, "INC {}", rp(p)),
+            Dcx(p) => write!(f, "DEC {}", rp(p)),
+            Dad(p) => write!(f, "ADD HL,{}", rp(p)),
+            Add(s) => write!(f, "ADD A,{}", r(s)),
+            Adc(s) => write!(f, "ADC A,{}", r(s)),
+            Sub(s) => write!(f, "SUB {}", r(s)),
+            Sbb(s) => write!(f, "SBC A,{}", r(s)),
+            Ana(s) => write!(f, "AND {}", r(s)),
+            Xra(s) => write!(f, "XOR {}", r(s)),
+            Ora(s) => write!(f, "OR {}", r(s)),
+            Cmp(s) => write!(f, "CP {}", r(s)),
+            Adi(n) => write!(f, "ADD A,{}", hex.byte(n)),
+            Aci(n) => write!(f, "ADC A,{}", hex.byte(n)),
+            Sui(n) => write!(f, "SUB {}", hex.byte(n)),
+            Sbi(n) => write!(f, "SBC A,{}", hex.byte(n)),
+            Ani(n) => write!(f, "AND {}", hex.byte(n)),
+            Xri(n) => write!(f, "XOR {}", hex.byte(n)),
+            Ori(n) => write!(f, "OR {}", hex.byte(n)),
+            Cpi(n) => write!(f, "CP {}", hex.byte(n)),
+            In(n) => write!(f, "IN A,({})", hex.byte(n)),
+            Out(n) => write!(f, "OUT ({}),A", hex.byte(n)),
+            Rlc => f.write_str("RLCA"),
+            Rrc => f.write_str("RRCA"),
+            Ral => f.write_str("RLA"),
+            Rar => f.write_str("RRA"),
+            Cma => f.write_str("CPL"),
+            Stc => f.write_str("SCF"),
+            Cmc => f.write_str("CCF"),
+            Hlt => f.write_str("HALT"),
+            Daa | Di | Ei | Nop | Ret => f.write_str(self.instruction.mnemonic()),
+            Call(_) => write!(f, "CALL {}", addr()),
+            Rst(n) => write!(f, "RST {}", hex.byte(n * 8)),
+            Jmp(_) => write!(f, "JP {}", addr()),
+            Jnz(_) | Jz(_) | Jnc(_) | Jc(_) | Jpo(_) | Jpe(_) | Jp(_) | Jm(_) => {
+                write!(f, "JP {},{}", self.condition(), addr())
+            }
+            Cnz(_) | Cz(_) | Cnc(_) | Cc(_) | Cpo(_) | Cpe(_) | Cp(_) | Cm(_) => {
+                write!(f, "CALL {},{}", self.condition(), addr())
+            }
+            Rnz | Rz | Rnc | Rc | Rpo | Rpe | Rp | Rm => write!(f, "RET {}", self.condition()),
+        }
+    }
+
+    /// Condition code of a conditional jump, call or return.
+    fn condition(&self) -> &'static str {
+        use Instruction::*;
+        match self.instruction {
+            Jnz(_) | Cnz(_) | Rnz => "NZ",
+            Jz(_) | Cz(_) | Rz => "Z",
+            Jnc(_) | Cnc(_) | Rnc => "NC",
+            Jc(_) | Cc(_) | Rc => "C",
+            Jpo(_) | Cpo(_) | Rpo => "PO",
+            Jpe(_) | Cpe(_) | Rpe => "PE",
+            Jp(_) | Cp(_) | Rp => "P",
+            _ => "M",
+        }
+    }
+}
+
+fn zilog_register(register: Register) -> &'static str {
+    match register {
+        Register::A => "A",
+        Register::F => "F",
+        Register::B => "B",
+        Register::C => "C",
+        Register::D => "D",
+        Register::E => "E",
+        Register::H => "H",
+        Register::L => "L",
+        Register::M => "(HL)",
+    }
+}
+
+fn zilog_pair(pair: RegisterPair) -> &'static str {
+    match pair {
+        RegisterPair::PSW => "AF",
+        RegisterPair::B => "BC",
+        RegisterPair::D => "DE",
+        RegisterPair::H => "HL",
+        RegisterPair::SP => "SP",
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.display().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::op_code::{Instruction, Register, RegisterPair};
+
+    use super::{HexStyle, Syntax};
+
+    #[test]
+    fn intel_syntax() {
+        let lxi = Instruction::Lxi(RegisterPair::SP, 0x00, 0xff);
+        assert_eq!(lxi.to_string(), "LXI SP,0FF00H");
+        assert_eq!(
+            Instruction::Mov(Register::A, Register::M).to_string(),
+            "MOV A,M"
+        );
+        assert_eq!(Instruction::Rst(1).to_string(), "RST 1");
+        assert_eq!(Instruction::Mvi(Register::B, 0x12).to_string(), "MVI B,12H");
+        assert_eq!(Instruction::Push(RegisterPair::PSW).to_string(), "PUSH PSW");
+        assert_eq!(Instruction::Jnz(0x0100).to_string(), "JNZ 0100H");
+        assert_eq!(Instruction::Xchg.to_string(), "XCHG");
+
+        assert_eq!(lxi.display().hex(HexStyle::C).to_string(), "LXI SP,0xff00");
+        assert_eq!(
+            Instruction::Cpi(0x0a)
+                .display()
+                .hex(HexStyle::Dollar)
+                .to_string(),
+            "CPI $0A"
+        );
+        assert_eq!(
+            Instruction::Call(0x18dc)
+                .display()
+                .symbol("init")
+                .to_string(),
+            "CALL init"
+        );
+    }
+
+    #[test]
+    fn zilog_syntax() {
+        let zilog =
+            |instruction: Instruction| instruction.display().syntax(Syntax::Zilog).to_string();
+        assert_eq!(
+            zilog(Instruction::Mov(Register::A, Register::M)),
+            "LD A,(HL)"
+        );
+        assert_eq!(zilog(Instruction::Jnz(0x1234)), "JP NZ,1234H");
+        assert_eq!(
+            zilog(Instruction::Lxi(RegisterPair::SP, 0x00, 0x24)),
+            "LD SP,2400H"
+        );
+        assert_eq!(zilog(Instruction::Stax(RegisterPair::D)), "LD (DE),A");
+        assert_eq!(zilog(Instruction::Push(RegisterPair::PSW)), "PUSH AF");
+        assert_eq!(zilog(Instruction::Dad(RegisterPair::B)), "ADD HL,BC");
+        assert_eq!(zilog(Instruction::Rst(7)), "RST 38H");
+        assert_eq!(zilog(Instruction::Rpe), "RET PE");
+        assert_eq!(zilog(Instruction::Call(0x0005)), "CALL 0005H");
+        assert_eq!(zilog(Instruction::Out(0x03)), "OUT (03H),A");
+        assert_eq!(zilog(Instruction::Hlt), "HALT");
+    }
+}
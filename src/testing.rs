@@ -0,0 +1,46 @@
+//! Fixtures shared by the unit tests.
+
+use crate::{
+    cpu_state::{Ram, System},
+    disassembler::Analysis,
+    in_out::DummyInOut,
+};
+
+/// Instructions after which [`run`] gives up on reaching `HLT`.
+const MAX_INSTRUCTIONS: usize = 100_000;
+
+/// A system with 64K of RAM holding `program` at `origin`, where execution starts. Programs
+/// running past 0xffff continue at 0.
+pub fn load(program: &[u8], origin: u16) -> System {
+    let mut ram = Ram::new(0x10000, false);
+    for (offset, byte) in program.iter().enumerate() {
+        ram.poke(origin.wrapping_add(offset as u16), *byte).unwrap();
+    }
+    System::new(ram, origin)
+}
+
+/// Executes `count` instructions, or fewer if one of them is `HLT`.
+pub fn step(system: &mut System, count: usize) {
+    for _ in 0..count {
+        let instruction = system.next_instruction().unwrap();
+        if system.execute(instruction, &DummyInOut).unwrap().is_none() {
+            return;
+        }
+    }
+}
+
+/// Executes instructions until `HLT`.
+pub fn run(system: &mut System) {
+    for _ in 0..MAX_INSTRUCTIONS {
+        let instruction = system.next_instruction().unwrap();
+        if system.execute(instruction, &DummyInOut).unwrap().is_none() {
+            return;
+        }
+    }
+    panic!("no HLT after {} instructions", MAX_INSTRUCTIONS);
+}
+
+/// Recursive descent analysis of `rom` loaded at `origin`, from `origin`.
+pub fn analyse(rom: &[u8], origin: u16) -> Analysis {
+    Analysis::new(rom, origin, &[origin])
+}
@@ -1,12 +1,15 @@
 use std::rc::Rc;
-use std::sync::atomic::AtomicU16;
 use std::{cell::RefCell, sync::Mutex};
 use wasm_bindgen::{prelude::*, Clamped};
 
 use crate::{
     cpu_state::{Ram, System},
+    i8080,
     in_out::InOut,
     op_code::{Instruction, Register, RegisterPair},
+    space_invaders::{
+        SpaceInvadersPorts, MEMORY_HEIGHT, MEMORY_WIDTH, REFRESH_RATE, SYSTEM_FREQUENCY, VIDEO_RAM,
+    },
 };
 
 use web_sys::console::log_1;
@@ -49,47 +52,6 @@ fn dump_state(system: &System) {
     log_1(&format!("Inte: {}", system.cpu().inte()).into());
 }
 
-#[derive(Default)]
-struct SpaceInvadersPorts {
-    in_ports: Mutex<[u8; 8]>,
-    out_ports: Mutex<[u8; 8]>,
-    shift_port: AtomicU16,
-}
-
-impl SpaceInvadersPorts {
-    fn set_input_bit(&self, port: usize, bit: u8) {
-        self.in_ports.lock().unwrap()[port] |= 1 << bit;
-    }
-
-    #[allow(dead_code)]
-    fn clear_input_bit(&self, port: usize, bit: u8) {
-        self.in_ports.lock().unwrap()[port] &= !(1 << bit);
-    }
-}
-
-impl InOut for SpaceInvadersPorts {
-    fn write(&self, port: u8, value: u8) {
-        if port == 4 {
-            let prev = self.shift_port.load(std::sync::atomic::Ordering::Relaxed);
-            let new = ((value as u16) << 8) + (prev >> 8);
-            self.shift_port
-                .store(new, std::sync::atomic::Ordering::Relaxed);
-        } else {
-            self.out_ports.lock().unwrap()[port as usize] = value;
-        }
-    }
-
-    fn read(&self, port: u8) -> u8 {
-        if port == 3 {
-            let val = self.shift_port.load(std::sync::atomic::Ordering::Relaxed);
-            let offset = self.out_ports.lock().unwrap()[2] & 0x07;
-            (val >> offset) as u8
-        } else {
-            self.in_ports.lock().unwrap()[port as usize]
-        }
-    }
-}
-
 #[derive(Default)]
 struct CpuTestPorts {
     ports: [u8; 8],
@@ -146,25 +108,14 @@ pub fn cpu_test() -> Result<(), JsValue> {
     let rom = include_bytes!("../roms/cputest");
     ram.register_rom(rom, 0x100).unwrap();
     // Shamelessly from: https://github.com/gergoerdi/clash-intel8080/blob/f2b09c5970efc0515f111b11d90c3ce648b648b6/test/Hardware/Intel8080/TestBench.hs#L20
-    let rom = [
-        0x3e, 0x0a, // 0x0000: exit:    MVI A, 0x0a
-        0xd3, 0x00, // 0x0002:          OUT 0
-        0x76, // 0x0004:          HLT
-        0x3e, 0x02, // 0x0005: message: MVI A, 0x02
-        0xb9, // 0x0007:          CMP C
-        0xc2, 0x0f, 0x00, // 0x0008:          JNZ 0x000f
-        0x7b, // 0x000B: putChr:  MOV A, E
-        0xd3, 0x00, // 0x000C:          OUT 0
-        0xc9, // 0x000E:          RET
-        0x0e, 0x24, // 0x000F: putStr:  MVI C, '$'
-        0x1a, // 0x0011: loop:    LDAX DE
-        0xb9, // 0x0012:          CMP C
-        0xc2, 0x17, 0x00, // 0x0013:          JNZ next
-        0xc9, // 0x0016:          RET
-        0xd3, 0x00, // 0x0017: next:    OUT 0
-        0x13, // 0x0019:          INX DE
-        0xc3, 0x11, 0x00, // 0x001a:          JMP loop
-    ];
+    let rom = i8080! {
+        exit: MVI A,0AH; OUT 0; HLT
+        message: MVI A,2; CMP C; JNZ putStr
+        putChr: MOV A,E; OUT 0; RET
+        putStr: MVI C,'$'
+        loop: LDAX D; CMP C; JNZ next; RET
+        next: OUT 0; INX D; JMP loop
+    };
     ram.register_rom(&rom, 0x0).unwrap();
     let port_handler = Rc::new(CpuTestPorts::default());
     let pc = 0x100;
@@ -211,17 +162,12 @@ impl EmulatorClosureState {
     }
 
     fn game_js_loop(&mut self, current_time: f64) {
-        let system_frequency = 2_000_000;
-        let system_frequency_for_ms = system_frequency / 1000;
-        let video_buffer_offset = 0x2400;
+        let system_frequency_for_ms = SYSTEM_FREQUENCY / 1000;
         let display_width = 224;
-        let memory_width = 32;
-        let memory_height = 224;
         let mut next_refresh_irq = 1;
         let mut cycle_count = 0;
-        let refresh_rate = 60;
         // we divide by two because there are two triggers per frame, not one!
-        let refresh_rate_irq_threshold = (system_frequency / refresh_rate) / 2;
+        let refresh_rate_irq_threshold = (SYSTEM_FREQUENCY / REFRESH_RATE) / 2;
 
         if self.time.is_none() {
             self.time = Some(current_time);
@@ -258,8 +204,8 @@ impl EmulatorClosureState {
             }
         }
 
-        let raw_video_buffer = self.system.get_slice(video_buffer_offset).unwrap();
-        let rgba_buffer = bitmap_to_rgba(raw_video_buffer, memory_width, memory_height);
+        let raw_video_buffer = self.system.get_slice(VIDEO_RAM).unwrap();
+        let rgba_buffer = bitmap_to_rgba(raw_video_buffer, MEMORY_WIDTH, MEMORY_HEIGHT);
 
         self.context
             .put_image_data(
@@ -0,0 +1,166 @@
+use std::fmt::Write;
+
+use serde_json::{json, Value};
+
+use crate::{
+    disassembler::{Analysis, PortAccess, Reference},
+    symbols::SymbolTable,
+};
+
+fn reference_name(kind: Reference) -> &'static str {
+    match kind {
+        Reference::Jump => "jump",
+        Reference::Call => "call",
+        Reference::Read => "read",
+        Reference::Write => "write",
+        Reference::Immediate => "pointer",
+        Reference::IndirectRead => "indirect read",
+        Reference::IndirectWrite => "indirect write",
+    }
+}
+
+fn port_name(access: PortAccess) -> &'static str {
+    match access {
+        PortAccess::In => "in",
+        PortAccess::Out => "out",
+    }
+}
+
+/// Text cross reference: every address referred to by the code, followed by where and how
+/// it is referred to, then every I/O port with the instructions accessing it.
+pub fn report(analysis: &Analysis, symbols: &SymbolTable) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "Addresses:");
+    for (addr, references) in analysis.references() {
+        let header = format!("{:04x} {}", addr, symbols.label(*addr).unwrap_or_default());
+        let _ = writeln!(out, "{}", header.trim_end());
+        for (from, kind) in references {
+            let _ = writeln!(
+                out,
+                "    {:<15} {:04x} {}",
+                reference_name(*kind),
+                from,
+                symbols.format(*from)
+            );
+        }
+    }
+
+    let _ = writeln!(out, "\nPorts:");
+    for (port, accesses) in analysis.ports() {
+        let _ = writeln!(out, "{:02x}", port);
+        for (from, access) in accesses {
+            let _ = writeln!(
+                out,
+                "    {:<15} {:04x} {}",
+                port_name(*access),
+                from,
+                symbols.format(*from)
+            );
+        }
+    }
+    out
+}
+
+/// The same cross reference as [`report`], as JSON.
+pub fn json(analysis: &Analysis, symbols: &SymbolTable) -> Value {
+    let location = |addr: u16| json!({ "address": addr, "symbol": symbols.format(addr) });
+    let addresses = analysis
+        .references()
+        .iter()
+        .map(|(addr, references)| {
+            let references = references
+                .iter()
+                .map(|(from, kind)| json!({ "kind": reference_name(*kind), "from": location(*from) }))
+                .collect::<Vec<_>>();
+            json!({
+                "address": addr,
+                "label": symbols.label(*addr),
+                "references": references,
+            })
+        })
+        .collect::<Vec<_>>();
+    let ports = analysis
+        .ports()
+        .iter()
+        .map(|(port, accesses)| {
+            let accesses = accesses
+                .iter()
+                .map(
+                    |(from, access)| json!({ "kind": port_name(*access), "from": location(*from) }),
+                )
+                .collect::<Vec<_>>();
+            json!({ "port": port, "accesses": accesses })
+        })
+        .collect::<Vec<_>>();
+    json!({ "addresses": addresses, "ports": ports })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{i8080, symbols::SymbolTable, testing};
+
+    #[test]
+    fn references_and_ports() {
+        let rom = i8080! {
+            LXI H,2000H
+            MOV A,M
+            INX H
+            MOV M,A
+            OUT 3
+            STA 2010H
+            CALL 0
+        };
+        let analysis = testing::analyse(&rom, 0);
+        let symbols = SymbolTable::parse("0000 start\n2000 buffer").unwrap();
+
+        let report = super::report(&analysis, &symbols);
+        assert_eq!(
+            report,
+            "Addresses:\n\
+             0000 start\n    call            000b start+0xb\n\
+             2000 buffer\n    pointer         0000 start\n    indirect read   0003 start+0x3\n\
+             2001\n    indirect write  0005 start+0x5\n\
+             2010\n    write           0008 start+0x8\n\
+             \nPorts:\n03\n    out             0006 start+0x6\n"
+        );
+
+        let json = super::json(&analysis, &symbols);
+        assert_eq!(json["addresses"][1]["label"], "buffer");
+        assert_eq!(
+            json["ports"][0],
+            json!({ "port": 3, "accesses": [{ "kind": "out", "from": { "address": 6, "symbol": "start+0x6" } }] })
+        );
+    }
+
+    #[test]
+    fn nothing_referenced() {
+        let analysis = testing::analyse(&[], 0);
+        let symbols = SymbolTable::new();
+        assert_eq!(super::report(&analysis, &symbols), "Addresses:\n\nPorts:\n");
+        assert_eq!(
+            super::json(&analysis, &symbols),
+            json!({ "addresses": [], "ports": [] })
+        );
+    }
+
+    #[test]
+    fn rst_across_the_end_of_memory() {
+        // LXI starts at 0xffff and ends at 0x0001.
+        let rom = i8080! {
+            NOP
+            LXI H,0FFFFH
+            RST 7
+        };
+        let analysis = testing::analyse(&rom, 0xfffe);
+        let report = super::report(&analysis, &SymbolTable::new());
+        assert_eq!(
+            report,
+            "Addresses:\n\
+             0038\n    call            0002 0002\n\
+             ffff\n    pointer         ffff ffff\n\
+             \nPorts:\n"
+        );
+    }
+}
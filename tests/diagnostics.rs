@@ -0,0 +1,90 @@
+//! CPU diagnostics run under the CP/M console layer. Besides the bundled cputest, the
+//! TST8080, 8080PRE and 8080EXM programs are run when found in the directory named by
+//! `I8080_DIAGNOSTICS`; 8080EXM takes billions of instructions, so its test is ignored
+//! unless asked for (`cargo test --release -- --ignored`).
+
+use emulator8080::cpm::{BufferConsole, Cpm, Exit};
+use std::path::PathBuf;
+
+const DIAGNOSTICS_DIR: &str = "I8080_DIAGNOSTICS";
+
+/// Runs `program` until it returns to CP/M, giving up after `max_instructions`, and
+/// returns what it printed.
+fn run_diagnostic(program: &[u8], max_instructions: u64) -> String {
+    let mut cpm = Cpm::new(program, BufferConsole::default()).unwrap();
+    for _ in 0..max_instructions {
+        match cpm.step().unwrap() {
+            None => {}
+            Some(Exit::WarmBoot) => return cpm.console().text(),
+            Some(exit) => panic!("{:?} after:\n{}", exit, cpm.console().text()),
+        }
+    }
+    panic!(
+        "still running after {} instructions, printed:\n{}",
+        max_instructions,
+        cpm.console().text()
+    );
+}
+
+/// Contents of the diagnostic `name` (e.g. TST8080.COM) in the diagnostics directory,
+/// whatever the case of its file name.
+fn find_diagnostic(name: &str) -> Option<Vec<u8>> {
+    let Some(dir) = std::env::var_os(DIAGNOSTICS_DIR) else {
+        eprintln!("{} is not set, skipping {}.", DIAGNOSTICS_DIR, name);
+        return None;
+    };
+    let path = std::fs::read_dir(PathBuf::from(&dir))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            path.file_name()
+                .is_some_and(|file| file.eq_ignore_ascii_case(name))
+        });
+    match path {
+        Some(path) => Some(std::fs::read(path).unwrap()),
+        None => {
+            eprintln!("{} not found in {:?}, skipping.", name, dir);
+            None
+        }
+    }
+}
+
+#[test]
+fn cputest() {
+    let program = std::fs::read("roms/cputest").unwrap();
+    let output = run_diagnostic(&program, 1_000_000);
+    assert!(output.contains("CPU IS OPERATIONAL"), "{}", output);
+}
+
+#[test]
+fn tst8080() {
+    let Some(program) = find_diagnostic("TST8080.COM") else {
+        return;
+    };
+    let output = run_diagnostic(&program, 1_000_000);
+    assert!(output.contains("CPU IS OPERATIONAL"), "{}", output);
+}
+
+#[test]
+fn preliminary_8080() {
+    let Some(program) = find_diagnostic("8080PRE.COM") else {
+        return;
+    };
+    let output = run_diagnostic(&program, 1_000_000);
+    assert!(
+        output.contains("8080 Preliminary tests complete"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+#[ignore]
+fn exerciser_8080() {
+    let Some(program) = find_diagnostic("8080EXM.COM") else {
+        return;
+    };
+    let output = run_diagnostic(&program, 10_000_000_000);
+    assert!(output.contains("Tests complete"), "{}", output);
+    assert!(!output.contains("ERROR"), "{}", output);
+}